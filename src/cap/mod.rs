@@ -25,21 +25,59 @@
 // SOFTWARE.
 //
 
+use super::CapQuery;
 use super::OctopipesCapError;
 use super::OctopipesCapMessage;
 use super::OctopipesError;
 
+/// ### reply_pipe
+///
+/// `reply_pipe` returns the path of the pipe where the server writes the replies to the CAP requests of a client.
+/// Each client has its own reply pipe, so replies can't be read by other clients
+pub(super) fn reply_pipe(cap_pipe: &str, client_id: &str) -> String {
+    format!("{}.{}", cap_pipe, client_id)
+}
+
+/// Subscription flag: the server stores the messages for the client while it's offline
+const SUBSCRIPTION_DURABLE: u8 = 0x01;
+
 /// ### encode_subscription
 ///
-/// `encode_subscription` encodes a payload for a SUBSCRIBE CAP message
-pub(super) fn encode_subscription(groups: &Vec<String>) -> Vec<u8> {
+/// `encode_subscription` encodes a payload for a SUBSCRIBE CAP message.
+/// Subscription flags are written in a trailing byte after the groups, which is omitted if no flag is set
+pub(super) fn encode_subscription(groups: &[String], durable: bool) -> Vec<u8> {
+    let mut payload: Vec<u8> = encode_groups(OctopipesCapMessage::Subscription, groups);
+    if durable {
+        payload.push(SUBSCRIPTION_DURABLE);
+    }
+    payload
+}
+
+/// ### encode_join
+///
+/// `encode_join` encodes a payload for a JOIN CAP message
+pub(super) fn encode_join(groups: &[String]) -> Vec<u8> {
+    encode_groups(OctopipesCapMessage::Join, groups)
+}
+
+/// ### encode_leave
+///
+/// `encode_leave` encodes a payload for a LEAVE CAP message
+pub(super) fn encode_leave(groups: &[String]) -> Vec<u8> {
+    encode_groups(OctopipesCapMessage::Leave, groups)
+}
+
+/// ### encode_groups
+///
+/// `encode_groups` encodes a payload made up of the message type followed by a list of groups
+fn encode_groups(message_type: OctopipesCapMessage, groups: &[String]) -> Vec<u8> {
     let mut payload_size: usize = 2; //Minimum size
     for group in groups {
         payload_size += group.len() + 1; //Group len + byte for group len
     }
     //Allocate result
     let mut payload: Vec<u8> = Vec::with_capacity(payload_size);
-    payload.push(OctopipesCapMessage::Subscription as u8);
+    payload.push(message_type as u8);
     //Group amount
     payload.push(groups.len() as u8);
     //Iterate over groups
@@ -105,6 +143,90 @@ pub(super) fn encode_unsubscription() -> Vec<u8> {
     vec![OctopipesCapMessage::Unsubscription as u8]
 }
 
+/// ### encode_unsubscription_result
+///
+/// `encode_unsubscription_result` encodes a payload for an UNSUBSCRIPTION RESULT CAP message
+pub(super) fn encode_unsubscription_result(error: OctopipesCapError) -> Vec<u8> {
+    //Return payload
+    vec![OctopipesCapMessage::UnsubscriptionResult as u8, error as u8]
+}
+
+/// ### encode_groups_result
+///
+/// `encode_groups_result` encodes a payload for a GROUPS RESULT CAP message, which replies to both JOIN and LEAVE
+pub(super) fn encode_groups_result(error: OctopipesCapError) -> Vec<u8> {
+    //Return payload
+    vec![OctopipesCapMessage::GroupsResult as u8, error as u8]
+}
+
+/// ### encode_query
+///
+/// `encode_query` encodes a payload for a QUERY CAP message; the argument is the group or the client id the query is about.
+/// Returns BadPacket if the argument is longer than 255 bytes
+pub(super) fn encode_query(query: CapQuery, argument: &str) -> Result<Vec<u8>, OctopipesError> {
+    if argument.len() > u8::MAX as usize {
+        return Err(OctopipesError::BadPacket);
+    }
+    let mut payload: Vec<u8> = Vec::with_capacity(3 + argument.len());
+    payload.push(OctopipesCapMessage::Query as u8);
+    payload.push(query as u8);
+    //Write argument size and then argument
+    payload.push(argument.len() as u8);
+    payload.extend_from_slice(argument.as_bytes());
+    Ok(payload)
+}
+
+/// ### encode_query_result
+///
+/// `encode_query_result` encodes a payload for a QUERY RESULT CAP message
+pub(super) fn encode_query_result(results: &[String]) -> Vec<u8> {
+    encode_groups(OctopipesCapMessage::QueryResult, results)
+}
+
+/// ### encode_heartbeat
+///
+/// `encode_heartbeat` encodes a payload for a HEARTBEAT CAP message
+pub(super) fn encode_heartbeat() -> Vec<u8> {
+    //Return payload
+    vec![OctopipesCapMessage::Heartbeat as u8]
+}
+
+/// ### encode_shutdown
+///
+/// `encode_shutdown` encodes a payload for a SHUTDOWN CAP message, sent by the server to notify clients it's going to stop
+pub(super) fn encode_shutdown() -> Vec<u8> {
+    //Return payload
+    vec![OctopipesCapMessage::Shutdown as u8]
+}
+
+/// ### encode_error
+///
+/// `encode_error` encodes a payload for an ERROR CAP message, sent by the server to the client whose message has been rejected
+pub(super) fn encode_error(error: OctopipesError) -> Vec<u8> {
+    let code: u8 = match error {
+        OctopipesError::BadPacket => 0x01,
+        OctopipesError::BadChecksum => 0x02,
+        OctopipesError::NoRecipient => 0x03,
+        OctopipesError::ReservedGroup => 0x04,
+        _ => 0xff,
+    };
+    vec![OctopipesCapMessage::Error as u8, code]
+}
+
+/// ### encode_disconnect
+///
+/// `encode_disconnect` encodes a payload for a DISCONNECT CAP message, sent by the server to kick a client
+pub(super) fn encode_disconnect(reason: &str) -> Vec<u8> {
+    //Reason can't be longer than 255 bytes
+    let reason: &[u8] = &reason.as_bytes()[..std::cmp::min(reason.len(), 255)];
+    let mut payload: Vec<u8> = Vec::with_capacity(2 + reason.len());
+    payload.push(OctopipesCapMessage::Disconnect as u8);
+    //Write reason size and then reason
+    payload.push(reason.len() as u8);
+    payload.extend_from_slice(reason);
+    payload
+}
+
 /// ### get_cap_message_type
 ///
 /// `get_cap_message_type` get the message type for a CAP message
@@ -123,14 +245,45 @@ pub(super) fn get_cap_message_type(data: &Vec<u8>) -> Result<OctopipesCapMessage
 
 /// ### decode_subscription
 ///
-/// `decode_subscription` decode a subscribe message
-pub(super) fn decode_subscription(data: &Vec<u8>) -> Result<Vec<String>, OctopipesError> {
+/// `decode_subscription` decode a subscribe message returning the groups and whether the subscription is durable
+pub(super) fn decode_subscription(data: &[u8]) -> Result<(Vec<String>, bool), OctopipesError> {
+    let (groups, index): (Vec<String>, usize) = decode_groups_at(OctopipesCapMessage::Subscription, data)?;
+    let flags: u8 = data.get(index).copied().unwrap_or(0);
+    Ok((groups, flags & SUBSCRIPTION_DURABLE != 0))
+}
+
+/// ### decode_join
+///
+/// `decode_join` decode a join message
+pub(super) fn decode_join(data: &[u8]) -> Result<Vec<String>, OctopipesError> {
+    decode_groups(OctopipesCapMessage::Join, data)
+}
+
+/// ### decode_leave
+///
+/// `decode_leave` decode a leave message
+pub(super) fn decode_leave(data: &[u8]) -> Result<Vec<String>, OctopipesError> {
+    decode_groups(OctopipesCapMessage::Leave, data)
+}
+
+/// ### decode_groups
+///
+/// `decode_groups` decode a payload made up of the message type followed by a list of groups
+fn decode_groups(message_type: OctopipesCapMessage, data: &[u8]) -> Result<Vec<String>, OctopipesError> {
+    decode_groups_at(message_type, data).map(|(groups, _)| groups)
+}
+
+/// ### decode_groups_at
+///
+/// `decode_groups_at` decode a payload made up of the message type followed by a list of groups.
+/// Returns the groups and the index of the first byte after them
+fn decode_groups_at(message_type: OctopipesCapMessage, data: &[u8]) -> Result<(Vec<String>, usize), OctopipesError> {
     //Size must be at least 2
     if data.len() < 2 {
         return Err(OctopipesError::BadPacket);
     }
     //Check byte 0
-    if data[0] != OctopipesCapMessage::Subscription as u8 {
+    if data[0] != message_type as u8 {
         return Err(OctopipesError::BadPacket);
     }
     //Get groups amount
@@ -143,6 +296,9 @@ pub(super) fn decode_subscription(data: &Vec<u8>) -> Result<Vec<String>, Octopip
         let group_size: usize = data[index] as usize;
         index += 1;
         let final_index: usize = index + group_size;
+        if final_index > data.len() {
+            return Err(OctopipesError::BadPacket);
+        }
         let mut group_str: String = String::with_capacity(group_size);
         for byte in &data[index..final_index] {
             group_str.push(*byte as char);
@@ -153,7 +309,7 @@ pub(super) fn decode_subscription(data: &Vec<u8>) -> Result<Vec<String>, Octopip
     if groups.len() != groups_amount {
         return Err(OctopipesError::BadPacket);
     }
-    Ok(groups)
+    Ok((groups, index))
 }
 
 /// ### decode_assignment
@@ -234,6 +390,147 @@ pub(super) fn decode_unsubscription(data: &Vec<u8>) -> Result<(), OctopipesError
     Ok(())
 }
 
+/// ### decode_unsubscription_result
+///
+/// `decode_unsubscription_result` decode an unsubscription result message
+pub(super) fn decode_unsubscription_result(data: &[u8]) -> Result<OctopipesCapError, OctopipesError> {
+    //Size must be at least 2
+    if data.len() < 2 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::UnsubscriptionResult as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check Cap Error
+    match OctopipesCapError::from_u8(data[1]) {
+        Some(cap_error) => Ok(cap_error),
+        None => Err(OctopipesError::BadPacket),
+    }
+}
+
+/// ### decode_groups_result
+///
+/// `decode_groups_result` decode a groups result message
+pub(super) fn decode_groups_result(data: &[u8]) -> Result<OctopipesCapError, OctopipesError> {
+    //Size must be at least 2
+    if data.len() < 2 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::GroupsResult as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check Cap Error
+    match OctopipesCapError::from_u8(data[1]) {
+        Some(cap_error) => Ok(cap_error),
+        None => Err(OctopipesError::BadPacket),
+    }
+}
+
+/// ### decode_query
+///
+/// `decode_query` decode a query message
+pub(super) fn decode_query(data: &[u8]) -> Result<(CapQuery, String), OctopipesError> {
+    //Size must be at least 3
+    if data.len() < 3 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::Query as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    let query: CapQuery = match CapQuery::from_u8(data[1]) {
+        Some(query) => query,
+        None => return Err(OctopipesError::BadPacket),
+    };
+    //Get argument
+    let final_index: usize = 3 + data[2] as usize;
+    if final_index > data.len() {
+        return Err(OctopipesError::BadPacket);
+    }
+    let argument: String = data[3..final_index].iter().map(|byte| *byte as char).collect();
+    Ok((query, argument))
+}
+
+/// ### decode_query_result
+///
+/// `decode_query_result` decode a query result message
+pub(super) fn decode_query_result(data: &[u8]) -> Result<Vec<String>, OctopipesError> {
+    decode_groups(OctopipesCapMessage::QueryResult, data)
+}
+
+/// ### decode_heartbeat
+///
+/// `decode_heartbeat` decode a heartbeat message
+pub(super) fn decode_heartbeat(data: &[u8]) -> Result<(), OctopipesError> {
+    //Size must be at least 1
+    if data.is_empty() {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::Heartbeat as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    Ok(())
+}
+
+/// ### decode_shutdown
+///
+/// `decode_shutdown` decode a shutdown message
+pub(super) fn decode_shutdown(data: &[u8]) -> Result<(), OctopipesError> {
+    //Size must be at least 1
+    if data.is_empty() {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::Shutdown as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    Ok(())
+}
+
+/// ### decode_error
+///
+/// `decode_error` decode an error message and returns the error the server rejected the message with
+pub(super) fn decode_error(data: &[u8]) -> Result<OctopipesError, OctopipesError> {
+    //Size must be at least 2
+    if data.len() < 2 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::Error as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    match data[1] {
+        0x01 => Ok(OctopipesError::BadPacket),
+        0x02 => Ok(OctopipesError::BadChecksum),
+        0x03 => Ok(OctopipesError::NoRecipient),
+        0x04 => Ok(OctopipesError::ReservedGroup),
+        _ => Ok(OctopipesError::Unknown),
+    }
+}
+
+/// ### decode_disconnect
+///
+/// `decode_disconnect` decode a disconnect message and returns the reason
+pub(super) fn decode_disconnect(data: &[u8]) -> Result<String, OctopipesError> {
+    //Size must be at least 2
+    if data.len() < 2 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Check byte 0
+    if data[0] != OctopipesCapMessage::Disconnect as u8 {
+        return Err(OctopipesError::BadPacket);
+    }
+    //Get reason
+    let final_index: usize = 2 + data[1] as usize;
+    if final_index > data.len() {
+        return Err(OctopipesError::BadPacket);
+    }
+    Ok(data[2..final_index].iter().map(|byte| *byte as char).collect())
+}
+
 //@! Tests
 
 #[cfg(test)]
@@ -245,7 +542,7 @@ mod tests {
         //Test subscribe payload encoding
         //We'll use two groups 'SUBSCRIBE' and 'SYSTEM'
         let payload: Vec<u8> =
-            encode_subscription(&vec![String::from("SUBSCRIBE"), String::from("SYSTEM")]);
+            encode_subscription(&vec![String::from("SUBSCRIBE"), String::from("SYSTEM")], false);
         assert_eq!(
             payload.len(),
             19,
@@ -360,7 +657,7 @@ mod tests {
     #[test]
     fn test_encode_subscription_without_groups() {
         //Test subscribe payload encoding
-        let payload: Vec<u8> = encode_subscription(&vec![]);
+        let payload: Vec<u8> = encode_subscription(&vec![], false);
         assert_eq!(
             payload.len(),
             2,
@@ -597,7 +894,7 @@ mod tests {
     #[test]
     fn test_get_cap_message_type() {
         //Test subscribe
-        let payload: Vec<u8> = encode_subscription(&vec![String::from("SUBSCRIBE")]);
+        let payload: Vec<u8> = encode_subscription(&vec![String::from("SUBSCRIBE")], false);
         assert_eq!(
             get_cap_message_type(&payload).unwrap(),
             OctopipesCapMessage::Subscription,
@@ -631,7 +928,7 @@ mod tests {
         //Create a subscribe payload to decode (two groups, SYS and HW)
         let payload: Vec<u8> = vec![0x01, 0x02, 0x03, 'S' as u8, 'Y' as u8, 'S' as u8, 0x02, 'H' as u8, 'W' as u8];
         match decode_subscription(&payload) {
-            Ok(groups) => {
+            Ok((groups, _)) => {
                 //Check groups
                 assert_eq!(groups.len(), 2, "There should be two groups; found {}", groups.len());
                 //Check group 0
@@ -645,6 +942,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_durable_subscription() {
+        let groups: Vec<String> = vec![String::from("SYS"), String::from("HW")];
+        let payload: Vec<u8> = encode_subscription(&groups, true);
+        assert_eq!(*payload.last().unwrap(), 0x01, "Last byte should be the durable flag");
+        assert_eq!(decode_subscription(&payload).unwrap(), (groups.clone(), true));
+        //Without flags
+        let payload: Vec<u8> = encode_subscription(&groups, false);
+        assert_eq!(payload.len(), 9, "Non durable subscription shouldn't have flags");
+        assert_eq!(decode_subscription(&payload).unwrap(), (groups, false));
+        //Truncated group
+        assert_eq!(decode_subscription(&[0x01, 0x01, 0x05, 'S' as u8]).err().unwrap(), OctopipesError::BadPacket);
+    }
+
     #[test]
     fn test_parse_subscribe_nok() {
         //Create a subscribe payload to decode (two groups, but one is missing)
@@ -736,4 +1047,132 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_reply_pipe() {
+        assert_eq!(reply_pipe("/tmp/cap.fifo", "client_a"), String::from("/tmp/cap.fifo.client_a"));
+        assert_ne!(reply_pipe("/tmp/cap.fifo", "client_a"), reply_pipe("/tmp/cap.fifo", "client_b"));
+    }
+
+    #[test]
+    fn test_unsubscription_result() {
+        let payload: Vec<u8> = encode_unsubscription_result(OctopipesCapError::NotSubscribed);
+        assert_eq!(payload, vec![0xfe, 0x03], "Unsubscription result payload should be [0xfe, 0x03]");
+        assert_eq!(decode_unsubscription_result(&payload).unwrap(), OctopipesCapError::NotSubscribed);
+        let payload: Vec<u8> = encode_unsubscription_result(OctopipesCapError::NoError);
+        assert_eq!(decode_unsubscription_result(&payload).unwrap(), OctopipesCapError::NoError);
+        //Bad packets
+        assert_eq!(decode_unsubscription_result(&vec![0xfe]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_unsubscription_result(&vec![0xff, 0x00]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_unsubscription_result(&vec![0xfe, 0x80]).err().unwrap(), OctopipesError::BadPacket);
+    }
+
+    #[test]
+    fn test_query() {
+        let payload: Vec<u8> = encode_query(CapQuery::GroupMembers, "SENSORS").unwrap();
+        assert_eq!(payload.len(), 10, "Payload len should be 10, but is {}", payload.len());
+        match decode_query(&payload) {
+            Ok((query, argument)) => {
+                assert!(query == CapQuery::GroupMembers, "Query should be GroupMembers");
+                assert_eq!(argument, String::from("SENSORS"));
+            }
+            Err(err) => panic!("Query should have been parsed without errors: {}", err),
+        }
+        let (query, argument) = decode_query(&encode_query(CapQuery::Clients, "").unwrap()).unwrap();
+        assert!(query == CapQuery::Clients, "Query should be Clients");
+        assert_eq!(argument, String::from(""));
+        //The argument size must fit in a byte
+        let long_argument: String = "a".repeat(255);
+        let (_, decoded) = decode_query(&encode_query(CapQuery::GroupMembers, &long_argument).unwrap()).unwrap();
+        assert_eq!(decoded, long_argument);
+        assert_eq!(encode_query(CapQuery::GroupMembers, &"a".repeat(256)).err().unwrap(), OctopipesError::BadPacket);
+        //Bad packets
+        assert_eq!(decode_query(&vec![0x06, 0x09, 0x00]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_query(&vec![0x06, 0x01, 0x04, 0x41]).err().unwrap(), OctopipesError::BadPacket);
+        //Result
+        let results: Vec<String> = vec![String::from("client_a"), String::from("client_b")];
+        let payload: Vec<u8> = encode_query_result(&results);
+        assert_eq!(payload[0], OctopipesCapMessage::QueryResult as u8);
+        assert_eq!(decode_query_result(&payload).unwrap(), results);
+        assert_eq!(decode_query_result(&encode_query_result(&[])).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let payload: Vec<u8> = encode_heartbeat();
+        assert_eq!(payload, vec![0x05], "Heartbeat payload should be [0x05]");
+        assert!(decode_heartbeat(&payload).is_ok(), "Heartbeat should have been parsed without errors");
+        assert_eq!(decode_heartbeat(&vec![0x02]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_heartbeat(&vec![]).err().unwrap(), OctopipesError::BadPacket);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let payload: Vec<u8> = encode_shutdown();
+        assert_eq!(payload, vec![0xfb], "Shutdown payload should be [0xfb]");
+        assert!(decode_shutdown(&payload).is_ok(), "Shutdown should have been parsed without errors");
+        assert_eq!(decode_shutdown(&encode_disconnect("bye")).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_shutdown(&vec![]).err().unwrap(), OctopipesError::BadPacket);
+    }
+
+    #[test]
+    fn test_error() {
+        let payload: Vec<u8> = encode_error(OctopipesError::BadChecksum);
+        assert_eq!(payload, vec![0xfa, 0x02], "Error payload should be [0xfa, 0x02]");
+        assert!(get_cap_message_type(&payload).unwrap() == OctopipesCapMessage::Error);
+        assert_eq!(decode_error(&payload).unwrap(), OctopipesError::BadChecksum);
+        assert_eq!(decode_error(&encode_error(OctopipesError::BadPacket)).unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_error(&encode_error(OctopipesError::NoRecipient)).unwrap(), OctopipesError::NoRecipient);
+        assert_eq!(decode_error(&encode_error(OctopipesError::ReservedGroup)).unwrap(), OctopipesError::ReservedGroup);
+        assert_eq!(decode_error(&vec![0xfa, 0x99]).unwrap(), OctopipesError::Unknown);
+        assert_eq!(decode_error(&vec![0xfa]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_error(&encode_shutdown()).err().unwrap(), OctopipesError::BadPacket);
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let payload: Vec<u8> = encode_disconnect("flooding");
+        assert_eq!(payload[0], 0xfc, "Payload at 0 should be DISCONNECT");
+        assert_eq!(payload[1], 8, "Payload at 1 should be reason length");
+        assert!(get_cap_message_type(&payload).unwrap() == OctopipesCapMessage::Disconnect);
+        assert_eq!(decode_disconnect(&payload).unwrap(), String::from("flooding"));
+        assert_eq!(decode_disconnect(&encode_disconnect("")).unwrap(), String::new());
+        assert_eq!(decode_disconnect(&vec![0xfc, 0x04, 0x61]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_disconnect(&vec![0x05, 0x00]).err().unwrap(), OctopipesError::BadPacket);
+    }
+
+    #[test]
+    fn test_join_and_leave() {
+        let groups: Vec<String> = vec![String::from("SENSORS"), String::from("ALARMS")];
+        //Join
+        let payload: Vec<u8> = encode_join(&groups);
+        assert_eq!(payload[0], OctopipesCapMessage::Join as u8, "Payload at 0 should be JOIN");
+        assert_eq!(get_cap_message_type(&payload).unwrap(), OctopipesCapMessage::Join);
+        match decode_join(&payload) {
+            Ok(decoded) => assert_eq!(decoded, groups, "Decoded groups mismatch"),
+            Err(err) => panic!("Join should have been parsed without errors: {}", err),
+        }
+        assert!(decode_leave(&payload).is_err(), "Join packet shouldn't be decoded as leave");
+        //Leave
+        let payload: Vec<u8> = encode_leave(&groups);
+        assert_eq!(payload[0], OctopipesCapMessage::Leave as u8, "Payload at 0 should be LEAVE");
+        match decode_leave(&payload) {
+            Ok(decoded) => assert_eq!(decoded, groups, "Decoded groups mismatch"),
+            Err(err) => panic!("Leave should have been parsed without errors: {}", err),
+        }
+        assert!(decode_join(&payload).is_err(), "Leave packet shouldn't be decoded as join");
+    }
+
+    #[test]
+    fn test_groups_result() {
+        let payload: Vec<u8> = encode_groups_result(OctopipesCapError::NotSubscribed);
+        assert_eq!(get_cap_message_type(&payload).unwrap(), OctopipesCapMessage::GroupsResult);
+        assert_eq!(decode_groups_result(&payload).unwrap(), OctopipesCapError::NotSubscribed);
+        let payload: Vec<u8> = encode_groups_result(OctopipesCapError::NoError);
+        assert_eq!(decode_groups_result(&payload).unwrap(), OctopipesCapError::NoError);
+        //Bad packets
+        assert_eq!(decode_groups_result(&vec![0xf9]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_groups_result(&vec![0xfe, 0x00]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(decode_groups_result(&vec![0xf9, 0x80]).err().unwrap(), OctopipesError::BadPacket);
+    }
 }
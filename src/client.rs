@@ -30,17 +30,34 @@ use super::OctopipesCapError;
 use super::OctopipesCapMessage;
 use super::OctopipesClient;
 use super::OctopipesError;
+use super::OctopipesInboxPolicy;
 use super::OctopipesMessage;
 use super::OctopipesOptions;
+use super::OctopipesOverflowPolicy;
 use super::OctopipesProtocolVersion;
+use super::OctopipesReconnectPolicy;
+use super::OctopipesSender;
 use super::OctopipesState;
+use super::CapQuery;
+use super::Inbox;
+use super::MessageHandlers;
+use super::OutboundQueue;
+use super::SentCallback;
+use super::Session;
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 use super::cap;
 use super::pipes;
 use super::serializer;
+use super::topic;
+
+const MAX_HEARTBEAT_FAILURES: usize = 2; //Heartbeats which can't be written in a row before the server is considered lost
 
 impl OctopipesClient {
     /// ### OctopipesClient Constructor
@@ -55,15 +72,28 @@ impl OctopipesClient {
             id: client_id,
             version: version,
             cap_pipe: cap_pipe,
-            tx_pipe: None,
-            rx_pipe: None,
+            cap_lock: Arc::new(Mutex::new(())),
+            tx_pipe: Arc::new(Mutex::new(None)),
+            rx_pipe: Arc::new(Mutex::new(None)),
+            groups: Arc::new(Mutex::new(Vec::new())),
+            session: Arc::new(Mutex::new(None)),
+            reconnect_policy: None,
+            heartbeat_interval: None,
+            no_echo: Arc::new(Mutex::new(false)),
+            durable: false,
+            outbound: Arc::new(Mutex::new(None)),
             state: Arc::new(Mutex::new(OctopipesState::Initialized)),
             client_loop: None,
-            client_receiver: None,
+            inbox: Arc::new(Inbox::new()),
             on_received_fn: None,
-            on_sent_fn: None,
+            on_sent_fn: Arc::new(Mutex::new(None)),
             on_subscribed_fn: None,
             on_unsubscribed_fn: None,
+            on_connection_lost_fn: None,
+            on_reconnected_fn: None,
+            on_kicked_fn: None,
+            on_server_shutdown_fn: None,
+            handlers: Arc::new(Mutex::new(MessageHandlers::new())),
         }
     }
 
@@ -77,20 +107,38 @@ impl OctopipesClient {
         match *client_state {
             OctopipesState::Subscribed => {
                 //Create threaded client
-                if self.rx_pipe.is_none() || self.tx_pipe.is_none() {
+                if self.rx_pipe.lock().unwrap().is_none() || self.tx_pipe.lock().unwrap().is_none() {
                     return Err(OctopipesError::Uninitialized);
                 }
                 //Set state to running
                 *client_state = OctopipesState::Running;
                 let this_state_rc = Arc::clone(&self.state);
-                let rx_pipe: String = self.rx_pipe.as_ref().unwrap().clone();
-                let tx_pipe: String = self.tx_pipe.as_ref().unwrap().clone();
+                let rx_pipe_rc: Arc<Mutex<Option<String>>> = Arc::clone(&self.rx_pipe);
+                let tx_pipe: Arc<Mutex<Option<String>>> = Arc::clone(&self.tx_pipe);
+                let outbound: Arc<Mutex<Option<OutboundQueue>>> = Arc::clone(&self.outbound);
                 let version: OctopipesProtocolVersion = self.version;
                 let client_id: String = self.id.clone();
-                let (client_sender, client_receiver) = mpsc::channel();
-                self.client_receiver = Some(client_receiver);
+                //Reconnect stuff
+                let cap_pipe: String = self.cap_pipe.clone();
+                let cap_lock: Arc<Mutex<()>> = Arc::clone(&self.cap_lock);
+                let groups: Arc<Mutex<Vec<String>>> = Arc::clone(&self.groups);
+                let session: Arc<Mutex<Option<Session>>> = Arc::clone(&self.session);
+                let reconnect_policy: Option<OctopipesReconnectPolicy> = self.reconnect_policy;
+                let heartbeat_interval: Option<Duration> = self.heartbeat_interval;
+                let durable: bool = self.durable;
+                let on_connection_lost_fn: Option<fn()> = self.on_connection_lost_fn;
+                let on_reconnected_fn: Option<fn()> = self.on_reconnected_fn;
+                let on_kicked_fn: Option<fn(&str)> = self.on_kicked_fn;
+                let on_server_shutdown_fn: Option<fn()> = self.on_server_shutdown_fn;
+                let on_sent_fn: SentCallback = Arc::clone(&self.on_sent_fn);
+                let handlers: Arc<Mutex<MessageHandlers>> = Arc::clone(&self.handlers);
+                let inbox: Arc<Inbox> = Arc::clone(&self.inbox);
                 self.client_loop = Some(thread::spawn(move || {
                     let mut terminate_thread: bool = false;
+                    let mut last_heartbeat: Instant = Instant::now();
+                    let mut heartbeat_failures: usize = 0; //Heartbeats which couldn't be written in a row
+                    let mut server_shutdown: bool = false; //Set when the server announced it is shutting down
+                    let mut pending: Vec<u8> = Vec::new(); //Incomplete packet waiting for the next read
                     while !terminate_thread {
                         {
                             let current_state = this_state_rc.lock().unwrap();
@@ -98,8 +146,34 @@ impl OctopipesClient {
                                 terminate_thread = true;
                             }
                         }
+                        if !terminate_thread {
+                            //Write messages left in the outbound queue
+                            let _ = flush_outbound(&tx_pipe, &outbound, *on_sent_fn.lock().unwrap());
+                            //Tell the server the client is still alive
+                            if let Some(interval) = heartbeat_interval {
+                                if last_heartbeat.elapsed() >= interval {
+                                    match cap_send(&cap_pipe, &client_id, &version, cap::encode_heartbeat()) {
+                                        Ok(..) => heartbeat_failures = 0,
+                                        Err(..) => heartbeat_failures += 1,
+                                    }
+                                    last_heartbeat = Instant::now();
+                                }
+                            }
+                        }
                         //Try to read (Read for 500 ms and sleep for 100ms)
-                        match pipes::pipe_read(&rx_pipe, 500) {
+                        let rx_pipe: String = match rx_pipe_rc.lock().unwrap().as_ref() {
+                            Some(rx_pipe) => rx_pipe.clone(),
+                            None => break,
+                        };
+                        let read_result: std::io::Result<Option<Vec<u8>>> = match heartbeat_failures >= MAX_HEARTBEAT_FAILURES {
+                            true => {
+                                //Nobody reads the CAP anymore (e.g. the server crashed leaving its pipes behind): handle it as if the pipes had been removed
+                                heartbeat_failures = 0;
+                                Err(std::io::Error::from(ErrorKind::NotFound))
+                            }
+                            false => pipes::pipe_read(&rx_pipe, 500),
+                        };
+                        match read_result {
                             Ok(data) => {
                                 match data {
                                     None => {
@@ -107,57 +181,140 @@ impl OctopipesClient {
                                         continue; //Just go on
                                     },
                                     Some(data) => {
-                                        //Otherwise parse message and send to callback
-                                        match serializer::decode_message(data) {
-                                            Ok(message) => {
-                                                //If message has ACK, send ACK back
-                                                if message.options.intersects(OctopipesOptions::RCK) {
-                                                    //if RCK is set, send ACK back
-                                                    let message_origin: Option<String> =
-                                                        match message.origin.as_ref() {
-                                                            Some(origin) => Some(origin.clone()),
-                                                            None => None,
-                                                        };
-                                                    //Prepare message
-                                                    let mut message: OctopipesMessage =
-                                                        OctopipesMessage::new(
-                                                            &version,
-                                                            &Some(client_id.clone()),
-                                                            &message_origin,
-                                                            message.ttl,
-                                                            OctopipesOptions::ACK,
-                                                            vec![],
-                                                        );
-                                                    //Encode message
-                                                    match serializer::encode_message(&mut message) {
-                                                        Ok(data_out) => {
-                                                            //Write message to CAP
-                                                            let _ =
-                                                                pipes::pipe_write(&tx_pipe, 5000, data_out);
+                                        //Otherwise parse messages (more than one could have been read, or only part of one) and send to callback
+                                        pending.extend(data);
+                                        for result in serializer::decode_messages(&mut pending) {
+                                            match result {
+                                                Ok(message) => {
+                                                    //If the server is kicking the client, stop here
+                                                    if let Some(reason) = get_disconnect_reason(&message, &client_id) {
+                                                        //The server has removed the pipes
+                                                        *tx_pipe.lock().unwrap() = None;
+                                                        *rx_pipe_rc.lock().unwrap() = None;
+                                                        *this_state_rc.lock().unwrap() = OctopipesState::Unsubscribed;
+                                                        *session.lock().unwrap() = None;
+                                                        if let Some(on_kicked) = on_kicked_fn {
+                                                            (on_kicked)(reason.as_str());
                                                         }
-                                                        Err(..) => { /*Ignore error*/ }
+                                                        inbox.push(Err(OctopipesError::Kicked), &this_state_rc);
+                                                        terminate_thread = true;
+                                                        break;
+                                                    }
+                                                    //If the server is shutting down, keep reading until it removes the pipes
+                                                    if is_server_message(&message, &client_id) && cap::decode_shutdown(&message.data).is_ok() {
+                                                        server_shutdown = true;
+                                                        if let Some(on_server_shutdown) = on_server_shutdown_fn {
+                                                            (on_server_shutdown)();
+                                                        }
+                                                        continue;
+                                                    }
+                                                    //If the server rejected a message sent by this client, report the error
+                                                    if is_server_message(&message, &client_id) {
+                                                        if let Ok(error) = cap::decode_error(&message.data) {
+                                                            inbox.push(Err(error), &this_state_rc);
+                                                            continue;
+                                                        }
+                                                    }
+                                                    //If message has ACK, send ACK back
+                                                    if message.options.intersects(OctopipesOptions::RCK) {
+                                                        //if RCK is set, send ACK back
+                                                        let message_origin: Option<String> =
+                                                            match message.origin.as_ref() {
+                                                                Some(origin) => Some(origin.clone()),
+                                                                None => None,
+                                                            };
+                                                        //Prepare message
+                                                        let message: OctopipesMessage =
+                                                            OctopipesMessage::new(
+                                                                &version,
+                                                                &Some(client_id.clone()),
+                                                                &message_origin,
+                                                                message.ttl,
+                                                                OctopipesOptions::ACK,
+                                                                vec![],
+                                                            );
+                                                        //Encode message
+                                                        match serializer::encode_message(&message) {
+                                                            Ok(data_out) => {
+                                                                //Write message to TX pipe
+                                                                let _ = write_frame(&tx_pipe, data_out);
+                                                            }
+                                                            Err(..) => { /*Ignore error*/ }
+                                                        }
+                                                    }
+                                                    //Route message to its handler; if there's no handler, put message in the inbox
+                                                    let handler: Option<fn(&OctopipesMessage)> =
+                                                        handlers.lock().unwrap().get_handler(&message);
+                                                    match handler {
+                                                        Some(handler) => (handler)(&message),
+                                                        None => inbox.push(Ok(message), &this_state_rc),
                                                     }
                                                 }
-                                                //Send message
-                                                if let Err(_) = client_sender.send(Ok(message)) {
-                                                    break; //Terminate thread
-                                                }
-                                            }
-                                            Err(err) => {
-                                                if let Err(_) = client_sender.send(Err(err)) {
-                                                    break; //Terminate thread
-                                                }
+                                                Err(err) => inbox.push(Err(err), &this_state_rc),
                                             }
                                         }
                                     }
                                 }
                             }
-                            Err(_) => {
-                                if let Err(_) = client_sender.send(Err(OctopipesError::ReadFailed))
+                            Err(ref err) if err.kind() == ErrorKind::NotFound && server_shutdown => {
+                                //RX pipe has been removed after a planned shutdown: don't reconnect
+                                let mut current_state = this_state_rc.lock().unwrap();
+                                if *current_state == OctopipesState::Running {
+                                    *tx_pipe.lock().unwrap() = None;
+                                    *rx_pipe_rc.lock().unwrap() = None;
+                                    *current_state = OctopipesState::Unsubscribed;
+                                    drop(current_state);
+                                    *session.lock().unwrap() = None;
+                                    inbox.push(Err(OctopipesError::ServerShutdown), &this_state_rc);
+                                }
+                                break; //Terminate thread
+                            }
+                            Err(ref err) if err.kind() == ErrorKind::NotFound && reconnect_policy.is_some() => {
+                                //RX pipe has been removed (or the server doesn't read heartbeats), the server has gone away: subscribe again
                                 {
-                                    break; //Terminate thread
+                                    let mut current_state = this_state_rc.lock().unwrap();
+                                    if *current_state != OctopipesState::Running {
+                                        continue; //Client is being stopped
+                                    }
+                                    *current_state = OctopipesState::Reconnecting;
+                                }
+                                if let Some(on_connection_lost) = on_connection_lost_fn {
+                                    (on_connection_lost)();
+                                }
+                                let subscription: Vec<u8> = cap::encode_subscription(&groups.lock().unwrap(), durable);
+                                match reconnect(&cap_pipe, &cap_lock, &client_id, &version, &subscription, reconnect_policy.as_ref().unwrap(), &this_state_rc) {
+                                    Some((new_tx_pipe, new_rx_pipe, server_version)) => {
+                                        //Swap pipes and go back running (data read from the old pipe is lost)
+                                        pending.clear();
+                                        *tx_pipe.lock().unwrap() = Some(new_tx_pipe);
+                                        *rx_pipe_rc.lock().unwrap() = Some(new_rx_pipe);
+                                        *session.lock().unwrap() = Some(Session {
+                                            subscribed_since: SystemTime::now(),
+                                            server_version,
+                                        });
+                                        {
+                                            let mut current_state = this_state_rc.lock().unwrap();
+                                            if *current_state == OctopipesState::Reconnecting {
+                                                *current_state = OctopipesState::Running;
+                                            }
+                                        }
+                                        if let Some(on_reconnected) = on_reconnected_fn {
+                                            (on_reconnected)();
+                                        }
+                                    }
+                                    None => {
+                                        //Attempts exhausted (or client stopped)
+                                        let mut current_state = this_state_rc.lock().unwrap();
+                                        if *current_state == OctopipesState::Reconnecting {
+                                            *current_state = OctopipesState::Unsubscribed;
+                                            drop(current_state);
+                                            inbox.push(Err(OctopipesError::NotSubscribed), &this_state_rc);
+                                        }
+                                        break; //Terminate thread
+                                    }
                                 }
                             }
+                            Err(_) => inbox.push(Err(OctopipesError::ReadFailed), &this_state_rc),
                         }
                     }
                     thread::sleep(std::time::Duration::from_millis(100));
@@ -165,7 +322,7 @@ impl OctopipesClient {
                 }));
                 Ok(())
             }
-            OctopipesState::Running => Err(OctopipesError::ThreadAlreadyRunning),
+            OctopipesState::Running | OctopipesState::Reconnecting => Err(OctopipesError::ThreadAlreadyRunning),
             _ => Err(OctopipesError::NotSubscribed),
         }
     }
@@ -176,7 +333,7 @@ impl OctopipesClient {
     pub fn loop_stop(&mut self) -> Result<(), OctopipesError> {
         let mut client_state = self.state.lock().unwrap();
         match *client_state {
-            OctopipesState::Running => {
+            OctopipesState::Running | OctopipesState::Reconnecting => {
                 //Stop thread
                 *client_state = OctopipesState::Stopped;
                 drop(client_state); //Otherwise the other thread will never read the state
@@ -193,91 +350,84 @@ impl OctopipesClient {
 
     /// ###  subscribe
     ///
-    /// `subscribe` subscribe to Octopipes server; the client will subscribe to the groups described in the subscription_list
-
+    /// `subscribe` subscribe to Octopipes server; the client will subscribe to the groups described in the subscription_list.
+    /// The client ids containing '+', '#' or '/' or starting with '$' are refused with `InvalidName` (the server ignores their requests)
     pub fn subscribe(
         &mut self,
-        subscription_list: &Vec<String>,
+        subscription_list: &[String],
     ) -> Result<OctopipesCapError, OctopipesError> {
-        //Prepare subscribe message
-        let payload: Vec<u8> = cap::encode_subscription(subscription_list);
-        //Send message through the CAP
-        match self.send_cap(payload) {
+        if !topic::is_valid_client_id(&self.id) {
+            return Ok(OctopipesCapError::InvalidName);
+        }
+        let payload: Vec<u8> = cap::encode_subscription(subscription_list, self.durable);
+        match cap_subscribe(&self.cap_pipe, &self.cap_lock, &self.id, &self.version, payload) {
             Err(err) => Err(err),
-            Ok(..) => {
-                //Wait for ASSIGNMENT
-                match pipes::pipe_read(&self.cap_pipe, 5000) {
-                    Err(..) => Err(OctopipesError::ReadFailed),
-                    Ok(data_in) => {
-                        match data_in {
-                            None => return Err(OctopipesError::NoDataAvailable),
-                            Some(data_in) => {
-                                //Parse message
-                                match serializer::decode_message(data_in) {
-                                    Err(err) => Err(err),
-                                    Ok(response) => {
-                                        //Check if message type is ASSIGNMENT
-                                        match cap::get_cap_message_type(&response.data) {
-                                            Ok(message_type) => {
-                                                match message_type {
-                                                    OctopipesCapMessage::Assignment => {
-                                                        //Ok, is an ASSIGNMENT
-                                                        //Parse assignment params
-                                                        match cap::decode_assignment(&response.data) {
-                                                            Ok((cap_error, pipe_tx, pipe_rx)) => {
-                                                                //Assign params
-                                                                if cap_error != OctopipesCapError::NoError {
-                                                                    return Ok(cap_error);
-                                                                }
-                                                                self.tx_pipe = pipe_tx;
-                                                                self.rx_pipe = pipe_rx;
-                                                                let mut client_state =
-                                                                    self.state.lock().unwrap();
-                                                                *client_state = OctopipesState::Subscribed;
-                                                                Ok(OctopipesCapError::NoError)
-                                                            }
-                                                            Err(err) => Err(err),
-                                                        }
-                                                    }
-                                                    _ => Err(OctopipesError::BadPacket),
-                                                }
-                                            }
-                                            Err(err) => Err(err),
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
+            Ok((cap_error, pipe_tx, pipe_rx, server_version)) => {
+                if cap_error != OctopipesCapError::NoError {
+                    return Ok(cap_error);
+                }
+                //Assign params
+                *self.tx_pipe.lock().unwrap() = pipe_tx;
+                *self.rx_pipe.lock().unwrap() = pipe_rx;
+                *self.groups.lock().unwrap() = subscription_list.to_vec();
+                *self.session.lock().unwrap() = Some(Session {
+                    subscribed_since: SystemTime::now(),
+                    server_version,
+                });
+                {
+                    let mut client_state = self.state.lock().unwrap();
+                    *client_state = OctopipesState::Subscribed;
                 }
+                //Write messages queued while the client was not subscribed
+                let _ = flush_outbound(&self.tx_pipe, &self.outbound, *self.on_sent_fn.lock().unwrap());
+                Ok(OctopipesCapError::NoError)
             }
         }
     }
 
     /// ###  unsubscribe
     ///
-    /// `unsubscribe` unsubscribe from Octopipes server; if thread is running it will be stopped
-
-    pub fn unsubscribe(&mut self) -> Result<(), OctopipesError> {
-        {
+    /// `unsubscribe` unsubscribe from Octopipes server and wait for the result; if thread is running it will be stopped.
+    /// The client is unsubscribed also if the server replies with `NotSubscribed`, since it doesn't know the client anymore.
+    /// If the server doesn't reply in time, `CapTimeout` is returned and the client is left subscribed
+    pub fn unsubscribe(&mut self) -> Result<OctopipesCapError, OctopipesError> {
+        let loop_running: bool = {
             let client_state = self.state.lock().unwrap();
             if *client_state != OctopipesState::Subscribed
                 && *client_state != OctopipesState::Running
+                && *client_state != OctopipesState::Reconnecting
             {
                 return Err(OctopipesError::NotSubscribed);
             }
-        }
-        //Prepare message
+            *client_state != OctopipesState::Subscribed
+        };
+        //Stop loop first, otherwise it would reconnect once the server removes the pipes
+        self.loop_stop()?;
+        //Send UNSUBSCRIPTION and wait for UNSUBSCRIPTION RESULT
         let payload: Vec<u8> = cap::encode_unsubscription();
-        match self.send_cap(payload) {
-            Err(err) => return Err(err),
-            Ok(..) => {}
-        }
-        //Stop loop
-        match self.loop_stop() {
-            Ok(..) => {}
-            Err(err) => return Err(err),
-        }
+        let result: Result<OctopipesCapError, OctopipesError> = match cap_request(
+            &self.cap_pipe,
+            &self.cap_lock,
+            &self.id,
+            &self.version,
+            payload,
+            OctopipesCapMessage::UnsubscriptionResult,
+        ) {
+            Ok(None) => Err(OctopipesError::CapTimeout),
+            Ok(Some(response)) => cap::decode_unsubscription_result(&response.data),
+            Err(err) => Err(err),
+        };
+        let cap_error: OctopipesCapError = match result {
+            Ok(cap_error) => cap_error,
+            Err(err) => {
+                //Still subscribed: restart the loop if it was running
+                *self.state.lock().unwrap() = OctopipesState::Subscribed;
+                if loop_running {
+                    let _ = self.loop_start();
+                }
+                return Err(err);
+            }
+        };
         //Call on unsubscribed
         match self.on_unsubscribed_fn {
             Some(on_unsub) => {
@@ -285,41 +435,116 @@ impl OctopipesClient {
             }
             None => {}
         }
-        //Set state to UNSUBSCRIBED
+        //Set state to UNSUBSCRIBED (the server has removed the pipes)
+        *self.tx_pipe.lock().unwrap() = None;
+        *self.rx_pipe.lock().unwrap() = None;
+        *self.session.lock().unwrap() = None;
         let mut client_state = self.state.lock().unwrap();
         *client_state = OctopipesState::Unsubscribed;
+        Ok(cap_error)
+    }
+
+    /// ###  join_groups
+    ///
+    /// `join_groups` adds groups to the current subscription, without subscribing again, and waits for the result.
+    /// If the server doesn't confirm the join, the groups are left unchanged and the error is returned
+    pub fn join_groups(&mut self, groups: &[String]) -> Result<(), OctopipesError> {
+        self.check_subscribed()?;
+        self.groups_request(cap::encode_join(groups))?;
+        let mut subscribed_groups = self.groups.lock().unwrap();
+        for group in groups {
+            if !subscribed_groups.contains(group) {
+                subscribed_groups.push(group.clone());
+            }
+        }
         Ok(())
     }
 
-    //Send message functions
+    /// ###  leave_groups
+    ///
+    /// `leave_groups` removes groups from the current subscription, without unsubscribing.
+    /// Messages sent to the client id are still received. As for `join_groups`, the groups change only once the server confirms it
+    pub fn leave_groups(&mut self, groups: &[String]) -> Result<(), OctopipesError> {
+        self.check_subscribed()?;
+        self.groups_request(cap::encode_leave(groups))?;
+        self.groups.lock().unwrap().retain(|group| !groups.contains(group));
+        Ok(())
+    }
+
+    /// ###  groups_request
+    ///
+    /// `groups_request` sends a JOIN or a LEAVE to the server through the CAP and waits for the GROUPS RESULT
+    fn groups_request(&self, payload: Vec<u8>) -> Result<(), OctopipesError> {
+        match cap_request(
+            &self.cap_pipe,
+            &self.cap_lock,
+            &self.id,
+            &self.version,
+            payload,
+            OctopipesCapMessage::GroupsResult,
+        )? {
+            None => Err(OctopipesError::CapTimeout),
+            Some(response) => match cap::decode_groups_result(&response.data)? {
+                OctopipesCapError::NoError => Ok(()),
+                OctopipesCapError::NotSubscribed => Err(OctopipesError::NotSubscribed),
+                _ => Err(OctopipesError::BadPacket),
+            },
+        }
+    }
 
-    /// ###  send_cap
+    /// ###  check_subscribed
     ///
-    /// `send_cap` sends a message to server through the CAP
+    /// `check_subscribed` returns NotSubscribed if the client is not subscribed to the server
+    fn check_subscribed(&self) -> Result<(), OctopipesError> {
+        match *self.state.lock().unwrap() {
+            OctopipesState::Subscribed | OctopipesState::Running | OctopipesState::Reconnecting => Ok(()),
+            _ => Err(OctopipesError::NotSubscribed),
+        }
+    }
 
-    fn send_cap(&self, payload: Vec<u8>) -> Result<(), OctopipesError> {
-        //Prepare message
-        let mut message: OctopipesMessage = OctopipesMessage::new(
+    //Discovery
+
+    /// ###  list_clients
+    ///
+    /// `list_clients` asks the server for the ids of the connected clients
+    pub fn list_clients(&self) -> Result<Vec<String>, OctopipesError> {
+        self.query(CapQuery::Clients, "")
+    }
+
+    /// ###  group_members
+    ///
+    /// `group_members` asks the server for the ids of the clients subscribed to a group
+    pub fn group_members(&self, group: &str) -> Result<Vec<String>, OctopipesError> {
+        self.query(CapQuery::GroupMembers, group)
+    }
+
+    /// ###  is_online
+    ///
+    /// `is_online` asks the server whether a client with this id is connected
+    pub fn is_online(&self, client_id: &str) -> Result<bool, OctopipesError> {
+        self.query(CapQuery::IsOnline, client_id).map(|results| results.iter().any(|id| id == client_id))
+    }
+
+    /// ###  query
+    ///
+    /// `query` sends a query to the server through the CAP and waits for the result; arguments longer than 255 bytes are refused with BadPacket
+    fn query(&self, query: CapQuery, argument: &str) -> Result<Vec<String>, OctopipesError> {
+        let payload: Vec<u8> = cap::encode_query(query, argument)?;
+        match cap_request(
+            &self.cap_pipe,
+            &self.cap_lock,
+            &self.id,
             &self.version,
-            &Some(self.id.clone()),
-            &None,
-            60,
-            OctopipesOptions::empty(),
             payload,
-        );
-        //Encode message
-        match serializer::encode_message(&mut message) {
-            Ok(data_out) => {
-                //Write message to cap
-                match pipes::pipe_write(&self.cap_pipe, 5000, data_out) {
-                    Ok(..) => Ok(()),
-                    Err(..) => Err(OctopipesError::WriteFailed),
-                }
-            }
-            Err(err) => Err(err),
+            OctopipesCapMessage::QueryResult,
+        )? {
+            None => Err(OctopipesError::CapTimeout),
+            Some(response) => cap::decode_query_result(&response.data),
         }
     }
 
+    //Send message functions
+
     /// ###  send
     ///
     /// `send` sends a message to a certain remote
@@ -337,21 +562,13 @@ impl OctopipesClient {
         remote: &String,
         data: Vec<u8>,
         ttl: u8,
-        options: OctopipesOptions,
+        mut options: OctopipesOptions,
     ) -> Result<(), OctopipesError> {
-        {
-            let client_state = self.state.lock().unwrap();
-            if *client_state != OctopipesState::Running
-                && *client_state != OctopipesState::Subscribed
-            {
-                return Err(OctopipesError::NotSubscribed);
-            }
-            if self.tx_pipe.is_none() {
-                return Err(OctopipesError::NotSubscribed);
-            }
+        if *self.no_echo.lock().unwrap() {
+            options.insert(OctopipesOptions::NOECHO);
         }
         //Prepare message
-        let mut message: OctopipesMessage = OctopipesMessage::new(
+        let message: OctopipesMessage = OctopipesMessage::new(
             &self.version,
             &Some(self.id.clone()),
             &Some(remote.clone()),
@@ -359,24 +576,56 @@ impl OctopipesClient {
             options,
             data,
         );
-        //Encode message
-        match serializer::encode_message(&mut message) {
-            Ok(data_out) => {
-                //Write message to cap
-                match pipes::pipe_write(&self.tx_pipe.as_ref().unwrap(), 5000, data_out) {
-                    Ok(..) => {
-                        //If on sent callback is set, call on sent
-                        {
-                            if self.on_sent_fn.as_ref().is_some() {
-                                (self.on_sent_fn.as_ref().unwrap())(&message);
-                            }
-                        }
-                        Ok(())
-                    }
-                    Err(..) => Err(OctopipesError::WriteFailed),
-                }
-            }
-            Err(err) => Err(err),
+        //Write message to TX pipe (on sent is called once the message is written, even if it's queued first)
+        send_message(&self.state, &self.tx_pipe, &self.outbound, &message, *self.on_sent_fn.lock().unwrap())
+    }
+
+    /// ###  sender
+    ///
+    /// `sender` returns a clonable OctopipesSender which can be moved to other threads to send messages through this client.
+    /// The sender shares the client's state and settings (no echo, on sent callback), so it can send only while the client is subscribed
+    pub fn sender(&self) -> OctopipesSender {
+        OctopipesSender {
+            id: self.id.clone(),
+            version: self.version,
+            state: Arc::clone(&self.state),
+            tx_pipe: Arc::clone(&self.tx_pipe),
+            outbound: Arc::clone(&self.outbound),
+            no_echo: Arc::clone(&self.no_echo),
+            on_sent_fn: Arc::clone(&self.on_sent_fn),
+        }
+    }
+
+    /// ###  set_outbound_queue
+    ///
+    /// `set_outbound_queue` enables the outbound queue. While the client is not subscribed or if a write to the TX pipe fails,
+    /// messages are stored in the queue (up to capacity, then the overflow policy is applied) and are sent in order once the client is subscribed again.
+    /// If a persistence file is provided, queued messages are stored on disk too and are loaded again from it
+    pub fn set_outbound_queue(
+        &mut self,
+        capacity: usize,
+        overflow: OctopipesOverflowPolicy,
+        persistence: Option<String>,
+    ) -> Result<(), OctopipesError> {
+        let queue: OutboundQueue = OutboundQueue::new(capacity, overflow, persistence)?;
+        *self.outbound.lock().unwrap() = Some(queue);
+        Ok(())
+    }
+
+    /// ###  disable_outbound_queue
+    ///
+    /// `disable_outbound_queue` disables the outbound queue; messages still in the queue are discarded
+    pub fn disable_outbound_queue(&mut self) {
+        *self.outbound.lock().unwrap() = None;
+    }
+
+    /// ###  get_outbound_queue_len
+    ///
+    /// `get_outbound_queue_len` returns the amount of messages waiting in the outbound queue
+    pub fn get_outbound_queue_len(&self) -> usize {
+        match self.outbound.lock().unwrap().as_ref() {
+            Some(queue) => queue.len(),
+            None => 0,
         }
     }
 
@@ -388,29 +637,47 @@ impl OctopipesClient {
     /// If no message is available Ok(None) is returned
     /// If there was an error while reading inbox, Err(OctopipesError) is returned
     pub fn get_next_message(&self) -> Result<Option<OctopipesMessage>, OctopipesError> {
-        {
-            //Check if thread is running
-            let current_state = self.state.lock().unwrap();
-            if *current_state != OctopipesState::Running {
-                return Err(OctopipesError::Uninitialized);
-            }
-        }
         //Try receive
-        match self.client_receiver.as_ref() {
-            None => Err(OctopipesError::Uninitialized),
-            Some(receiver) => match receiver.try_recv() {
-                Ok(payload) => match payload {
-                    Ok(message) => Ok(Some(message)),
-                    Err(error) => Err(error),
-                },
-                Err(error) => match error {
-                    mpsc::TryRecvError::Empty => Ok(None),
-                    _ => Err(OctopipesError::ThreadError),
-                },
+        match self.inbox.pop() {
+            Some(payload) => match payload {
+                Ok(message) => Ok(Some(message)),
+                Err(error) => Err(error),
             },
+            None => {
+                //Check if thread is running
+                let current_state = self.state.lock().unwrap();
+                if *current_state != OctopipesState::Running
+                    && *current_state != OctopipesState::Reconnecting
+                {
+                    return Err(OctopipesError::Uninitialized);
+                }
+                Ok(None)
+            }
         }
     }
 
+    /// ###  set_inbox_capacity
+    ///
+    /// `set_inbox_capacity` sets the maximum amount of messages (None for unbounded, which is the default) which can wait in the inbox
+    /// and the policy to apply when a message is received and the inbox is full. With a capacity of 0 every message is dropped
+    pub fn set_inbox_capacity(&mut self, capacity: Option<usize>, policy: OctopipesInboxPolicy) {
+        self.inbox.set_capacity(capacity, policy);
+    }
+
+    /// ###  get_inbox_len
+    ///
+    /// `get_inbox_len` returns the amount of messages waiting in the inbox
+    pub fn get_inbox_len(&self) -> usize {
+        self.inbox.len()
+    }
+
+    /// ###  get_dropped_messages
+    ///
+    /// `get_dropped_messages` returns the amount of received messages which have been dropped because the inbox was full
+    pub fn get_dropped_messages(&self) -> u64 {
+        self.inbox.dropped()
+    }
+
     /// ###  get_all_message
     ///
     /// `get_all_message` Gets all the available messages on the receiver
@@ -430,6 +697,55 @@ impl OctopipesClient {
         Ok(inbox)
     }
 
+    //Introspection
+
+    /// ###  state
+    ///
+    /// `state` returns the current state of the client
+    pub fn state(&self) -> OctopipesState {
+        *self.state.lock().unwrap()
+    }
+
+    /// ###  id
+    ///
+    /// `id` returns the client id
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// ###  groups
+    ///
+    /// `groups` returns the groups the client subscribed to (empty if it never subscribed)
+    pub fn groups(&self) -> Vec<String> {
+        self.groups.lock().unwrap().clone()
+    }
+
+    /// ###  assigned_pipes
+    ///
+    /// `assigned_pipes` returns the pipes (tx, rx) assigned by the server, if the client is subscribed
+    pub fn assigned_pipes(&self) -> Option<(String, String)> {
+        let tx_pipe: Option<String> = self.tx_pipe.lock().unwrap().clone();
+        let rx_pipe: Option<String> = self.rx_pipe.lock().unwrap().clone();
+        match (tx_pipe, rx_pipe) {
+            (Some(tx_pipe), Some(rx_pipe)) => Some((tx_pipe, rx_pipe)),
+            _ => None,
+        }
+    }
+
+    /// ###  subscribed_since
+    ///
+    /// `subscribed_since` returns the time of the last successful subscription (reconnections included), or None if not subscribed
+    pub fn subscribed_since(&self) -> Option<SystemTime> {
+        self.session.lock().unwrap().map(|session| session.subscribed_since)
+    }
+
+    /// ###  server_version
+    ///
+    /// `server_version` returns the protocol version the server replied with to the subscription, or None if not subscribed
+    pub fn server_version(&self) -> Option<OctopipesProtocolVersion> {
+        self.session.lock().unwrap().map(|session| session.server_version)
+    }
+
     //Callbacks setters
 
     /// ###  set_on_received_callback
@@ -444,9 +760,10 @@ impl OctopipesClient {
 
     /// ###  set_on_sent_callbacl
     ///
-    /// `set_on_sent_callbacl` sets the function to call when a message is sent
+    /// `set_on_sent_callbacl` sets the function to call when a message is sent.
+    /// Queued messages are reported once they're written to the TX pipe. It applies to the senders already created too
     pub fn set_on_sent_callback(&mut self, callback: fn(&OctopipesMessage)) {
-        self.on_sent_fn = Some(callback);
+        *self.on_sent_fn.lock().unwrap() = Some(callback);
     }
 
     /// ###  set_on_subscribed
@@ -462,6 +779,474 @@ impl OctopipesClient {
     pub fn set_on_unsubscribed(&mut self, callback: fn()) {
         self.on_unsubscribed_fn = Some(callback);
     }
+
+    /// ###  set_on_connection_lost
+    ///
+    /// `set_on_connection_lost` sets the function to call when the client loop detects the server has gone away and starts reconnecting.
+    /// Must be set before `loop_start`
+    pub fn set_on_connection_lost(&mut self, callback: fn()) {
+        self.on_connection_lost_fn = Some(callback);
+    }
+
+    /// ###  set_on_reconnected
+    ///
+    /// `set_on_reconnected` sets the function to call when the client loop has subscribed again to the server.
+    /// Must be set before `loop_start`
+    pub fn set_on_reconnected(&mut self, callback: fn()) {
+        self.on_reconnected_fn = Some(callback);
+    }
+
+    /// ###  set_on_kicked
+    ///
+    /// `set_on_kicked` sets the function to call when the server has kicked the client.
+    /// The callback receives the reason provided by the server; the client is then unsubscribed
+    pub fn set_on_kicked(&mut self, callback: fn(&str)) {
+        self.on_kicked_fn = Some(callback);
+    }
+
+    /// ###  set_on_server_shutdown
+    ///
+    /// `set_on_server_shutdown` sets the function to call when the server announces a planned shutdown.
+    /// Once the server has stopped, the client is unsubscribed and `ServerShutdown` is put in the inbox
+    pub fn set_on_server_shutdown(&mut self, callback: fn()) {
+        self.on_server_shutdown_fn = Some(callback);
+    }
+
+    /// ###  set_reconnect_policy
+    ///
+    /// `set_reconnect_policy` enables (or disables if None) automatic reconnection. When the server goes away,
+    /// the client loop subscribes again with the original groups and swaps in the new assigned pipes.
+    /// Must be set before `loop_start`
+    pub fn set_reconnect_policy(&mut self, policy: Option<OctopipesReconnectPolicy>) {
+        self.reconnect_policy = policy;
+    }
+
+    /// ###  set_no_echo
+    ///
+    /// `set_no_echo` sets whether the messages sent by this client must not be delivered back to it,
+    /// even if it is subscribed to their group. It applies to the senders already created too
+    pub fn set_no_echo(&mut self, no_echo: bool) {
+        *self.no_echo.lock().unwrap() = no_echo;
+    }
+
+    /// ###  set_durable
+    ///
+    /// `set_durable` sets whether the next subscriptions are durable: while the client is offline (unsubscribed or reaped),
+    /// the server stores the messages sent to its groups and delivers them once the client subscribes again with the same id and reads its pipe.
+    /// A non durable subscription discards the stored messages. Must be set before `subscribe`
+    pub fn set_durable(&mut self, durable: bool) {
+        self.durable = durable;
+    }
+
+    /// ###  set_heartbeat_interval
+    ///
+    /// `set_heartbeat_interval` sets how often the client loop sends a heartbeat to the server through the CAP (disabled by default).
+    /// Heartbeats are sent only while the client loop is running; None disables them. Must be set before `loop_start`.
+    /// Clients talking to a server with a client timeout must enable them if they can be silent for longer than the timeout, otherwise they get reaped.
+    /// If heartbeats can't be written to the CAP for a few times in a row, the server is considered lost (see `set_reconnect_policy`)
+    pub fn set_heartbeat_interval(&mut self, interval: Option<Duration>) {
+        self.heartbeat_interval = interval;
+    }
+
+    //Handlers
+
+    /// ###  on_group
+    ///
+    /// `on_group` registers the handler to call, from the client loop, for each message sent to a certain group (or to this client id).
+    /// Messages handled by a handler are not put in the inbox. Handlers can be registered both before and after `loop_start`
+    pub fn on_group(&mut self, group: &str, handler: fn(&OctopipesMessage)) {
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.groups.insert(group.to_string(), handler);
+    }
+
+    /// ###  on_fallback
+    ///
+    /// `on_fallback` registers the handler to call for the messages which don't have a group handler.
+    /// If no fallback is set, these messages are put in the inbox
+    pub fn on_fallback(&mut self, handler: fn(&OctopipesMessage)) {
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.fallback = Some(handler);
+    }
+
+    /// ###  remove_group_handler
+    ///
+    /// `remove_group_handler` removes the handler associated to a group. Returns whether a handler was registered
+    pub fn remove_group_handler(&mut self, group: &str) -> bool {
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.groups.remove(group).is_some()
+    }
+
+    /// ###  remove_fallback
+    ///
+    /// `remove_fallback` removes the fallback handler; unhandled messages will be put in the inbox again
+    pub fn remove_fallback(&mut self) {
+        let mut handlers = self.handlers.lock().unwrap();
+        handlers.fallback = None;
+    }
+}
+
+impl OctopipesSender {
+    /// ###  send
+    ///
+    /// `send` sends a message to a certain remote through the client's TX pipe
+    pub fn send(&self, remote: &str, data: Vec<u8>) -> Result<(), OctopipesError> {
+        self.send_ex(remote, data, 0, OctopipesOptions::empty())
+    }
+
+    /// ###  send_ex
+    ///
+    /// `send_ex` sends a message to a certain remote with extended options through the client's TX pipe
+    pub fn send_ex(
+        &self,
+        remote: &str,
+        data: Vec<u8>,
+        ttl: u8,
+        mut options: OctopipesOptions,
+    ) -> Result<(), OctopipesError> {
+        if *self.no_echo.lock().unwrap() {
+            options.insert(OctopipesOptions::NOECHO);
+        }
+        //Prepare message
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &self.version,
+            &Some(self.id.clone()),
+            &Some(remote.to_string()),
+            ttl,
+            options,
+            data,
+        );
+        send_message(&self.state, &self.tx_pipe, &self.outbound, &message, *self.on_sent_fn.lock().unwrap())
+    }
+}
+
+impl MessageHandlers {
+    /// ###  new
+    ///
+    /// `new` instances a new empty MessageHandlers
+    fn new() -> MessageHandlers {
+        MessageHandlers {
+            groups: HashMap::new(),
+            fallback: None,
+        }
+    }
+
+    /// ###  get_handler
+    ///
+    /// `get_handler` returns the handler for the message recipient, or the fallback if no handler is registered for it.
+    /// If no handler is registered for the exact group, the handler with the longest group (with wildcards or queue group) matching it is returned
+    fn get_handler(&self, message: &OctopipesMessage) -> Option<fn(&OctopipesMessage)> {
+        let remote: &String = match message.remote.as_ref() {
+            Some(remote) => remote,
+            None => return self.fallback,
+        };
+        match self.groups.get(remote) {
+            Some(handler) => Some(*handler),
+            None => match self
+                .groups
+                .iter()
+                .filter(|(group, _)| topic::subscription_matches(group, remote))
+                .max_by_key(|(group, _)| group.len())
+            {
+                Some((_, handler)) => Some(*handler),
+                None => self.fallback,
+            },
+        }
+    }
+}
+
+//@! Privates
+
+/// ###  cap_send
+///
+/// `cap_send` sends a message to server through the CAP
+fn cap_send(
+    cap_pipe: &str,
+    client_id: &str,
+    version: &OctopipesProtocolVersion,
+    payload: Vec<u8>,
+) -> Result<(), OctopipesError> {
+    //Prepare message
+    let message: OctopipesMessage = OctopipesMessage::new(
+        version,
+        &Some(client_id.to_string()),
+        &None,
+        60,
+        OctopipesOptions::empty(),
+        payload,
+    );
+    //Encode message
+    match serializer::encode_message(&message) {
+        Ok(data_out) => {
+            //Write message to cap
+            match pipes::pipe_write(cap_pipe, 5000, &data_out) {
+                Ok(..) => Ok(()),
+                Err(..) => Err(OctopipesError::WriteFailed),
+            }
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// ###  cap_subscribe
+///
+/// `cap_subscribe` sends a subscription payload through the CAP and waits for the assignment.
+/// Returns the CAP error and the assigned pipes (tx, rx)
+fn cap_subscribe(
+    cap_pipe: &str,
+    cap_lock: &Mutex<()>,
+    client_id: &str,
+    version: &OctopipesProtocolVersion,
+    payload: Vec<u8>,
+) -> Result<(OctopipesCapError, Option<String>, Option<String>, OctopipesProtocolVersion), OctopipesError> {
+    //Send message through the CAP and wait for ASSIGNMENT
+    match cap_request(cap_pipe, cap_lock, client_id, version, payload, OctopipesCapMessage::Assignment)? {
+        None => Err(OctopipesError::NoDataAvailable),
+        Some(response) => {
+            //Ok, is an ASSIGNMENT; parse assignment params
+            let server_version: OctopipesProtocolVersion = response.version;
+            cap::decode_assignment(&response.data)
+                .map(|(cap_error, pipe_tx, pipe_rx)| (cap_error, pipe_tx, pipe_rx, server_version))
+        }
+    }
+}
+
+/// ###  cap_request
+///
+/// `cap_request` sends a request through the CAP and waits for the server reply on the client's reply pipe.
+/// Returns None if the server didn't reply in time
+fn cap_request(
+    cap_pipe: &str,
+    cap_lock: &Mutex<()>,
+    client_id: &str,
+    version: &OctopipesProtocolVersion,
+    payload: Vec<u8>,
+    expected: OctopipesCapMessage,
+) -> Result<Option<OctopipesMessage>, OctopipesError> {
+    //Only one request at a time can wait on the reply pipe
+    let _cap_guard = cap_lock.lock().unwrap();
+    //Create reply pipe before sending the request, so the server can write to it
+    let reply_pipe: String = cap::reply_pipe(cap_pipe, client_id);
+    if pipes::pipe_create(&reply_pipe).is_err() {
+        return Err(OctopipesError::OpenFailed);
+    }
+    let result = cap_send(cap_pipe, client_id, version, payload)
+        .and_then(|_| cap_read_reply(&reply_pipe, expected));
+    let _ = pipes::pipe_delete(&reply_pipe);
+    result
+}
+
+/// ###  cap_read_reply
+///
+/// `cap_read_reply` waits for the server reply on the reply pipe, which must be of the expected type.
+/// Returns None if the server didn't reply in time
+fn cap_read_reply(
+    reply_pipe: &str,
+    expected: OctopipesCapMessage,
+) -> Result<Option<OctopipesMessage>, OctopipesError> {
+    match pipes::pipe_read(reply_pipe, 5000) {
+        Err(..) => Err(OctopipesError::ReadFailed),
+        Ok(None) => Ok(None),
+        Ok(Some(data_in)) => {
+            //Parse message
+            let response: OctopipesMessage = serializer::decode_message(data_in)?;
+            //Check message type
+            if cap::get_cap_message_type(&response.data)? != expected {
+                return Err(OctopipesError::BadPacket);
+            }
+            Ok(Some(response))
+        }
+    }
+}
+
+/// ###  is_server_message
+///
+/// `is_server_message` returns whether the message has been sent by the server itself to this client
+fn is_server_message(message: &OctopipesMessage, client_id: &str) -> bool {
+    //Server messages have no origin and are addressed to the client itself (the server rejects client frames without their own origin)
+    message.origin.is_none() && message.remote.as_deref() == Some(client_id)
+}
+
+/// ###  get_disconnect_reason
+///
+/// `get_disconnect_reason` returns the reason if the message is a DISCONNECT sent by the server to this client
+fn get_disconnect_reason(message: &OctopipesMessage, client_id: &str) -> Option<String> {
+    if !is_server_message(message, client_id) {
+        return None;
+    }
+    cap::decode_disconnect(&message.data).ok()
+}
+
+/// ###  reconnect
+///
+/// `reconnect` subscribes again to the server, retrying with the backoff described by the reconnect policy.
+/// Returns the new assigned pipes (tx, rx) and the server version or None if attempts are exhausted or the client is stopped meanwhile
+fn reconnect(
+    cap_pipe: &str,
+    cap_lock: &Mutex<()>,
+    client_id: &str,
+    version: &OctopipesProtocolVersion,
+    subscription: &[u8],
+    policy: &OctopipesReconnectPolicy,
+    state: &Mutex<OctopipesState>,
+) -> Option<(String, String, OctopipesProtocolVersion)> {
+    let mut delay: Duration = policy.initial_delay;
+    let mut attempts: usize = 0;
+    loop {
+        //Wait before the next attempt, checking whether the client is being stopped
+        let t_start: Instant = Instant::now();
+        loop {
+            if *state.lock().unwrap() != OctopipesState::Reconnecting {
+                return None;
+            }
+            let elapsed: Duration = t_start.elapsed();
+            if elapsed >= delay {
+                break;
+            }
+            thread::sleep(std::cmp::min(delay - elapsed, Duration::from_millis(100)));
+        }
+        if let Ok((OctopipesCapError::NoError, Some(tx_pipe), Some(rx_pipe), server_version)) =
+            cap_subscribe(cap_pipe, cap_lock, client_id, version, subscription.to_vec())
+        {
+            return Some((tx_pipe, rx_pipe, server_version));
+        }
+        attempts += 1;
+        if let Some(max_attempts) = policy.max_attempts {
+            if attempts >= max_attempts {
+                return None;
+            }
+        }
+        delay = std::cmp::min(delay * 2, policy.max_delay);
+    }
+}
+
+/// ###  send_message
+///
+/// `send_message` encodes a message and writes it to the TX pipe, if the client is subscribed.
+/// If the outbound queue is enabled, queued messages are written first and the message is queued if it can't be written.
+/// On sent is called for each message written to the TX pipe
+fn send_message(
+    state: &Mutex<OctopipesState>,
+    tx_pipe: &Mutex<Option<String>>,
+    outbound: &Mutex<Option<OutboundQueue>>,
+    message: &OctopipesMessage,
+    on_sent: Option<fn(&OctopipesMessage)>,
+) -> Result<(), OctopipesError> {
+    //Encode message
+    let data_out: Vec<u8> = serializer::encode_message(message)?;
+    let subscribed: bool = {
+        let client_state = state.lock().unwrap();
+        *client_state == OctopipesState::Running || *client_state == OctopipesState::Subscribed
+    };
+    let mut outbound = outbound.lock().unwrap();
+    match outbound.as_mut() {
+        None => {
+            if !subscribed {
+                return Err(OctopipesError::NotSubscribed);
+            }
+            write_frame(tx_pipe, data_out)?;
+        }
+        Some(queue) => {
+            //Queued messages must be written first, to preserve order
+            if !subscribed
+                || queue.flush(|frame| write_queued_frame(tx_pipe, frame, on_sent)).is_err()
+                || write_frame(tx_pipe, data_out.clone()).is_err()
+            {
+                return queue.push(data_out);
+            }
+        }
+    }
+    if let Some(on_sent) = on_sent {
+        (on_sent)(message);
+    }
+    Ok(())
+}
+
+/// ###  flush_outbound
+///
+/// `flush_outbound` writes the messages in the outbound queue (if enabled) to the TX pipe
+fn flush_outbound(
+    tx_pipe: &Mutex<Option<String>>,
+    outbound: &Mutex<Option<OutboundQueue>>,
+    on_sent: Option<fn(&OctopipesMessage)>,
+) -> Result<(), OctopipesError> {
+    let mut outbound = outbound.lock().unwrap();
+    match outbound.as_mut() {
+        Some(queue) => queue.flush(|frame| write_queued_frame(tx_pipe, frame, on_sent)),
+        None => Ok(()),
+    }
+}
+
+/// ###  write_queued_frame
+///
+/// `write_queued_frame` writes a frame taken from the outbound queue to the TX pipe, then calls on sent with the decoded message
+fn write_queued_frame(
+    tx_pipe: &Mutex<Option<String>>,
+    frame: Vec<u8>,
+    on_sent: Option<fn(&OctopipesMessage)>,
+) -> Result<(), OctopipesError> {
+    let message: Option<OctopipesMessage> = match on_sent {
+        Some(..) => serializer::decode_message(frame.clone()).ok(),
+        None => None,
+    };
+    write_frame(tx_pipe, frame)?;
+    if let (Some(on_sent), Some(message)) = (on_sent, message) {
+        (on_sent)(&message);
+    }
+    Ok(())
+}
+
+/// ###  write_frame
+///
+/// `write_frame` writes an encoded message to the TX pipe.
+/// The TX pipe lock is held for the entire write, so frames written by different threads are never interleaved
+fn write_frame(tx_pipe: &Mutex<Option<String>>, data_out: Vec<u8>) -> Result<(), OctopipesError> {
+    let tx_pipe = tx_pipe.lock().unwrap();
+    match tx_pipe.as_ref() {
+        None => Err(OctopipesError::NotSubscribed),
+        Some(tx_pipe) => match pipes::pipe_write(tx_pipe, 5000, &data_out) {
+            Ok(..) => Ok(()),
+            Err(..) => Err(OctopipesError::WriteFailed),
+        },
+    }
+}
+
+impl AsRawFd for OctopipesClient {
+    /// ###  as_raw_fd
+    ///
+    /// `as_raw_fd` returns a file descriptor which is readable while there are messages in the client's inbox.
+    /// It can be used to wait for messages with select/poll or with an event loop; it must never be read by the user
+    fn as_raw_fd(&self) -> RawFd {
+        self.inbox.readiness_fd()
+    }
+}
+
+#[cfg(feature = "mio")]
+impl mio::event::Source for OctopipesClient {
+    /// ###  register
+    ///
+    /// `register` registers the client readiness fd in the mio registry. Since mio is edge triggered,
+    /// once the client becomes readable, messages should be fetched until `get_next_message` returns None
+    fn register(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &mio::Registry,
+        token: mio::Token,
+        interests: mio::Interest,
+    ) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &mio::Registry) -> std::io::Result<()> {
+        mio::unix::SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
 }
 
 impl Drop for OctopipesClient {
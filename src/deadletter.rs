@@ -0,0 +1,159 @@
+//! ## Dead letter
+//!
+//! `deadletter` is the module which takes care of encoding and decoding the dead letters
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::serializer;
+use super::OctopipesDeadLetter;
+use super::OctopipesDeadLetterReason;
+use super::OctopipesError;
+use super::OctopipesMessage;
+
+impl OctopipesDeadLetter {
+    /// ### new
+    ///
+    /// `new` instances a new dead letter for a message
+    pub(crate) fn new(reason: OctopipesDeadLetterReason, recipient: Option<&str>, message: &OctopipesMessage) -> OctopipesDeadLetter {
+        OctopipesDeadLetter {
+            reason,
+            recipient: recipient.map(|recipient| recipient.to_string()),
+            message: message.clone(),
+        }
+    }
+
+    /// ### encode
+    ///
+    /// `encode` encodes the dead letter into a message payload:
+    /// reason (1 byte), recipient (len + bytes; len is 0 if there's no recipient), undeliverable message (encoded frame).
+    /// Returns BadPacket if the recipient is longer than 255 bytes
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, OctopipesError> {
+        let frame: Vec<u8> = serializer::encode_message(&self.message)?;
+        let recipient: &str = self.recipient.as_deref().unwrap_or("");
+        if recipient.len() > u8::MAX as usize {
+            return Err(OctopipesError::BadPacket);
+        }
+        let mut payload: Vec<u8> = Vec::with_capacity(2 + recipient.len() + frame.len());
+        payload.push(self.reason as u8);
+        payload.push(recipient.len() as u8);
+        payload.extend_from_slice(recipient.as_bytes());
+        payload.extend_from_slice(&frame);
+        Ok(payload)
+    }
+
+    /// ### decode
+    ///
+    /// `decode` decodes a dead letter from the payload of a message received from the dead letter group
+    pub fn decode(data: &[u8]) -> Result<OctopipesDeadLetter, OctopipesError> {
+        if data.len() < 2 {
+            return Err(OctopipesError::BadPacket);
+        }
+        let reason: OctopipesDeadLetterReason = match OctopipesDeadLetterReason::from_u8(data[0]) {
+            Some(reason) => reason,
+            None => return Err(OctopipesError::BadPacket),
+        };
+        //Get recipient
+        let final_index: usize = 2 + data[1] as usize;
+        if final_index > data.len() {
+            return Err(OctopipesError::BadPacket);
+        }
+        let recipient: Option<String> = match data[1] {
+            0 => None,
+            _ => Some(data[2..final_index].iter().map(|byte| *byte as char).collect()),
+        };
+        let message: OctopipesMessage = serializer::decode_message(data[final_index..].to_vec())?;
+        Ok(OctopipesDeadLetter {
+            reason,
+            recipient,
+            message,
+        })
+    }
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OctopipesOptions;
+    use crate::OctopipesProtocolVersion;
+
+    #[test]
+    fn test_dead_letter_encode_decode() {
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("sender")),
+            &Some(String::from("jobs")),
+            5,
+            OctopipesOptions::RCK,
+            vec![1, 2, 3],
+        );
+        let dead_letter: OctopipesDeadLetter = OctopipesDeadLetter::new(OctopipesDeadLetterReason::Expired, Some("worker"), &message);
+        let payload: Vec<u8> = dead_letter.encode().unwrap();
+        assert_eq!(payload[0], 0x02, "Payload at 0 should be the reason");
+        let decoded: OctopipesDeadLetter = OctopipesDeadLetter::decode(&payload).unwrap();
+        assert_eq!(decoded.reason, OctopipesDeadLetterReason::Expired);
+        assert_eq!(decoded.recipient, Some(String::from("worker")));
+        assert_eq!(decoded.message.origin, Some(String::from("sender")));
+        assert_eq!(decoded.message.remote, Some(String::from("jobs")));
+        assert!(decoded.message.isset_option(OctopipesOptions::RCK));
+        assert_eq!(decoded.message.data, vec![1, 2, 3]);
+        //Without recipient
+        let dead_letter: OctopipesDeadLetter = OctopipesDeadLetter::new(OctopipesDeadLetterReason::NoSubscribers, None, &message);
+        let decoded: OctopipesDeadLetter = OctopipesDeadLetter::decode(&dead_letter.encode().unwrap()).unwrap();
+        assert_eq!(decoded.reason, OctopipesDeadLetterReason::NoSubscribers);
+        assert_eq!(decoded.recipient, None);
+        //Recipient too long
+        let long_recipient: String = "a".repeat(256);
+        let dead_letter: OctopipesDeadLetter = OctopipesDeadLetter::new(OctopipesDeadLetterReason::Expired, Some(&long_recipient), &message);
+        assert_eq!(dead_letter.encode().err().unwrap(), OctopipesError::BadPacket);
+        //Bad payloads
+        assert!(OctopipesDeadLetter::decode(&[0x01]).is_err());
+        assert!(OctopipesDeadLetter::decode(&[0x09, 0x00]).is_err(), "Unknown reason");
+        assert!(OctopipesDeadLetter::decode(&[0x01, 0x04, 0x61]).is_err(), "Truncated recipient");
+        assert!(OctopipesDeadLetter::decode(&payload[..payload.len() - 1]).is_err(), "Truncated message");
+    }
+
+    #[test]
+    fn test_nack_reason() {
+        let nack: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &None,
+            &Some(String::from("sender")),
+            1,
+            OctopipesOptions::NACK,
+            vec![OctopipesDeadLetterReason::NoSubscribers as u8],
+        );
+        assert_eq!(nack.nack_reason(), Some(OctopipesDeadLetterReason::NoSubscribers));
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("client")),
+            &Some(String::from("sender")),
+            1,
+            OctopipesOptions::NACK,
+            vec![0x01],
+        );
+        assert_eq!(message.nack_reason(), None, "NACKs from clients must be ignored");
+    }
+}
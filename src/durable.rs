@@ -0,0 +1,210 @@
+//! ## Durable
+//!
+//! `durable` is the module which takes care of storing messages for durable clients while they're offline
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::topic;
+use super::DurableSession;
+use super::OctopipesDurableLimits;
+use super::StoredMessage;
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+impl DurableSession {
+    /// ### new
+    ///
+    /// `new` instances a new empty DurableSession for a client subscribed to the provided groups
+    pub(crate) fn new(groups: Vec<String>) -> DurableSession {
+        DurableSession {
+            groups,
+            offline_since: Instant::now(),
+            messages: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+
+    /// ### matches
+    ///
+    /// `matches` returns whether a message sent to the provided group must be stored for this client.
+    /// Queue groups are not considered, since their messages are delivered to the members which are online
+    pub(crate) fn matches(&self, group: &str) -> bool {
+        self.groups
+            .iter()
+            .any(|subscription| topic::parse_queue_group(subscription).is_none() && topic::topic_matches(subscription, group))
+    }
+
+    /// ### is_expired
+    ///
+    /// `is_expired` returns whether the client has been offline for longer than the max age, so it's not expected to come back
+    pub(crate) fn is_expired(&self, limits: &OctopipesDurableLimits) -> bool {
+        self.offline_since.elapsed() > limits.max_age
+    }
+
+    /// ### push
+    ///
+    /// `push` stores an encoded message; the oldest messages are discarded if limits are exceeded
+    pub(crate) fn push(&mut self, data: &[u8], ttl: u8, limits: &OctopipesDurableLimits) {
+        self.bytes += data.len();
+        self.messages.push_back(StoredMessage {
+            stored_at: Instant::now(),
+            ttl,
+            data: data.to_vec(),
+        });
+        self.shrink(limits);
+    }
+
+    /// ### restore
+    ///
+    /// `restore` puts back the messages the client didn't read before going offline again; they come before the ones stored since
+    pub(crate) fn restore(&mut self, mut messages: VecDeque<StoredMessage>, limits: &OctopipesDurableLimits) {
+        self.bytes += messages.iter().map(|message| message.data.len()).sum::<usize>();
+        messages.append(&mut self.messages);
+        self.messages = messages;
+        self.shrink(limits);
+    }
+
+    /// ### take
+    ///
+    /// `take` returns the stored messages which haven't expired yet, in the order they were stored
+    pub(crate) fn take(mut self, limits: &OctopipesDurableLimits) -> VecDeque<StoredMessage> {
+        self.expire(limits);
+        self.messages
+    }
+
+    /// ### shrink
+    ///
+    /// `shrink` discards the expired messages and then the oldest ones until the limits are respected
+    fn shrink(&mut self, limits: &OctopipesDurableLimits) {
+        self.expire(limits);
+        while self.messages.len() > limits.max_messages || self.bytes > limits.max_bytes {
+            self.pop();
+        }
+    }
+
+    /// ### expire
+    ///
+    /// `expire` discards the messages older than the max age
+    fn expire(&mut self, limits: &OctopipesDurableLimits) {
+        while let Some(message) = self.messages.front() {
+            if message.stored_at.elapsed() <= limits.max_age {
+                break;
+            }
+            self.pop();
+        }
+    }
+
+    /// ### pop
+    ///
+    /// `pop` discards the oldest message
+    fn pop(&mut self) {
+        if let Some(message) = self.messages.pop_front() {
+            self.bytes -= message.data.len();
+        }
+    }
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn test_durable_session_matches() {
+        let session: DurableSession = DurableSession::new(vec![
+            String::from("sensors/#"),
+            String::from("$share/workers/jobs"),
+            String::from("client"),
+        ]);
+        assert!(session.matches("sensors/kitchen/temp"));
+        assert!(session.matches("client"));
+        assert!(!session.matches("jobs"), "Queue groups shouldn't be stored");
+        assert!(!session.matches("alarms"));
+    }
+
+    #[test]
+    fn test_durable_session_limits() {
+        let limits: OctopipesDurableLimits = OctopipesDurableLimits {
+            max_messages: 3,
+            max_bytes: 10,
+            max_age: Duration::from_millis(200),
+        };
+        let mut session: DurableSession = DurableSession::new(vec![String::from("client")]);
+        //Count
+        for i in 0..4 {
+            session.push(&[i], 5, &limits);
+        }
+        assert_eq!(session.messages.len(), 3);
+        assert_eq!(session.bytes, 3);
+        assert_eq!(session.messages.front().unwrap().data, vec![1], "Oldest message should have been discarded");
+        //Bytes
+        session.push(&[4, 4, 4, 4, 4, 4, 4, 4], 5, &limits);
+        assert_eq!(session.bytes, 10);
+        let payloads: Vec<Vec<u8>> = session.messages.iter().map(|message| message.data.clone()).collect();
+        assert_eq!(payloads, vec![vec![2], vec![3], vec![4, 4, 4, 4, 4, 4, 4, 4]]);
+        //Age
+        sleep(Duration::from_millis(300));
+        session.push(&[5], 7, &limits);
+        let messages: VecDeque<StoredMessage> = session.take(&limits);
+        assert_eq!(messages.len(), 1, "Expired messages should have been discarded");
+        assert_eq!(messages[0].data, vec![5]);
+        assert_eq!(messages[0].ttl, 7);
+    }
+
+    #[test]
+    fn test_durable_session_restore() {
+        let limits: OctopipesDurableLimits = OctopipesDurableLimits {
+            max_messages: 3,
+            max_bytes: 10,
+            max_age: Duration::from_secs(60),
+        };
+        let mut unread: DurableSession = DurableSession::new(vec![String::from("client")]);
+        unread.push(&[0], 5, &limits);
+        unread.push(&[1], 5, &limits);
+        let mut session: DurableSession = DurableSession::new(vec![String::from("client")]);
+        session.push(&[2], 5, &limits);
+        session.push(&[3], 5, &limits);
+        session.restore(unread.take(&limits), &limits);
+        assert_eq!(session.bytes, 3);
+        let payloads: Vec<Vec<u8>> = session.messages.iter().map(|message| message.data.clone()).collect();
+        assert_eq!(payloads, vec![vec![1], vec![2], vec![3]], "Unread messages should come first and the oldest should be discarded");
+    }
+
+    #[test]
+    fn test_durable_session_expired() {
+        let limits: OctopipesDurableLimits = OctopipesDurableLimits {
+            max_messages: 3,
+            max_bytes: 10,
+            max_age: Duration::from_millis(200),
+        };
+        let session: DurableSession = DurableSession::new(vec![String::from("client")]);
+        assert!(!session.is_expired(&limits));
+        sleep(Duration::from_millis(300));
+        assert!(session.is_expired(&limits), "Session should expire after max age");
+    }
+}
@@ -0,0 +1,305 @@
+//! ## Inbox
+//!
+//! `inbox` is the module which takes care of managing the client's inbox
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::Inbox;
+use super::InboxQueue;
+use super::OctopipesError;
+use super::OctopipesInboxPolicy;
+use super::OctopipesMessage;
+use super::OctopipesState;
+
+extern crate libc;
+
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+impl Inbox {
+    /// ### Inbox Constructor
+    ///
+    /// `new` instances a new unbounded Inbox
+    pub(crate) fn new() -> Inbox {
+        Inbox {
+            readiness: readiness_pipe(),
+            queue: Mutex::new(InboxQueue {
+                messages: VecDeque::new(),
+                capacity: None,
+                policy: OctopipesInboxPolicy::Block,
+                dropped: 0,
+            }),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// ### set_capacity
+    ///
+    /// `set_capacity` sets the inbox capacity (None for unbounded) and the policy to apply when it's full
+    pub(crate) fn set_capacity(&self, capacity: Option<usize>, policy: OctopipesInboxPolicy) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.capacity = capacity;
+        queue.policy = policy;
+        //Wake up blocked writer, since the capacity may have changed
+        self.not_full.notify_all();
+    }
+
+    /// ### push
+    ///
+    /// `push` puts a message in the inbox. If the inbox is full the policy is applied:
+    /// with Block, the caller waits until there's room for the message or until the client loop is stopped (in that case the message is dropped).
+    /// An inbox with capacity 0 can't ever make room, so every message is dropped whatever the policy
+    pub(crate) fn push(&self, message: Result<OctopipesMessage, OctopipesError>, state: &Mutex<OctopipesState>) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            let capacity: usize = match queue.capacity {
+                None => break,
+                Some(capacity) => capacity,
+            };
+            if queue.messages.len() < capacity {
+                break;
+            }
+            if capacity == 0 {
+                queue.dropped += 1;
+                return;
+            }
+            match queue.policy {
+                OctopipesInboxPolicy::DropOldest => {
+                    queue.messages.pop_front();
+                    queue.dropped += 1;
+                }
+                OctopipesInboxPolicy::DropNewest => {
+                    queue.dropped += 1;
+                    return;
+                }
+                OctopipesInboxPolicy::Block => {
+                    //Check if client loop is being stopped
+                    {
+                        let current_state = state.lock().unwrap();
+                        if *current_state != OctopipesState::Running && *current_state != OctopipesState::Reconnecting {
+                            queue.dropped += 1;
+                            return;
+                        }
+                    }
+                    queue = self.not_full.wait_timeout(queue, Duration::from_millis(100)).unwrap().0;
+                }
+            }
+        }
+        if queue.messages.is_empty() {
+            //Inbox becomes readable
+            self.set_ready(true);
+        }
+        queue.messages.push_back(message);
+    }
+
+    /// ### pop
+    ///
+    /// `pop` takes the first message out of the inbox
+    pub(crate) fn pop(&self) -> Option<Result<OctopipesMessage, OctopipesError>> {
+        let mut queue = self.queue.lock().unwrap();
+        let message = queue.messages.pop_front();
+        if message.is_some() {
+            if queue.messages.is_empty() {
+                //Inbox is not readable anymore
+                self.set_ready(false);
+            }
+            self.not_full.notify_one();
+        }
+        message
+    }
+
+    /// ### len
+    ///
+    /// `len` returns the amount of messages in the inbox
+    pub(crate) fn len(&self) -> usize {
+        self.queue.lock().unwrap().messages.len()
+    }
+
+    /// ### readiness_fd
+    ///
+    /// `readiness_fd` returns the file descriptor which is readable while the inbox is not empty
+    pub(crate) fn readiness_fd(&self) -> RawFd {
+        self.readiness.0
+    }
+
+    /// ### set_ready
+    ///
+    /// `set_ready` writes a byte to the readiness pipe if ready, otherwise drains it. Must be called with the queue locked
+    fn set_ready(&self, ready: bool) {
+        let mut byte: [u8; 1] = [0];
+        unsafe {
+            if ready {
+                libc::write(self.readiness.1, byte.as_ptr() as *const libc::c_void, 1);
+            } else {
+                while libc::read(self.readiness.0, byte.as_mut_ptr() as *mut libc::c_void, 1) > 0 {}
+            }
+        }
+    }
+
+    /// ### dropped
+    ///
+    /// `dropped` returns the amount of messages dropped because the inbox was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.queue.lock().unwrap().dropped
+    }
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        //Close readiness pipe
+        unsafe {
+            if self.readiness.0 >= 0 {
+                libc::close(self.readiness.0);
+            }
+            if self.readiness.1 >= 0 {
+                libc::close(self.readiness.1);
+            }
+        }
+    }
+}
+
+/// ### readiness_pipe
+///
+/// `readiness_pipe` creates the non blocking self-pipe used to notify the inbox readiness. Returns (-1, -1) if the pipe couldn't be created
+fn readiness_pipe() -> (RawFd, RawFd) {
+    let mut fds: [libc::c_int; 2] = [-1, -1];
+    unsafe {
+        if libc::pipe(fds.as_mut_ptr()) != 0 {
+            return (-1, -1);
+        }
+        for fd in fds.iter() {
+            libc::fcntl(*fd, libc::F_SETFL, libc::fcntl(*fd, libc::F_GETFL) | libc::O_NONBLOCK);
+            libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    (fds[0], fds[1])
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::OctopipesOptions;
+    use super::super::OctopipesProtocolVersion;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn new_message(data: u8) -> Result<OctopipesMessage, OctopipesError> {
+        Ok(OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("test_client")),
+            &Some(String::from("test_remote")),
+            60,
+            OctopipesOptions::empty(),
+            vec![data],
+        ))
+    }
+
+    #[test]
+    fn test_inbox_drop_policies() {
+        let state: Mutex<OctopipesState> = Mutex::new(OctopipesState::Running);
+        //Drop oldest
+        let inbox: Inbox = Inbox::new();
+        inbox.set_capacity(Some(2), OctopipesInboxPolicy::DropOldest);
+        for i in 0..4 {
+            inbox.push(new_message(i), &state);
+        }
+        assert_eq!(inbox.len(), 2, "Inbox len should be 2, but is {}", inbox.len());
+        assert_eq!(inbox.dropped(), 2, "Dropped messages should be 2, but are {}", inbox.dropped());
+        assert_eq!(inbox.pop().unwrap().unwrap().data, vec![2], "Oldest messages should have been dropped");
+        //Drop newest
+        let inbox: Inbox = Inbox::new();
+        inbox.set_capacity(Some(2), OctopipesInboxPolicy::DropNewest);
+        for i in 0..4 {
+            inbox.push(new_message(i), &state);
+        }
+        assert_eq!(inbox.len(), 2, "Inbox len should be 2, but is {}", inbox.len());
+        assert_eq!(inbox.dropped(), 2, "Dropped messages should be 2, but are {}", inbox.dropped());
+        assert_eq!(inbox.pop().unwrap().unwrap().data, vec![0], "Newest messages should have been dropped");
+    }
+
+    #[test]
+    fn test_inbox_zero_capacity() {
+        //No policy can make room in an inbox with capacity 0: messages are dropped without blocking
+        let state: Mutex<OctopipesState> = Mutex::new(OctopipesState::Running);
+        for policy in [OctopipesInboxPolicy::DropOldest, OctopipesInboxPolicy::DropNewest, OctopipesInboxPolicy::Block] {
+            let inbox: Inbox = Inbox::new();
+            inbox.set_capacity(Some(0), policy);
+            for i in 0..3 {
+                inbox.push(new_message(i), &state);
+            }
+            assert_eq!(inbox.len(), 0, "Inbox len should be 0, but is {}", inbox.len());
+            assert_eq!(inbox.dropped(), 3, "Dropped messages should be 3, but are {}", inbox.dropped());
+            assert!(inbox.pop().is_none(), "Inbox should be empty");
+        }
+    }
+
+    #[test]
+    fn test_inbox_block() {
+        let state: Arc<Mutex<OctopipesState>> = Arc::new(Mutex::new(OctopipesState::Running));
+        let inbox: Arc<Inbox> = Arc::new(Inbox::new());
+        inbox.set_capacity(Some(1), OctopipesInboxPolicy::Block);
+        inbox.push(new_message(0), &state);
+        //Second push blocks until the first message is popped
+        let thread_inbox: Arc<Inbox> = Arc::clone(&inbox);
+        let thread_state: Arc<Mutex<OctopipesState>> = Arc::clone(&state);
+        let join_hnd = thread::spawn(move || {
+            thread_inbox.push(new_message(1), &thread_state);
+        });
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(inbox.len(), 1, "Inbox len should be 1, but is {}", inbox.len());
+        assert_eq!(inbox.pop().unwrap().unwrap().data, vec![0], "First message should be 0");
+        join_hnd.join().expect("Could not join push thread");
+        assert_eq!(inbox.pop().unwrap().unwrap().data, vec![1], "Second message should be 1");
+        assert_eq!(inbox.dropped(), 0, "No message should have been dropped");
+        //A blocked push gives up when the client is stopped
+        inbox.push(new_message(2), &state);
+        *state.lock().unwrap() = OctopipesState::Stopped;
+        inbox.push(new_message(3), &state);
+        assert_eq!(inbox.len(), 1, "Inbox len should be 1, but is {}", inbox.len());
+        assert_eq!(inbox.dropped(), 1, "Dropped messages should be 1, but are {}", inbox.dropped());
+    }
+
+    #[test]
+    fn test_inbox_readiness() {
+        let state: Mutex<OctopipesState> = Mutex::new(OctopipesState::Running);
+        let inbox: Inbox = Inbox::new();
+        assert!(inbox.readiness_fd() >= 0, "Readiness fd should be valid");
+        let is_readable = |fd: RawFd| -> bool {
+            let mut pollfd = libc::pollfd { fd: fd, events: libc::POLLIN, revents: 0 };
+            unsafe { libc::poll(&mut pollfd, 1, 0) == 1 }
+        };
+        assert!(!is_readable(inbox.readiness_fd()), "Empty inbox shouldn't be readable");
+        inbox.push(new_message(0), &state);
+        inbox.push(new_message(1), &state);
+        assert!(is_readable(inbox.readiness_fd()), "Inbox should be readable");
+        inbox.pop();
+        assert!(is_readable(inbox.readiness_fd()), "Inbox should still be readable");
+        inbox.pop();
+        assert!(!is_readable(inbox.readiness_fd()), "Inbox shouldn't be readable once empty");
+    }
+}
@@ -5,7 +5,7 @@
 //! ## Examples
 //! ### Client
 //! 
-//! ```
+//! ```no_run
 //! extern crate rustypipes;
 //! use std::time::Duration;
 //! 
@@ -16,7 +16,7 @@
 //!     println!("Error while trying to subscribe: {}", error);
 //! }
 //! //Start loop (optional, a client could also only send messages)
-//! if let Err(error) = client.start_loop() {
+//! if let Err(error) = client.loop_start() {
 //!     println!("Error while trying to start loop: {}", error);
 //! }
 //! //In a loop get the next message
@@ -42,7 +42,7 @@
 //! 
 //! ### Server
 //! 
-//! ```
+//! ```no_run
 //! extern crate rustypipes;
 //! use std::time::Duration;
 //! use std::thread::sleep;
@@ -97,14 +97,23 @@
 
 mod cap;
 pub mod client;
+mod deadletter;
+mod durable;
+mod inbox;
 pub mod message;
 pub(crate) mod misc;
 mod pipes;
+mod presence;
+mod queue;
 mod serializer;
 pub mod server;
+mod topic;
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
 use std::thread;
+use std::time::Duration;
 
 #[macro_use]
 extern crate bitflags;
@@ -113,6 +122,13 @@ pub const RUSTYPIPES_VERSION: &str = "0.1.1";
 pub const RUSTYPIPES_VERSION_MAJOR: i32 = 0;
 pub const RUSTYPIPES_VERSION_MINOR: i32 = 1;
 
+/// Root of the groups where only the server can publish
+pub const SYS_GROUP: &str = "$SYS";
+/// Reserved group where the server publishes the presence events (`OctopipesPresenceEvent`)
+pub const PRESENCE_GROUP: &str = "$SYS/presence";
+/// Prefix of queue group subscriptions (`$share/<queue>/<group>`): each message sent to the group is delivered to only one member of the queue
+pub const QUEUE_GROUP_PREFIX: &str = "$share/";
+
 /// ## Data types
 
 /// ### OctopipesError
@@ -132,8 +148,13 @@ pub enum OctopipesError {
     CapTimeout,
     NotSubscribed,
     NotUnsubscribed,
+    QueueFull,
     ThreadError,
     ThreadAlreadyRunning,
+    Kicked,
+    ServerShutdown,
+    NoRecipient,
+    ReservedGroup,
     Unknown,
 }
 
@@ -145,6 +166,8 @@ pub enum OctopipesCapError {
     NoError = 0,
     NameAlreadyTaken = 1,
     FileSystemError = 2,
+    NotSubscribed = 3,
+    InvalidName = 4,
 }
 
 /// ### OctopipesCapMessage
@@ -155,18 +178,39 @@ pub enum OctopipesCapError {
 pub enum OctopipesCapMessage {
     Subscription = 0x01,
     Unsubscription = 0x02,
+    Join = 0x03,
+    Leave = 0x04,
+    Heartbeat = 0x05,
+    Query = 0x06,
+    GroupsResult = 0xf9,
+    Error = 0xfa,
+    Shutdown = 0xfb,
+    Disconnect = 0xfc,
+    QueryResult = 0xfd,
+    UnsubscriptionResult = 0xfe,
     Assignment = 0xff,
 }
 
+/// ### CapQuery
+///
+/// `CapQuery` describes what a client is asking for with a QUERY CAP message
+#[derive(Copy, Clone, PartialEq)]
+enum CapQuery {
+    Clients = 0x00,
+    GroupMembers = 0x01,
+    IsOnline = 0x02,
+}
+
 /// ### OctopipesState
 ///
 /// `OctopipesState` describes the current state of the OctopipesClient
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Copy, Clone, PartialEq, std::fmt::Debug)]
 pub enum OctopipesState {
     Initialized,
     Subscribed,
     Running,
+    Reconnecting,
     Unsubscribed,
     Stopped,
 }
@@ -180,6 +224,9 @@ bitflags! {
         const RCK = 0b00000001;
         const ACK = 0b00000010;
         const ICK = 0b00000100;
+        const NOECHO = 0b00001000; //The message is not delivered back to its origin
+        const RETAIN = 0b00010000; //The server stores the message and delivers it to the clients subscribing later to its group
+        const NACK = 0b00100000; //Sent by the server to the origin of an undeliverable message which had RCK set
     }
 }
 
@@ -196,6 +243,7 @@ pub enum OctopipesProtocolVersion {
 ///
 /// `OctopipesMessage` contains the data of a message
 
+#[derive(Clone)]
 pub struct OctopipesMessage {
     version: OctopipesProtocolVersion,
     pub origin: Option<String>,
@@ -205,6 +253,66 @@ pub struct OctopipesMessage {
     pub data: Vec<u8>,
 }
 
+/// ### OctopipesDeadLetterReason
+///
+/// `OctopipesDeadLetterReason` describes why a message couldn't be delivered
+#[derive(Copy, Clone, PartialEq, std::fmt::Debug)]
+pub enum OctopipesDeadLetterReason {
+    NoSubscribers = 0x01, //No client is subscribed to the message remote
+    Expired = 0x02,       //The recipient didn't read the message within its ttl
+    WriteFailed = 0x03,   //The message couldn't be written to the recipient
+}
+
+/// ### OctopipesDeadLetter
+///
+/// `OctopipesDeadLetter` describes a message which couldn't be delivered; it's passed to the dead letter callback and published to the dead letter group
+#[derive(Clone)]
+pub struct OctopipesDeadLetter {
+    pub reason: OctopipesDeadLetterReason,
+    pub recipient: Option<String>, //Client the message couldn't be delivered to; None if there were no subscribers
+    pub message: OctopipesMessage,
+}
+
+/// ### OctopipesServerEvent
+///
+/// `OctopipesServerEvent` describes something which happened on the server while processing the CAP and the workers
+#[derive(Clone)]
+pub enum OctopipesServerEvent {
+    ClientSubscribed { client: String, groups: Vec<String> },
+    ClientUnsubscribed { client: String },
+    MessageRouted { origin: Option<String>, remote: String, recipients: Vec<String> },
+    MessageDropped(OctopipesDeadLetter), //A message couldn't be delivered
+    DecodeError { client: String, error: OctopipesServerError }, //A message sent by the client has been rejected
+    WorkerFailed { client: String, error: OctopipesServerError },
+}
+
+/// ### OctopipesPresenceKind
+///
+/// `OctopipesPresenceKind` describes what happened to a client in a presence event
+#[derive(Copy, Clone, PartialEq, std::fmt::Debug)]
+pub enum OctopipesPresenceKind {
+    Joined = 0x01, //The client subscribed
+    Left = 0x02,   //The client unsubscribed
+    Reaped = 0x03, //The client has been reaped since it was silent for too long
+    Kicked = 0x04, //The client has been kicked by the server
+}
+
+/// ### OctopipesPresenceEvent
+///
+/// `OctopipesPresenceEvent` is published by the server to `PRESENCE_GROUP` whenever a client joins or leaves
+#[derive(Clone, std::fmt::Debug)]
+pub struct OctopipesPresenceEvent {
+    pub kind: OctopipesPresenceKind,
+    pub client_id: String,
+    pub groups: Vec<String>,
+    pub timestamp: std::time::SystemTime,
+}
+
+/// ### SentCallback
+///
+/// `SentCallback` is the callback called when a message has been written, shared by the client, its loop and its senders
+type SentCallback = Arc<Mutex<Option<fn(&OctopipesMessage)>>>;
+
 /// ### OctopipesClient
 ///
 /// `OctopipesClient` is a container for an Octopipes Client
@@ -215,18 +323,131 @@ pub struct OctopipesClient {
     version: OctopipesProtocolVersion,
     //Pipes paths
     cap_pipe: String,
-    tx_pipe: Option<String>,
-    rx_pipe: Option<String>,
+    cap_lock: Arc<Mutex<()>>, //Held while waiting for the reply to a CAP request, since replies share the same pipe
+    tx_pipe: Arc<Mutex<Option<String>>>, //Shared with senders; the lock is held while writing a frame
+    rx_pipe: Arc<Mutex<Option<String>>>, //Shared with the client loop, which can swap pipes when reconnecting
+    //Subscription
+    groups: Arc<Mutex<Vec<String>>>, //Shared with the client loop, which subscribes to them again when reconnecting
+    session: Arc<Mutex<Option<Session>>>, //Updated by the client loop when reconnecting
+    reconnect_policy: Option<OctopipesReconnectPolicy>,
+    heartbeat_interval: Option<Duration>,
+    no_echo: Arc<Mutex<bool>>, //Set NOECHO on each sent message; shared with senders
+    durable: bool, //Subscribe with a durable subscription
+    outbound: Arc<Mutex<Option<OutboundQueue>>>, //Shared with senders and client loop
     //State
     state: Arc<Mutex<OctopipesState>>,
     //Thread
     client_loop: Option<thread::JoinHandle<()>>,
-    client_receiver: Option<mpsc::Receiver<Result<OctopipesMessage, OctopipesError>>>, //Returns Result<&OctopipesMessage, &OctopipesError> when a message is received by the client loop
+    inbox: Arc<Inbox>, //Filled by the client loop with Result<OctopipesMessage, OctopipesError> when a message is received
     //Callbacks
     on_received_fn: Option<fn(Result<&OctopipesMessage, &OctopipesError>)>,
-    on_sent_fn: Option<fn(&OctopipesMessage)>,
+    on_sent_fn: SentCallback, //Shared with senders and client loop
     on_subscribed_fn: Option<fn()>,
     on_unsubscribed_fn: Option<fn()>,
+    on_connection_lost_fn: Option<fn()>,
+    on_reconnected_fn: Option<fn()>,
+    on_kicked_fn: Option<fn(&str)>,
+    on_server_shutdown_fn: Option<fn()>,
+    //Handlers
+    handlers: Arc<Mutex<MessageHandlers>>, //Shared with the client loop, so handlers can be registered while it's running
+}
+
+/// ### Session
+///
+/// `Session` stores the details of the current subscription of a client
+#[derive(Copy, Clone)]
+struct Session {
+    subscribed_since: std::time::SystemTime,
+    server_version: OctopipesProtocolVersion,
+}
+
+/// ### OctopipesReconnectPolicy
+///
+/// `OctopipesReconnectPolicy` describes how the client loop subscribes again when the server goes away.
+/// The delay between two attempts starts from `initial_delay` and is doubled at each failure up to `max_delay`.
+/// If `max_attempts` is None, the client will try to reconnect forever
+
+#[derive(Copy, Clone)]
+pub struct OctopipesReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: Option<usize>,
+}
+
+/// ### OctopipesOverflowPolicy
+///
+/// `OctopipesOverflowPolicy` describes what to do when a message is pushed into a full queue
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OctopipesOverflowPolicy {
+    DropOldest,
+    DropNewest,
+    Reject,
+}
+
+/// ### OutboundQueue
+///
+/// `OutboundQueue` stores the encoded messages which couldn't be sent while the client was disconnected
+struct OutboundQueue {
+    frames: VecDeque<Vec<u8>>,
+    capacity: usize,
+    overflow: OctopipesOverflowPolicy,
+    persistence: Option<String>, //Path of the file where frames are persisted
+}
+
+/// ### OctopipesInboxPolicy
+///
+/// `OctopipesInboxPolicy` describes what the client loop does when a message is received and the inbox is full.
+/// With `Block` the client loop stops reading until there's room in the inbox, which makes the server wait too
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum OctopipesInboxPolicy {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+/// ### Inbox
+///
+/// `Inbox` stores the messages received by the client loop until they're fetched.
+/// The read end of the readiness pipe is readable while the inbox is not empty
+struct Inbox {
+    queue: Mutex<InboxQueue>,
+    not_full: Condvar,
+    readiness: (std::os::unix::io::RawFd, std::os::unix::io::RawFd), //Self-pipe (read, write)
+}
+
+/// ### InboxQueue
+///
+/// `InboxQueue` is the content of the Inbox
+struct InboxQueue {
+    messages: VecDeque<Result<OctopipesMessage, OctopipesError>>,
+    capacity: Option<usize>,
+    policy: OctopipesInboxPolicy,
+    dropped: u64,
+}
+
+/// ### MessageHandlers
+///
+/// `MessageHandlers` stores the handlers registered on the client for each recipient group
+struct MessageHandlers {
+    groups: HashMap<String, fn(&OctopipesMessage)>,
+    fallback: Option<fn(&OctopipesMessage)>,
+}
+
+/// ### OctopipesSender
+///
+/// `OctopipesSender` is a clonable handle, obtained from an OctopipesClient, which can be moved to other threads to send messages through the client's TX pipe
+
+#[derive(Clone)]
+pub struct OctopipesSender {
+    id: String,
+    version: OctopipesProtocolVersion,
+    state: Arc<Mutex<OctopipesState>>,
+    tx_pipe: Arc<Mutex<Option<String>>>,
+    outbound: Arc<Mutex<Option<OutboundQueue>>>,
+    no_echo: Arc<Mutex<bool>>,
+    on_sent_fn: SentCallback,
 }
 
 //@! Server
@@ -245,7 +466,20 @@ pub struct OctopipesServer {
     cap_listener: Option<thread::JoinHandle<()>>,
     cap_receiver: Option<mpsc::Receiver<Result<OctopipesMessage, OctopipesServerError>>>, //Receives OctopipesMessage from clients; responses are sent through methods
     //workers
-    workers: Vec<OctopipesServerWorker>
+    workers: HashMap<String, OctopipesServerWorker>, //Workers by client id
+    clients: Vec<String>,                            //Client ids in subscription order
+    routes: RoutingIndex,                            //Subscriptions of the workers
+    retained: RefCell<HashMap<String, OctopipesMessage>>, //Last retained message by group
+    durable: RefCell<HashMap<String, DurableSession>>, //Sessions of the durable clients which are offline
+    durable_limits: OctopipesDurableLimits,
+    dead_letter_group: Option<String>, //Group where undeliverable messages are published
+    client_timeout: Option<Duration>, //Workers silent for longer than this are reaped
+    drain_deadline: Duration,         //Max time spent dispatching pending messages on shutdown
+    events: RefCell<VecDeque<OctopipesServerEvent>>, //Events not polled yet
+    //Callbacks
+    on_client_reaped_fn: Option<fn(&str)>,
+    on_dead_letter_fn: Option<fn(&OctopipesDeadLetter)>,
+    on_event_fn: Option<fn(&OctopipesServerEvent)>,
 }
 
 /// ### OctopipesServerWorker
@@ -262,6 +496,9 @@ struct OctopipesServerWorker {
     worker_loop: Option<thread::JoinHandle<()>>,
     worker_active: Arc<Mutex<bool>>, //When set to false, the worker must terminate
     receiver: mpsc::Receiver<Result<OctopipesMessage, OctopipesServerError>>,
+    //Replay
+    stored: RefCell<VecDeque<StoredMessage>>, //Messages stored while the client was offline, not delivered yet
+    retained: RefCell<VecDeque<StoredMessage>>, //Retained messages of its groups, not delivered yet (they don't expire)
 }
 
 /// ### Subscription
@@ -269,7 +506,69 @@ struct OctopipesServerWorker {
 /// `Subscription` is a struct which stores the data for a single subscription from a client
 struct Subscription {
     subscription_time: std::time::Instant,
+    last_seen: Arc<Mutex<std::time::Instant>>, //Last time a CAP message or a frame was received from the client; shared with the worker thread
     groups: Vec<String>,
+    durable: bool, //Messages must be stored while the client is offline
+}
+
+/// ### OctopipesDurableLimits
+///
+/// `OctopipesDurableLimits` describes how many messages the server stores for each offline durable client.
+/// When a limit is exceeded the oldest messages are discarded; messages older than `max_age` are discarded too,
+/// as well as the whole session of a client which has been offline for longer than `max_age`
+
+#[derive(Copy, Clone)]
+pub struct OctopipesDurableLimits {
+    pub max_messages: usize,
+    pub max_bytes: usize,
+    pub max_age: Duration,
+}
+
+/// ### DurableSession
+///
+/// `DurableSession` stores the messages addressed to a durable client while it's offline
+struct DurableSession {
+    groups: Vec<String>,
+    offline_since: std::time::Instant,
+    messages: VecDeque<StoredMessage>,
+    bytes: usize, //Sum of the size of the stored messages
+}
+
+/// ### StoredMessage
+///
+/// `StoredMessage` is an encoded message stored for an offline client
+struct StoredMessage {
+    stored_at: std::time::Instant,
+    ttl: u8,
+    data: Vec<u8>,
+}
+
+/// ### TopicTrie
+///
+/// `TopicTrie` indexes the subscriptions of the clients by group level (levels are separated by '/').
+/// Subscriptions can contain wildcards: '+' matches a single level, '#' (as last level) matches any number of levels
+struct TopicTrie {
+    children: HashMap<String, TopicTrie>,
+    clients: Vec<String>, //Clients subscribed to the filter ending at this node
+}
+
+/// ### RoutingIndex
+///
+/// `RoutingIndex` maps the groups to the clients subscribed to them.
+/// Exact groups are looked up in an HashMap, while the subscriptions containing wildcards are stored in a topic trie
+struct RoutingIndex {
+    groups: HashMap<String, Vec<String>>,
+    wildcards: TopicTrie,
+    queues: HashMap<String, QueueGroup>, //Queue groups by subscription
+}
+
+/// ### QueueGroup
+///
+/// `QueueGroup` stores the members of a queue group; messages matching its filter are delivered to one member at a time (round robin)
+struct QueueGroup {
+    filter: String,
+    members: Vec<String>,
+    next: Cell<usize>, //Index of the member which will receive the next message
 }
 
 /// ### OctopipesServerError
@@ -293,6 +592,9 @@ pub enum OctopipesServerError {
     WorkerAlreadyRunning,
     WorkerNotRunning,
     NoRecipient,
+    BadOrigin,
+    InvalidClientId,
+    ReservedGroup,
     BadClientDir,
     Unknown,
 }
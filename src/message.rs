@@ -25,6 +25,7 @@
 // SOFTWARE.
 //
 
+use super::OctopipesDeadLetterReason;
 use super::OctopipesMessage;
 use super::OctopipesOptions;
 use super::OctopipesProtocolVersion;
@@ -60,4 +61,15 @@ impl OctopipesMessage {
     pub fn isset_option(&self, option: OctopipesOptions) -> bool {
         self.options.intersects(option)
     }
+
+    /// ### nack_reason
+    ///
+    /// `nack_reason` returns the reason why a message sent by the client couldn't be delivered, if this message is a NACK from the server.
+    /// Only the server can send messages without origin, since it rejects the client frames which don't carry their own origin
+    pub fn nack_reason(&self) -> Option<OctopipesDeadLetterReason> {
+        if !self.isset_option(OctopipesOptions::NACK) || self.origin.is_some() {
+            return None;
+        }
+        self.data.first().and_then(|reason| OctopipesDeadLetterReason::from_u8(*reason))
+    }
 }
@@ -25,14 +25,20 @@
 // SOFTWARE.
 //
 
+use super::CapQuery;
 use super::OctopipesCapError;
 use super::OctopipesCapMessage;
+use super::OctopipesDeadLetterReason;
+use super::OctopipesDurableLimits;
 use super::OctopipesError;
 use super::OctopipesProtocolVersion;
 use super::OctopipesOptions;
+use super::OctopipesPresenceKind;
+use super::OctopipesReconnectPolicy;
 use super::OctopipesServerError;
 
 use std::fmt;
+use std::time::Duration;
 
 //Types utils
 impl OctopipesProtocolVersion {
@@ -49,6 +55,16 @@ impl OctopipesCapMessage {
         match value {
             0x01 => Some(OctopipesCapMessage::Subscription),
             0x02 => Some(OctopipesCapMessage::Unsubscription),
+            0x03 => Some(OctopipesCapMessage::Join),
+            0x04 => Some(OctopipesCapMessage::Leave),
+            0x05 => Some(OctopipesCapMessage::Heartbeat),
+            0x06 => Some(OctopipesCapMessage::Query),
+            0xf9 => Some(OctopipesCapMessage::GroupsResult),
+            0xfa => Some(OctopipesCapMessage::Error),
+            0xfb => Some(OctopipesCapMessage::Shutdown),
+            0xfc => Some(OctopipesCapMessage::Disconnect),
+            0xfd => Some(OctopipesCapMessage::QueryResult),
+            0xfe => Some(OctopipesCapMessage::UnsubscriptionResult),
             0xff => Some(OctopipesCapMessage::Assignment),
             _ => None,
         }
@@ -57,7 +73,51 @@ impl OctopipesCapMessage {
         match self {
             OctopipesCapMessage::Assignment => "ASSIGNMENT",
             OctopipesCapMessage::Subscription => "SUBSCRIPTION",
-            OctopipesCapMessage::Unsubscription => "UNSUBSCRIPTION"
+            OctopipesCapMessage::Unsubscription => "UNSUBSCRIPTION",
+            OctopipesCapMessage::Join => "JOIN",
+            OctopipesCapMessage::Leave => "LEAVE",
+            OctopipesCapMessage::Heartbeat => "HEARTBEAT",
+            OctopipesCapMessage::Query => "QUERY",
+            OctopipesCapMessage::GroupsResult => "GROUPS_RESULT",
+            OctopipesCapMessage::Error => "ERROR",
+            OctopipesCapMessage::Shutdown => "SHUTDOWN",
+            OctopipesCapMessage::Disconnect => "DISCONNECT",
+            OctopipesCapMessage::QueryResult => "QUERY_RESULT",
+            OctopipesCapMessage::UnsubscriptionResult => "UNSUBSCRIPTION_RESULT"
+        }
+    }
+}
+
+impl OctopipesDeadLetterReason {
+    pub(crate) fn from_u8(value: u8) -> Option<OctopipesDeadLetterReason> {
+        match value {
+            0x01 => Some(OctopipesDeadLetterReason::NoSubscribers),
+            0x02 => Some(OctopipesDeadLetterReason::Expired),
+            0x03 => Some(OctopipesDeadLetterReason::WriteFailed),
+            _ => None,
+        }
+    }
+}
+
+impl OctopipesPresenceKind {
+    pub(crate) fn from_u8(value: u8) -> Option<OctopipesPresenceKind> {
+        match value {
+            0x01 => Some(OctopipesPresenceKind::Joined),
+            0x02 => Some(OctopipesPresenceKind::Left),
+            0x03 => Some(OctopipesPresenceKind::Reaped),
+            0x04 => Some(OctopipesPresenceKind::Kicked),
+            _ => None,
+        }
+    }
+}
+
+impl CapQuery {
+    pub(crate) fn from_u8(value: u8) -> Option<CapQuery> {
+        match value {
+            0x00 => Some(CapQuery::Clients),
+            0x01 => Some(CapQuery::GroupMembers),
+            0x02 => Some(CapQuery::IsOnline),
+            _ => None,
         }
     }
 }
@@ -68,6 +128,8 @@ impl OctopipesCapError {
             0x00 => Some(OctopipesCapError::NoError),
             0x01 => Some(OctopipesCapError::NameAlreadyTaken),
             0x02 => Some(OctopipesCapError::FileSystemError),
+            0x03 => Some(OctopipesCapError::NotSubscribed),
+            0x04 => Some(OctopipesCapError::InvalidName),
             _ => None
         }
     }
@@ -75,6 +137,8 @@ impl OctopipesCapError {
         match self {
             OctopipesCapError::FileSystemError => "FileSystemError",
             OctopipesCapError::NameAlreadyTaken => "NameAlreadyTaken",
+            OctopipesCapError::NotSubscribed => "NotSubscribed",
+            OctopipesCapError::InvalidName => "InvalidName",
             OctopipesCapError::NoError => "NoError"
         }
     }
@@ -92,10 +156,39 @@ impl OctopipesOptions {
         if value & OctopipesOptions::ICK.bits() != 0 {
             option.set(OctopipesOptions::ICK, true);
         }
+        if value & OctopipesOptions::NOECHO.bits() != 0 {
+            option.set(OctopipesOptions::NOECHO, true);
+        }
+        if value & OctopipesOptions::RETAIN.bits() != 0 {
+            option.set(OctopipesOptions::RETAIN, true);
+        }
+        if value & OctopipesOptions::NACK.bits() != 0 {
+            option.set(OctopipesOptions::NACK, true);
+        }
         option
     }
 }
 
+impl Default for OctopipesReconnectPolicy {
+    fn default() -> Self {
+        OctopipesReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            max_attempts: None,
+        }
+    }
+}
+
+impl Default for OctopipesDurableLimits {
+    fn default() -> Self {
+        OctopipesDurableLimits {
+            max_messages: 1024,
+            max_bytes: 1024 * 1024,
+            max_age: Duration::from_secs(3600),
+        }
+    }
+}
+
 impl OctopipesError {
     pub fn to_string(&self) -> &str {
         match self {
@@ -107,9 +200,14 @@ impl OctopipesError {
             OctopipesError::NotSubscribed => "The client must be subscribed to the server before receiving and sending messages",
             OctopipesError::NotUnsubscribed => "The client must be unsubscribed to perform this action",
             OctopipesError::OpenFailed => "Could not open the requested pipe",
+            OctopipesError::QueueFull => "The outbound queue is full",
             OctopipesError::ReadFailed => "Could not read from pipe",
             OctopipesError::ThreadAlreadyRunning => "Client loop Thread is already running",
             OctopipesError::ThreadError => "Thread error",
+            OctopipesError::Kicked => "The client has been kicked by the server",
+            OctopipesError::ServerShutdown => "The server has been shut down",
+            OctopipesError::NoRecipient => "The message sent had no recipient",
+            OctopipesError::ReservedGroup => "Only the server can send messages to $SYS groups",
             OctopipesError::UnsupportedVersion => "Unsupported protocol version",
             OctopipesError::WriteFailed => "Could not write to pipe",
             _ => "Unknown error"
@@ -138,6 +236,9 @@ impl OctopipesServerError {
             OctopipesServerError::BadPacket => "It was not possible to decode packet, since it contains bad data",
             OctopipesServerError::CapTimeout => "CAP timeout",
             OctopipesServerError::NoRecipient => "The provided message has no recipient",
+            OctopipesServerError::BadOrigin => "The message origin is not the client which sent it",
+            OctopipesServerError::InvalidClientId => "The client id can't be used as a group",
+            OctopipesServerError::ReservedGroup => "Clients can't send messages to $SYS groups",
             OctopipesServerError::OpenFailed => "Could not open the requested pipe",
             OctopipesServerError::ReadFailed => "Could not read from pipe",
             OctopipesServerError::ThreadAlreadyRunning => "Client loop Thread is already running",
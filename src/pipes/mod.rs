@@ -25,15 +25,18 @@
 // SOFTWARE.
 //
 
+extern crate libc;
 extern crate unix_named_pipe;
 
 use std::io::{Error, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::thread;
 use std::time::{Duration, Instant};
 
 /// ### pipe_create
 ///
 /// `pipe_create` creates a Unix Pipe in the specified path
-pub(super) fn pipe_create(path: &String) -> std::io::Result<()> {
+pub(super) fn pipe_create(path: &str) -> std::io::Result<()> {
     match unix_named_pipe::create(path, Some(0o666)) {
         Ok(..) => Ok(()),
         Err(error) => {
@@ -48,7 +51,7 @@ pub(super) fn pipe_create(path: &String) -> std::io::Result<()> {
 /// ### pipe_delete
 ///
 /// `pipe_delete` deletes a Unix Pipe in the specified path
-pub(super) fn pipe_delete(path: &String) -> std::io::Result<()> {
+pub(super) fn pipe_delete(path: &str) -> std::io::Result<()> {
     match std::fs::remove_file(path) {
         Ok(..) => Ok(()),
         Err(error) => Err(error),
@@ -58,7 +61,7 @@ pub(super) fn pipe_delete(path: &String) -> std::io::Result<()> {
 /// ### pipe_read
 ///
 /// `pipe_read` read from pipe; Returns or if after millis nothing has been read or if there's no more data available
-pub(super) fn pipe_read(path: &String, timeout_millis: u128) -> std::io::Result<Option<Vec<u8>>> {
+pub(super) fn pipe_read(path: &str, timeout_millis: u128) -> std::io::Result<Option<Vec<u8>>> {
     //Try open pipe
     let res = unix_named_pipe::open_read(path);
     if res.is_err() {
@@ -81,6 +84,7 @@ pub(super) fn pipe_read(path: &String, timeout_millis: u128) -> std::io::Result<
                         break;
                     } else {
                         time_elapsed = t_start.elapsed(); //Sum time only if no data was received (in order to prevent cuts)
+                        idle_wait(time_elapsed, timeout_millis);
                         continue;
                     }
                 }
@@ -92,6 +96,7 @@ pub(super) fn pipe_read(path: &String, timeout_millis: u128) -> std::io::Result<
                 match error.kind() {
                     ErrorKind::WouldBlock => {
                         time_elapsed = t_start.elapsed();
+                        idle_wait(time_elapsed, timeout_millis);
                         continue;
                     },
                     _ => return Err(error)
@@ -106,60 +111,74 @@ pub(super) fn pipe_read(path: &String, timeout_millis: u128) -> std::io::Result<
     }
 }
 
+/// ### idle_wait
+///
+/// `idle_wait` sleeps for a while before polling the pipe again, unless the timeout has already expired:
+/// in that case the pipe must be read once more immediately, since data written before it is closed would be lost
+fn idle_wait(time_elapsed: Duration, timeout_millis: u128) {
+    if time_elapsed.as_millis() < timeout_millis || timeout_millis == 0 {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// ### pipe_has_reader
+///
+/// `pipe_has_reader` returns whether there's an endpoint reading the pipe, without waiting for it
+pub(super) fn pipe_has_reader(path: &str) -> bool {
+    unix_named_pipe::open_write(path).is_ok()
+}
+
 /// ### pipe_write
 ///
-/// `pipe_write` write to pipe; Returns after millis if nothing has been written or if the entire payload has been written. ErrorKind is WriteZero if there was no endpoint reading the pipe
-pub(super) fn pipe_write(path: &String, timeout_millis: u128, data_out: Vec<u8>) -> std::io::Result<()> {
-    //Try open pipe
+/// `pipe_write` write to pipe; Returns after millis if nothing has been written or if the entire payload has been written. ErrorKind is WriteZero if there was no endpoint reading the pipe.
+/// Anything else than a FIFO (e.g. a regular file planted at the path) is refused with InvalidInput
+pub(super) fn pipe_write(path: &str, timeout_millis: u128, data_out: &[u8]) -> std::io::Result<()> {
     let t_start = Instant::now();
     let mut time_elapsed: Duration = Duration::from_millis(0);
-    let mut pipe_wrapper: Option<std::fs::File> = None;
+    let mut bytes_written: usize = 0;
     while time_elapsed.as_millis() < timeout_millis || timeout_millis == 0 {
-        let res = unix_named_pipe::open_write(path);
-        match res {
-            Ok(file) => { 
-                //Pipe OPEN, break and go write
-                pipe_wrapper = Some(file);
-                break;
-            },
+        //Try open pipe
+        let mut pipe: std::fs::File = match unix_named_pipe::open_write(path) {
+            Ok(file) => file, //Pipe OPEN, go write
             Err(err) => {
-                match err.kind() {
-                    ErrorKind::Other => {
-                        //Continue
-                        time_elapsed = t_start.elapsed();
-                        continue;
-                    },
-                    _ => return Err(err)
+                //ENXIO is returned if there's no endpoint reading the pipe yet
+                if err.kind() == ErrorKind::Other || err.raw_os_error() == Some(libc::ENXIO) {
+                    //Continue
+                    thread::sleep(Duration::from_millis(1));
+                    time_elapsed = t_start.elapsed();
+                    continue;
                 }
+                return Err(err)
             }
+        };
+        if !pipe.metadata()?.file_type().is_fifo() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Not a FIFO"));
         }
-    }
-    if pipe_wrapper.is_none() {
-        return Err(Error::from(ErrorKind::WriteZero))
-    }
-    let mut bytes_written: usize = 0;
-    let mut pipe: std::fs::File = pipe_wrapper.unwrap();
-    while time_elapsed.as_millis() < timeout_millis || timeout_millis == 0 {
-        match pipe.write(data_out.as_slice()) {
-            Ok(bytes) => {
-                //Sum elapsed time
-                time_elapsed = t_start.elapsed();
-                bytes_written += bytes;
-                if bytes_written == data_out.len() {
-                    break;
-                } else {
-                    continue;
+        while time_elapsed.as_millis() < timeout_millis || timeout_millis == 0 {
+            match pipe.write(&data_out[bytes_written..]) {
+                Ok(bytes) => {
+                    //Sum elapsed time
+                    time_elapsed = t_start.elapsed();
+                    bytes_written += bytes;
+                    if bytes_written == data_out.len() {
+                        return Ok(());
+                    }
+                }
+                Err(error) => {
+                    time_elapsed = t_start.elapsed();
+                    match error.kind() {
+                        //The reader could have closed the pipe right after we opened it; if nothing was written yet, open it again
+                        ErrorKind::BrokenPipe if bytes_written == 0 => break,
+                        //The pipe is full, or the reader closed it in the middle of the payload:
+                        //keep it open (otherwise the data already written is lost) until the reader gets back
+                        ErrorKind::WouldBlock | ErrorKind::BrokenPipe => thread::sleep(Duration::from_millis(1)),
+                        _ => return Err(error),
+                    }
                 }
             }
-            Err(error) => {
-                return Err(error)
-            },
         }
     }
-    if bytes_written < data_out.len() {
-        return Err(Error::from(ErrorKind::WriteZero));
-    }
-    Ok(())
+    Err(Error::from(ErrorKind::WriteZero))
 }
 
 //@! Tests
@@ -167,7 +186,6 @@ pub(super) fn pipe_write(path: &String, timeout_millis: u128, data_out: Vec<u8>)
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
 
     #[test]
     fn test_pipe_create_and_delete() {
@@ -205,7 +223,7 @@ mod tests {
                 data.push(i);
             }
             //Write data
-            match pipe_write(&pipe_rx_copy, 5000, data) {
+            match pipe_write(&pipe_rx_copy, 5000, &data) {
                 Ok(()) => println!("Successfully wrote 255 bytes to pipe rx"),
                 Err(ioerr) => panic!("Could not write to pipe: {}", ioerr),
             }
@@ -245,6 +263,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_pipe_write_reader_reopens() {
+        //A payload larger than the pipe buffer is written entirely, even if the reader closes and opens the pipe again while reading it
+        let pipe: String = String::from("/tmp/pipe_write_reopen");
+        match pipe_create(&pipe) {
+            Ok(_) => println!("Pipe created with success"),
+            Err(ioerr) => panic!("Could not create pipe: {}", ioerr),
+        }
+        let data: Vec<u8> = (0..262144).map(|i| (i % 251) as u8).collect();
+        let pipe_copy: String = pipe.clone();
+        let data_copy: Vec<u8> = data.clone();
+        let join_hnd: thread::JoinHandle<()> = thread::spawn(move || {
+            if let Err(ioerr) = pipe_write(&pipe_copy, 10000, &data_copy) {
+                panic!("Could not write to pipe: {}", ioerr);
+            }
+        });
+        //Read in short sessions
+        let mut data_in: Vec<u8> = Vec::new();
+        let t_start = Instant::now();
+        while data_in.len() < data.len() && t_start.elapsed().as_millis() < 10000 {
+            match pipe_read(&pipe, 20) {
+                Ok(Some(chunk)) => data_in.extend(chunk),
+                Ok(None) => {}
+                Err(ioerr) => panic!("Error while reading from pipe: {}", ioerr),
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        join_hnd.join().expect("Could not join write thread");
+        assert!(data_in == data, "Data read ({} bytes) differs from data written", data_in.len());
+        if let Err(ioerr) = pipe_delete(&pipe) {
+            panic!("Could not delete previously created pipe: {}", ioerr);
+        }
+    }
+
     #[test]
     fn test_pipe_read_no_endpoint() {
         //Try to create a pipe in /tmp/pipe_test
@@ -289,7 +341,7 @@ mod tests {
         match pipe_write(
             &String::from("/tmp/pipe_write_noendpoint"),
             3000,
-            vec![0x00, 0x01, 0x02, 0x03]
+            &[0x00, 0x01, 0x02, 0x03]
         ) {
             Ok(_) => {
                 panic!("Pipe write without end point should have returned error (WriteZero), but returned OK");
@@ -312,4 +364,17 @@ mod tests {
             Err(ioerr) => panic!("Could not delete previously created pipe: {}", ioerr),
         }
     }
+
+    #[test]
+    fn test_pipe_write_not_fifo() {
+        //A regular file planted where a pipe is expected must be left untouched
+        let path: String = String::from("/tmp/pipe_write_not_fifo");
+        std::fs::write(&path, b"planted").expect("Could not create regular file");
+        match pipe_write(&path, 1000, &[0x00, 0x01, 0x02, 0x03]) {
+            Ok(_) => panic!("Pipe write to a regular file should have returned error (InvalidInput), but returned OK"),
+            Err(ioerr) => assert_eq!(ioerr.kind(), ErrorKind::InvalidInput),
+        }
+        assert_eq!(std::fs::read(&path).expect("Could not read regular file"), b"planted".to_vec());
+        std::fs::remove_file(&path).expect("Could not delete regular file");
+    }
 }
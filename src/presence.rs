@@ -0,0 +1,163 @@
+//! ## Presence
+//!
+//! `presence` is the module which takes care of encoding and decoding the presence events published by the server
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::OctopipesError;
+use super::OctopipesPresenceEvent;
+use super::OctopipesPresenceKind;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+impl OctopipesPresenceEvent {
+    /// ### new
+    ///
+    /// `new` instances a new presence event for a client, happened now
+    pub(crate) fn new(kind: OctopipesPresenceKind, client_id: &str, groups: Vec<String>) -> OctopipesPresenceEvent {
+        OctopipesPresenceEvent {
+            kind,
+            client_id: client_id.to_string(),
+            groups,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    /// ### encode
+    ///
+    /// `encode` encodes the presence event into a message payload:
+    /// kind (1 byte), timestamp in milliseconds since UNIX epoch (8 bytes BE), client id (len + bytes), groups (amount + (len + bytes) for each group)
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = Vec::with_capacity(11 + self.client_id.len());
+        payload.push(self.kind as u8);
+        let timestamp: u64 = match self.timestamp.duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_millis() as u64,
+            Err(_) => 0,
+        };
+        payload.extend_from_slice(&timestamp.to_be_bytes());
+        //Write client id
+        payload.push(self.client_id.len() as u8);
+        payload.extend_from_slice(self.client_id.as_bytes());
+        //Write groups
+        payload.push(self.groups.len() as u8);
+        for group in self.groups.iter() {
+            payload.push(group.len() as u8);
+            payload.extend_from_slice(group.as_bytes());
+        }
+        payload
+    }
+
+    /// ### decode
+    ///
+    /// `decode` decodes the payload of a message received on the presence group
+    pub fn decode(data: &[u8]) -> Result<OctopipesPresenceEvent, OctopipesError> {
+        //Size must be at least 11 (kind, timestamp, id len, groups amount)
+        if data.len() < 11 {
+            return Err(OctopipesError::BadPacket);
+        }
+        let kind: OctopipesPresenceKind = match OctopipesPresenceKind::from_u8(data[0]) {
+            Some(kind) => kind,
+            None => return Err(OctopipesError::BadPacket),
+        };
+        let mut timestamp_bytes: [u8; 8] = [0; 8];
+        timestamp_bytes.copy_from_slice(&data[1..9]);
+        let timestamp: SystemTime = UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(timestamp_bytes));
+        //Get client id
+        let mut index: usize = 9;
+        let client_id: String = read_string(data, &mut index)?;
+        //Get groups
+        if index >= data.len() {
+            return Err(OctopipesError::BadPacket);
+        }
+        let groups_amount: usize = data[index] as usize;
+        index += 1;
+        let mut groups: Vec<String> = Vec::with_capacity(groups_amount);
+        while groups.len() < groups_amount {
+            groups.push(read_string(data, &mut index)?);
+        }
+        Ok(OctopipesPresenceEvent {
+            kind,
+            client_id,
+            groups,
+            timestamp,
+        })
+    }
+}
+
+/// ### read_string
+///
+/// `read_string` reads a string prefixed by its length, starting from index; index is moved after the string
+fn read_string(data: &[u8], index: &mut usize) -> Result<String, OctopipesError> {
+    if *index >= data.len() {
+        return Err(OctopipesError::BadPacket);
+    }
+    let length: usize = data[*index] as usize;
+    let final_index: usize = *index + 1 + length;
+    if final_index > data.len() {
+        return Err(OctopipesError::BadPacket);
+    }
+    let string: String = data[*index + 1..final_index].iter().map(|byte| *byte as char).collect();
+    *index = final_index;
+    Ok(string)
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence_encode_decode() {
+        let event: OctopipesPresenceEvent = OctopipesPresenceEvent::new(
+            OctopipesPresenceKind::Joined,
+            "myclient",
+            vec![String::from("SENSORS"), String::from("ALARMS")],
+        );
+        let payload: Vec<u8> = event.encode();
+        assert_eq!(payload.len(), 11 + 8 + 8 + 7, "Payload len should be 34, but is {}", payload.len());
+        let decoded: OctopipesPresenceEvent = OctopipesPresenceEvent::decode(&payload).unwrap();
+        assert_eq!(decoded.kind, OctopipesPresenceKind::Joined);
+        assert_eq!(decoded.client_id, String::from("myclient"));
+        assert_eq!(decoded.groups, vec![String::from("SENSORS"), String::from("ALARMS")]);
+        //Timestamp is kept with milliseconds precision
+        let difference: Duration = match event.timestamp.duration_since(decoded.timestamp) {
+            Ok(difference) => difference,
+            Err(err) => err.duration(),
+        };
+        assert!(difference < Duration::from_millis(1), "Timestamp mismatch");
+    }
+
+    #[test]
+    fn test_presence_decode_bad_packet() {
+        let payload: Vec<u8> = OctopipesPresenceEvent::new(OctopipesPresenceKind::Left, "myclient", vec![]).encode();
+        //Truncated
+        assert_eq!(OctopipesPresenceEvent::decode(&payload[0..payload.len() - 1]).err().unwrap(), OctopipesError::BadPacket);
+        assert_eq!(OctopipesPresenceEvent::decode(&payload[0..5]).err().unwrap(), OctopipesError::BadPacket);
+        //Bad kind
+        let mut bad_kind: Vec<u8> = payload.clone();
+        bad_kind[0] = 0x00;
+        assert_eq!(OctopipesPresenceEvent::decode(&bad_kind).err().unwrap(), OctopipesError::BadPacket);
+    }
+}
@@ -0,0 +1,226 @@
+//! ## Queue
+//!
+//! `queue` is the module which takes care of managing the client's outbound queue
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::OctopipesError;
+use super::OctopipesOverflowPolicy;
+use super::OutboundQueue;
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+
+impl OutboundQueue {
+    /// ### OutboundQueue Constructor
+    ///
+    /// `new` instances a new OutboundQueue. If a persistence file is provided, the frames stored in it are loaded into the queue
+    pub(crate) fn new(capacity: usize, overflow: OctopipesOverflowPolicy, persistence: Option<String>) -> Result<OutboundQueue, OctopipesError> {
+        let mut queue: OutboundQueue = OutboundQueue {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            overflow,
+            persistence,
+        };
+        if let Some(path) = queue.persistence.as_ref() {
+            //Load frames from file; if it doesn't exist, the queue is just empty
+            let mut data: Vec<u8> = Vec::new();
+            match std::fs::File::open(path) {
+                Ok(mut file) => {
+                    if file.read_to_end(&mut data).is_err() {
+                        return Err(OctopipesError::ReadFailed);
+                    }
+                }
+                Err(err) => {
+                    if err.kind() != std::io::ErrorKind::NotFound {
+                        return Err(OctopipesError::OpenFailed);
+                    }
+                }
+            }
+            //Records are made up of frame length (4 bytes BE) and frame
+            let mut index: usize = 0;
+            while index + 4 <= data.len() {
+                let frame_size: usize = u32::from_be_bytes([data[index], data[index + 1], data[index + 2], data[index + 3]]) as usize;
+                index += 4;
+                if index + frame_size > data.len() {
+                    return Err(OctopipesError::BadPacket);
+                }
+                queue.frames.push_back(data[index..index + frame_size].to_vec());
+                index += frame_size;
+            }
+            //Apply capacity to loaded frames (oldest are dropped)
+            while queue.frames.len() > queue.capacity {
+                queue.frames.pop_front();
+            }
+        }
+        Ok(queue)
+    }
+
+    /// ### push
+    ///
+    /// `push` enqueues an encoded message. If the queue is full, the overflow policy is applied
+    pub(crate) fn push(&mut self, frame: Vec<u8>) -> Result<(), OctopipesError> {
+        if self.frames.len() >= self.capacity {
+            match self.overflow {
+                OctopipesOverflowPolicy::DropOldest => {
+                    self.frames.pop_front();
+                }
+                OctopipesOverflowPolicy::DropNewest => return Ok(()),
+                OctopipesOverflowPolicy::Reject => return Err(OctopipesError::QueueFull),
+            }
+        }
+        //A queue with no capacity can't store anything
+        if self.capacity > 0 {
+            self.frames.push_back(frame);
+            self.persist();
+        }
+        Ok(())
+    }
+
+    /// ### flush
+    ///
+    /// `flush` writes the queued frames in order through the provided writer. It stops at the first failure,
+    /// leaving the failed frame at the head of the queue
+    pub(crate) fn flush<F>(&mut self, mut write: F) -> Result<(), OctopipesError>
+    where
+        F: FnMut(Vec<u8>) -> Result<(), OctopipesError>,
+    {
+        if self.frames.is_empty() {
+            return Ok(());
+        }
+        let mut result: Result<(), OctopipesError> = Ok(());
+        while let Some(frame) = self.frames.front() {
+            if let Err(err) = write(frame.clone()) {
+                result = Err(err);
+                break;
+            }
+            self.frames.pop_front();
+        }
+        self.persist();
+        result
+    }
+
+    /// ### len
+    ///
+    /// `len` returns the amount of queued frames
+    pub(crate) fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// ### persist
+    ///
+    /// `persist` rewrites the persistence file with the current queue content (if persistence is enabled).
+    /// Persistence is best effort: if the file can't be written, the queue is kept in memory anyway
+    fn persist(&self) {
+        if let Some(path) = self.persistence.as_ref() {
+            let mut data: Vec<u8> = Vec::new();
+            for frame in self.frames.iter() {
+                data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                data.extend_from_slice(frame);
+            }
+            //Write to a temporary file and then replace the old one
+            let tmp_path: String = format!("{}.tmp", path);
+            if let Ok(mut file) = std::fs::File::create(&tmp_path) {
+                if file.write_all(&data).is_ok() {
+                    let _ = std::fs::rename(&tmp_path, path);
+                }
+            }
+        }
+    }
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_overflow() {
+        //Drop oldest
+        let mut queue: OutboundQueue = OutboundQueue::new(2, OctopipesOverflowPolicy::DropOldest, None).unwrap();
+        assert!(queue.push(vec![0x01]).is_ok());
+        assert!(queue.push(vec![0x02]).is_ok());
+        assert!(queue.push(vec![0x03]).is_ok());
+        assert_eq!(queue.len(), 2, "Queue len should be 2, but is {}", queue.len());
+        assert_eq!(queue.frames[0], vec![0x02], "Oldest frame should have been dropped");
+        //Drop newest
+        let mut queue: OutboundQueue = OutboundQueue::new(2, OctopipesOverflowPolicy::DropNewest, None).unwrap();
+        assert!(queue.push(vec![0x01]).is_ok());
+        assert!(queue.push(vec![0x02]).is_ok());
+        assert!(queue.push(vec![0x03]).is_ok());
+        assert_eq!(queue.len(), 2, "Queue len should be 2, but is {}", queue.len());
+        assert_eq!(queue.frames[1], vec![0x02], "Newest frame should have been dropped");
+        //Reject
+        let mut queue: OutboundQueue = OutboundQueue::new(1, OctopipesOverflowPolicy::Reject, None).unwrap();
+        assert!(queue.push(vec![0x01]).is_ok());
+        assert_eq!(queue.push(vec![0x02]).err().unwrap(), OctopipesError::QueueFull, "Push should have returned QueueFull");
+        assert_eq!(queue.len(), 1, "Queue len should be 1, but is {}", queue.len());
+    }
+
+    #[test]
+    fn test_queue_flush() {
+        let mut queue: OutboundQueue = OutboundQueue::new(8, OctopipesOverflowPolicy::Reject, None).unwrap();
+        for i in 0..4 {
+            assert!(queue.push(vec![i]).is_ok());
+        }
+        //Fail at third frame
+        let mut written: Vec<Vec<u8>> = Vec::new();
+        let result = queue.flush(|frame| {
+            if frame[0] == 2 {
+                return Err(OctopipesError::WriteFailed);
+            }
+            written.push(frame);
+            Ok(())
+        });
+        assert_eq!(result.err().unwrap(), OctopipesError::WriteFailed, "Flush should have failed");
+        assert_eq!(written, vec![vec![0], vec![1]], "First two frames should have been written in order");
+        assert_eq!(queue.len(), 2, "Two frames should be still queued, but are {}", queue.len());
+        //Flush the rest
+        written.clear();
+        assert!(queue.flush(|frame| {
+            written.push(frame);
+            Ok(())
+        }).is_ok());
+        assert_eq!(written, vec![vec![2], vec![3]], "Remaining frames should have been written in order");
+        assert_eq!(queue.len(), 0, "Queue should be empty");
+    }
+
+    #[test]
+    fn test_queue_persistence() {
+        let path: String = String::from("/tmp/rustypipes_queue_test.dat");
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut queue: OutboundQueue = OutboundQueue::new(4, OctopipesOverflowPolicy::Reject, Some(path.clone())).unwrap();
+            assert!(queue.push(vec![0x01, 0x02, 0x03]).is_ok());
+            assert!(queue.push(vec![0x04]).is_ok());
+        }
+        //Reload queue
+        let queue: OutboundQueue = OutboundQueue::new(4, OctopipesOverflowPolicy::Reject, Some(path.clone())).unwrap();
+        assert_eq!(queue.len(), 2, "Reloaded queue len should be 2, but is {}", queue.len());
+        assert_eq!(queue.frames[0], vec![0x01, 0x02, 0x03], "First frame mismatch");
+        assert_eq!(queue.frames[1], vec![0x04], "Second frame mismatch");
+        let _ = std::fs::remove_file(&path);
+    }
+}
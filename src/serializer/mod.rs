@@ -35,6 +35,7 @@ const STX: u8 = 0x02;
 const ETX: u8 = 0x03;
 
 const MINIMUM_SIZE_VERSION_1: usize = 17;
+const MAX_PACKET_SIZE: usize = 16 * 1024 * 1024; //Packets declaring a bigger size are rejected
 
 /// ### encode_message
 ///
@@ -212,7 +213,10 @@ pub(super) fn decode_message(data: Vec<u8>) -> Result<OctopipesMessage, Octopipe
                     }
                     curr_index += 1;
                     //Data
-                    final_index = curr_index + (data_size as usize);
+                    final_index = match curr_index.checked_add(data_size as usize) {
+                        Some(final_index) => final_index,
+                        None => return Err(OctopipesError::BadPacket),
+                    };
                     //Verify if data fits
                     if final_index >= data.len() {
                         return Err(OctopipesError::BadPacket);
@@ -243,6 +247,77 @@ pub(super) fn decode_message(data: Vec<u8>) -> Result<OctopipesMessage, Octopipe
     }
 }
 
+/// ### decode_messages
+///
+/// `decode_messages` decodes a buffer which may contain more than one Octopipes packet (e.g. when a reader collected several writes at once)
+/// into a list of OctopipesMessage. The decoded packets are removed from the buffer, while an incomplete trailing packet is left there,
+/// so that it can be completed with the data of the next read. If the header of a packet is not valid, the rest of the buffer is decoded as a single packet.
+/// Packets larger than MAX_PACKET_SIZE are not waited for: they're reported as BadPacket and the buffer is discarded
+pub(super) fn decode_messages(buffer: &mut Vec<u8>) -> Vec<Result<OctopipesMessage, OctopipesError>> {
+    let mut messages: Vec<Result<OctopipesMessage, OctopipesError>> = Vec::new();
+    while !buffer.is_empty() {
+        match get_packet_size(buffer) {
+            Ok(Some(packet_size)) if packet_size <= buffer.len() => {
+                //Split packet from the rest of the buffer
+                let remaining: Vec<u8> = buffer.split_off(packet_size);
+                messages.push(decode_message(std::mem::replace(buffer, remaining)));
+            }
+            Ok(..) if buffer.len() > MAX_PACKET_SIZE => {
+                //Can't be a valid packet: discard it
+                buffer.clear();
+                messages.push(Err(OctopipesError::BadPacket));
+            }
+            Ok(..) => break, //Incomplete packet: wait for the rest
+            Err(..) => {
+                //Not splittable
+                messages.push(decode_message(std::mem::take(buffer)));
+                break;
+            }
+        }
+    }
+    messages
+}
+
+/// ### get_packet_size
+///
+/// `get_packet_size` returns the size of the first packet in the buffer reading its header. Returns None if the header is incomplete
+/// and BadPacket if the header is not valid or the packet is larger than MAX_PACKET_SIZE
+fn get_packet_size(data: &[u8]) -> Result<Option<usize>, OctopipesError> {
+    if data[0] != SOH {
+        return Err(OctopipesError::BadPacket);
+    }
+    if data.len() < 3 {
+        return match data.len() == 2 && OctopipesProtocolVersion::from_u8(data[1]).is_none() {
+            true => Err(OctopipesError::BadPacket),
+            false => Ok(None),
+        };
+    }
+    match OctopipesProtocolVersion::from_u8(data[1]) {
+        Some(OctopipesProtocolVersion::Version1) => {
+            let origin_size: usize = data[2] as usize;
+            let remote_size: usize = match data.get(3 + origin_size) {
+                Some(remote_size) => *remote_size as usize,
+                None => return Ok(None),
+            };
+            //Data size is after remote and ttl
+            let data_size_index: usize = 3 + origin_size + 1 + remote_size + 1;
+            let data_size_bytes = match data.get(data_size_index..data_size_index + 8) {
+                Some(data_size_bytes) => data_size_bytes,
+                None => return Ok(None),
+            };
+            let mut data_size: u64 = 0;
+            for byte in data_size_bytes {
+                data_size = (data_size << 8) | *byte as u64;
+            }
+            match (MINIMUM_SIZE_VERSION_1 + origin_size + remote_size).checked_add(data_size as usize) {
+                Some(packet_size) if packet_size <= MAX_PACKET_SIZE => Ok(Some(packet_size)),
+                _ => Err(OctopipesError::BadPacket),
+            }
+        }
+        None => Err(OctopipesError::UnsupportedVersion),
+    }
+}
+
 /// ### calculate_checksum
 ///
 /// `calculate_checksum` Calculate checksum for the provided Octopipes Message
@@ -1161,6 +1236,107 @@ mod tests {
         println!("Decode No remote passed");
     }
 
+    #[test]
+    fn test_decode_messages() {
+        //Encode two messages and concatenate them, as if they had been read at once
+        let message_a: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("test_client")),
+            &Some(String::from("test_remote")),
+            60,
+            OctopipesOptions::RCK,
+            vec![1, 2, 3],
+        );
+        let message_b: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("test_client")),
+            &Some(String::from("BROADCAST")),
+            60,
+            OctopipesOptions::empty(),
+            vec![4, 5, 6, 7, 8],
+        );
+        let mut data: Vec<u8> = encode_message(&message_a).expect("Could not encode message");
+        data.extend(encode_message(&message_b).expect("Could not encode message"));
+        let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+        assert!(data.is_empty(), "Buffer should have been consumed");
+        assert_eq!(messages.len(), 2, "Should have decoded 2 messages, but decoded {}", messages.len());
+        let decoded_a: &OctopipesMessage = messages[0].as_ref().expect("First message should be valid");
+        assert_eq!(*decoded_a.remote.as_ref().unwrap(), String::from("test_remote"), "First message remote should be test_remote");
+        assert_eq!(decoded_a.data, vec![1, 2, 3], "First message data mismatch");
+        let decoded_b: &OctopipesMessage = messages[1].as_ref().expect("Second message should be valid");
+        assert_eq!(*decoded_b.remote.as_ref().unwrap(), String::from("BROADCAST"), "Second message remote should be BROADCAST");
+        assert_eq!(decoded_b.data, vec![4, 5, 6, 7, 8], "Second message data mismatch");
+        //A truncated trailing packet is kept in the buffer until the rest is read (at any split point)
+        let frame_b: Vec<u8> = encode_message(&message_b).expect("Could not encode message");
+        for split in 1..frame_b.len() {
+            let mut data: Vec<u8> = encode_message(&message_a).expect("Could not encode message");
+            data.extend_from_slice(&frame_b[..split]);
+            let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+            assert_eq!(messages.len(), 1, "Should have decoded 1 message, but decoded {} (split at {})", messages.len(), split);
+            assert!(messages[0].is_ok(), "First message should be valid");
+            assert_eq!(data, frame_b[..split].to_vec(), "Truncated packet should be left in the buffer");
+            data.extend_from_slice(&frame_b[split..]);
+            let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+            assert_eq!(messages.len(), 1, "Should have decoded 1 message, but decoded {} (split at {})", messages.len(), split);
+            assert_eq!(messages[0].as_ref().expect("Second message should be valid").data, vec![4, 5, 6, 7, 8]);
+            assert!(data.is_empty(), "Buffer should have been consumed");
+        }
+        //Garbage is reported as a bad packet and discarded
+        let mut data: Vec<u8> = vec![0xaa, 0xbb, 0xcc];
+        let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+        assert_eq!(messages.len(), 1, "Should have decoded 1 message, but decoded {}", messages.len());
+        assert_eq!(*messages[0].as_ref().err().unwrap(), OctopipesError::BadPacket, "Garbage should be a bad packet");
+        assert!(data.is_empty(), "Garbage should have been discarded");
+    }
+
+    #[test]
+    fn test_decode_messages_oversized() {
+        //A header declaring a huge data size mustn't make the buffer grow forever
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("test_client")),
+            &Some(String::from("test_remote")),
+            60,
+            OctopipesOptions::ICK,
+            vec![1, 2, 3],
+        );
+        let frame: Vec<u8> = encode_message(&message).expect("Could not encode message");
+        for data_size in [(MAX_PACKET_SIZE as u64) + 1, u64::MAX].iter() {
+            let mut data: Vec<u8> = frame.clone();
+            //Data size is after SOH, version, origin, remote and ttl
+            let data_size_index: usize = 3 + 11 + 1 + 11 + 1;
+            data[data_size_index..data_size_index + 8].copy_from_slice(&data_size.to_be_bytes());
+            //Followed by valid frames
+            data.extend(frame.iter());
+            let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+            assert_eq!(messages.len(), 1, "Should have decoded 1 message, but decoded {}", messages.len());
+            assert_eq!(*messages[0].as_ref().err().unwrap(), OctopipesError::BadPacket, "Oversized packet should be a bad packet");
+            assert!(data.is_empty(), "Buffer should have been discarded");
+            //Reading can go on with the next frames
+            data.extend(frame.iter());
+            let messages: Vec<Result<OctopipesMessage, OctopipesError>> = decode_messages(&mut data);
+            assert_eq!(messages.len(), 1, "Should have decoded 1 message, but decoded {}", messages.len());
+            assert_eq!(messages[0].as_ref().expect("Message should be valid").data, vec![1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_encode_decode_noecho() {
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &OctopipesProtocolVersion::Version1,
+            &Some(String::from("test_client")),
+            &Some(String::from("BROADCAST")),
+            60,
+            OctopipesOptions::NOECHO | OctopipesOptions::RCK,
+            vec![1, 2, 3],
+        );
+        let data: Vec<u8> = encode_message(&message).expect("Could not encode message");
+        let decoded: OctopipesMessage = decode_message(data).expect("Could not decode message");
+        assert!(decoded.isset_option(OctopipesOptions::NOECHO), "NOECHO should be set");
+        assert!(decoded.isset_option(OctopipesOptions::RCK), "RCK should be set");
+        assert!(!decoded.isset_option(OctopipesOptions::ICK), "ICK shouldn't be set");
+    }
+
     #[test]
     fn test_decode_bad_encoded() {
         println!("Testing decoding bad encoded");
@@ -26,24 +26,43 @@
 // SOFTWARE.
 //
 
+use super::CapQuery;
+use super::DurableSession;
 use super::OctopipesCapError;
 use super::OctopipesCapMessage;
+use super::OctopipesDeadLetter;
+use super::OctopipesDeadLetterReason;
+use super::OctopipesDurableLimits;
+use super::OctopipesError;
 use super::OctopipesMessage;
 use super::OctopipesOptions;
+use super::OctopipesPresenceEvent;
+use super::OctopipesPresenceKind;
 use super::OctopipesProtocolVersion;
 use super::OctopipesServer;
 use super::OctopipesServerError;
+use super::OctopipesServerEvent;
 use super::OctopipesServerState;
 use super::OctopipesServerWorker;
+use super::RoutingIndex;
+use super::StoredMessage;
 use super::Subscription;
+use super::PRESENCE_GROUP;
 
 use super::cap;
 use super::pipes;
 use super::serializer;
+use super::topic;
 
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::fs::FileTypeExt;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+const EVENT_QUEUE_CAPACITY: usize = 1024; //Max events kept until the next poll
+const REPLAY_TTL: u8 = 1; //Max seconds to wait for a client reading a queued message (processing waits meanwhile)
 
 impl OctopipesServer {
     /// ###  new
@@ -61,13 +80,27 @@ impl OctopipesServer {
             client_folder: client_folder,
             cap_receiver: None,
             cap_listener: None,
-            workers: Vec::new(),
+            workers: HashMap::new(),
+            clients: Vec::new(),
+            routes: RoutingIndex::new(),
+            retained: RefCell::new(HashMap::new()),
+            durable: RefCell::new(HashMap::new()),
+            durable_limits: OctopipesDurableLimits::default(),
+            dead_letter_group: None,
+            client_timeout: None,
+            drain_deadline: Duration::from_secs(2),
+            events: RefCell::new(VecDeque::new()),
+            on_client_reaped_fn: None,
+            on_dead_letter_fn: None,
+            on_event_fn: None,
         }
     }
 
     /// ###  stop_server
     ///
-    /// `stop_server` stops the octopipes server (workers and cap listener)
+    /// `stop_server` stops the octopipes server (workers and cap listener).
+    /// Subscribed clients are notified with a SHUTDOWN CAP message first, then the messages pending in the workers
+    /// are dispatched until there are no more or the drain deadline expires
     pub fn stop_server(&mut self) -> Result<(), OctopipesServerError> {
         {
             let current_state = self.state.lock().unwrap();
@@ -75,8 +108,51 @@ impl OctopipesServer {
                 return Ok(())
             }
         }
+        //Notify clients (don't wait long for clients which are not reading)
+        let notice: Vec<u8> = cap::encode_shutdown();
+        for worker in self.iter_workers() {
+            let message: OctopipesMessage = OctopipesMessage::new(
+                &self.version,
+                &None,
+                &Some(worker.client_id.clone()),
+                0,
+                OctopipesOptions::empty(),
+                notice.clone(),
+            );
+            if let Ok(data_out) = serializer::encode_message(&message) {
+                let _ = pipes::pipe_write(&worker.pipe_write, 200, &data_out);
+            }
+        }
+        //Drain pending messages; workers which fail (e.g. can't read their pipe anymore) are skipped
+        let t_start: Instant = Instant::now();
+        let mut draining: Vec<String> = self.clients.clone();
+        while !draining.is_empty() && t_start.elapsed() < self.drain_deadline {
+            let mut processed: usize = 0;
+            let mut failed: Vec<String> = Vec::new();
+            for client in draining.iter() {
+                let worker: &OctopipesServerWorker = match self.workers.get(client) {
+                    Some(worker) => worker,
+                    None => continue,
+                };
+                match worker.get_next_message() {
+                    Ok(Some(message)) => {
+                        let _ = self.dispatch_message(&message);
+                        processed += 1;
+                    }
+                    Ok(None) => {}
+                    Err(OctopipesServerError::BadPacket)
+                    | Err(OctopipesServerError::BadChecksum)
+                    | Err(OctopipesServerError::BadOrigin) => processed += 1, //Rejected frame, go on with the next one
+                    Err(..) => failed.push(client.clone()),
+                }
+            }
+            if processed == 0 {
+                break;
+            }
+            draining.retain(|client| !failed.contains(client));
+        }
         //Stop workers
-        for worker in self.workers.iter_mut() {
+        for worker in self.workers.values_mut() {
             if let Err(error) = worker.stop_worker() {
                 return Err(error)
             }
@@ -104,14 +180,15 @@ impl OctopipesServer {
         }
         //Delete all pipes in client folder first
         if let Ok(files) = std::fs::read_dir(self.client_folder.clone()) {
-            //Delete all files
+            //Delete all files and pipes (a server which crashed leaves the pipes of its clients behind)
             for file_res in files {
                 if let Ok(file) = file_res {
-                    let file_path = file.path();
-                    if file_path.is_file() {
-                        let _ = std::fs::remove_file(file_path);
+                    if let Ok(file_type) = file.file_type() {
+                        if file_type.is_file() || file_type.is_fifo() {
+                            let _ = std::fs::remove_file(file.path());
+                        }
                     }
-                } 
+                }
             }
         }
         //Create CAP copy
@@ -131,6 +208,7 @@ impl OctopipesServer {
         self.cap_receiver = Some(cap_receiver);
         self.cap_listener = Some(thread::spawn(move || {
             let mut terminate_thread: bool = false;
+            let mut pending: Vec<u8> = Vec::new(); //Incomplete packet waiting for the next read
             while !terminate_thread {
                 {
                     let current_server_state = server_state_clone.lock().unwrap();
@@ -157,23 +235,17 @@ impl OctopipesServer {
                             continue;
                         },
                         Some(data_in) => {
-                            //Parse message
-                            match serializer::decode_message(data_in) {
-                                Err(err) => {
-                                    if let Err(_) = cap_sender.send(Err(err.to_server_error())) {
-                                        break; //Terminate thread
-                                    }
-                                }
-                                Ok(message) => {
-                                    //Send CAP message
-                                    if let Err(_) = cap_sender.send(Ok(message)) {
-                                        break; //Terminate thread
-                                    }
+                            //Parse messages (several clients could have written to the CAP)
+                            pending.extend(data_in);
+                            for result in serializer::decode_messages(&mut pending) {
+                                let result = result.map_err(|err| err.to_server_error());
+                                //Send CAP message
+                                if cap_sender.send(result).is_err() {
+                                    terminate_thread = true; //Terminate thread
                                 }
                             }
                         }
                     }
-                    thread::sleep(Duration::from_millis(100));
                 }
             }
         }));
@@ -200,61 +272,31 @@ impl OctopipesServer {
         }
     }
 
-    /// ###  lock_cap
+    /// ###  write_reply
     ///
-    /// `lock_cap` Set Server state to BLOCK in order to stop reading from pipe
-    fn lock_cap(&mut self) {
-        let mut server_state = self.state.lock().unwrap();
-        *server_state = OctopipesServerState::Block;
-        thread::sleep(Duration::from_millis(100)); //Give main thread the time to block
-    }
-
-    /// ###  unlock_cap
-    ///
-    /// `unlock_cap` Set server state back to RUNNING in order to allow read from pipe
-    fn unlock_cap(&mut self) {
-        let mut server_state = self.state.lock().unwrap();
-        *server_state = OctopipesServerState::Running;
-    }
-
-    /// ###  write_cap
-    ///
-    /// `write_cap` write a message to the CAP
-    fn write_cap(
+    /// `write_reply` write a reply to a CAP request to the reply pipe of the client
+    fn write_reply(
         &mut self,
-        client: &String,
+        client: &str,
         data_out: Vec<u8>,
     ) -> Result<(), OctopipesServerError> {
-        //Block CAP
-        self.lock_cap();
         //Prepare message
         let message: OctopipesMessage = OctopipesMessage::new(
             &self.version,
             &None,
-            &Some(client.clone()),
+            &Some(client.to_string()),
             60,
             OctopipesOptions::empty(),
             data_out,
         );
         //Encode message
         match serializer::encode_message(&message) {
-            Err(err) => {
-                self.unlock_cap();
-                Err(err.to_server_error())
-            }
+            Err(err) => Err(err.to_server_error()),
             Ok(data) => {
-                //Write data out
-                match pipes::pipe_write(&self.cap_pipe, 60000, data) {
-                    Ok(..) => {
-                        //Unlock CAP
-                        self.unlock_cap();
-                        Ok(())
-                    }
-                    Err(..) => {
-                        //Unlock CAP
-                        self.unlock_cap();
-                        Err(OctopipesServerError::WriteFailed)
-                    }
+                //Write data out (the client is waiting for it, so don't wait longer than it does)
+                match pipes::pipe_write(&cap::reply_pipe(&self.cap_pipe, client), 5000, &data) {
+                    Ok(..) => Ok(()),
+                    Err(..) => Err(OctopipesServerError::WriteFailed),
                 }
             }
         }
@@ -287,8 +329,12 @@ impl OctopipesServer {
         //Instance new worker
         match OctopipesServerWorker::new(client, subscriptions, cli_tx_pipe, cli_rx_pipe) {
             Ok(new_worker) => {
-                //Push new worker
-                self.workers.push(new_worker);
+                //Index subscriptions and push new worker
+                for group in new_worker.subscription.groups.iter() {
+                    self.routes.insert(group, &new_worker.client_id);
+                }
+                self.clients.push(new_worker.client_id.clone());
+                self.workers.insert(new_worker.client_id.clone(), new_worker);
                 Ok(())
             }
             Err(err) => Err(err),
@@ -297,21 +343,67 @@ impl OctopipesServer {
 
     /// ###  stop_worker
     ///
-    /// `stop_worker` stops a running worker for the Octopipes Server. The server must be in Running state
+    /// `stop_worker` stops a running worker for the Octopipes Server. The server must be in Running state.
+    /// Messages received by the worker and not processed yet are dispatched before the worker is removed
     pub fn stop_worker(&mut self, client: &String) -> Result<(), OctopipesServerError> {
-        //Look for worker in workers
-        let mut item: usize = 0;
-        for worker in self.workers.iter_mut() {
-            if worker.client_id == *client {
-                let result = worker.stop_worker();
-                drop(worker);
-                //Remove worker from workers
-                self.workers.remove(item);
-                return result;
+        //Remove worker from workers
+        let mut worker: OctopipesServerWorker = match self.workers.remove(client) {
+            Some(worker) => worker,
+            None => return Err(OctopipesServerError::WorkerNotFound),
+        };
+        self.clients.retain(|client_id| client_id != client);
+        for group in worker.subscription.groups.iter() {
+            self.routes.remove(group, client);
+        }
+        let result = worker.stop_worker();
+        //Messages sent by the client before leaving must still be delivered
+        let mut pending: Vec<OctopipesMessage> = Vec::new();
+        loop {
+            match worker.get_next_message() {
+                Ok(Some(message)) => pending.push(message),
+                Ok(None) | Err(OctopipesServerError::WorkerNotRunning) => break,
+                Err(..) => continue, //Rejected frame
             }
-            item += 1;
         }
-        Err(OctopipesServerError::WorkerNotFound)
+        for message in pending.iter() {
+            let _ = self.dispatch_message(message);
+        }
+        //Store messages for durable clients while they're offline (not the ones the client has just sent)
+        if worker.subscription.durable {
+            let mut session: DurableSession = DurableSession::new(worker.subscription.groups.clone());
+            session.restore(worker.stored.take(), &self.durable_limits);
+            self.durable.borrow_mut().insert(client.clone(), session);
+        }
+        drop(worker);
+        self.emit(OctopipesServerEvent::ClientUnsubscribed { client: client.clone() });
+        result
+    }
+
+    /// ###  kick
+    ///
+    /// `kick` forcibly disconnects a client: a DISCONNECT CAP message with the provided reason is sent to the client and then its worker is stopped.
+    /// The worker is stopped even if the client couldn't be notified
+    pub fn kick(&mut self, client_id: &str, reason: &str) -> Result<(), OctopipesServerError> {
+        let groups: Vec<String> = self.get_client_groups(client_id);
+        let worker: &OctopipesServerWorker = match self.workers.get(client_id) {
+            Some(worker) => worker,
+            None => return Err(OctopipesServerError::WorkerNotFound),
+        };
+        //Notify the client on its RX pipe
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &self.version,
+            &None,
+            &Some(String::from(client_id)),
+            2,
+            OctopipesOptions::empty(),
+            cap::encode_disconnect(reason),
+        );
+        let _ = worker.send(&message);
+        self.stop_worker(&String::from(client_id))?;
+        //Kicked clients lose their durable session
+        self.durable.borrow_mut().remove(client_id);
+        self.publish_presence(OctopipesPresenceKind::Kicked, client_id, groups);
+        Ok(())
     }
 
     /// ### dispatch_message
@@ -326,22 +418,180 @@ impl OctopipesServer {
             return Err((None, OctopipesServerError::NoRecipient));
         }
         let recipient: String = message.remote.as_ref().unwrap().clone();
-        let workers_associated: Vec<&OctopipesServerWorker> = self.match_subscription(&recipient);
-        //For each associated worker, send the message out
+        //Only the server can publish to $SYS groups (e.g. presence events)
+        if message.origin.is_some() && topic::is_sys_group(&recipient) {
+            return Err((None, OctopipesServerError::ReservedGroup));
+        }
+        //Store (or clear if payload is empty) the retained message for this group
+        let retained: bool = message.options.intersects(OctopipesOptions::RETAIN) && !message.data.is_empty();
+        if message.options.intersects(OctopipesOptions::RETAIN) {
+            let mut retained = self.retained.borrow_mut();
+            match message.data.is_empty() {
+                true => retained.remove(&recipient),
+                false => retained.insert(recipient.clone(), message.clone()),
+            };
+        }
+        //The origin doesn't get its own message back if it set NOECHO (nor takes the queue group turns)
+        let exclude: Option<&str> = match message.options.intersects(OctopipesOptions::NOECHO) {
+            true => message.origin.as_deref(),
+            false => None,
+        };
+        let workers_associated: Vec<&OctopipesServerWorker> = self.select_recipients(&recipient, exclude);
+        let mut durable = self.durable.borrow_mut();
+        //Discard the sessions of the clients which have been offline for too long
+        durable.retain(|_, session| !session.is_expired(&self.durable_limits));
+        let mut offline_sessions: Vec<&mut DurableSession> =
+            durable.values_mut().filter(|session| session.matches(&recipient)).collect();
+        if workers_associated.is_empty() && offline_sessions.is_empty() {
+            drop(offline_sessions);
+            drop(durable);
+            //A retained message is kept for the clients subscribing later, so it's not undeliverable
+            if !retained {
+                self.dead_letter(message, OctopipesDeadLetterReason::NoSubscribers, None);
+            }
+            return Ok(());
+        }
+        //Encode the message once and send the same frame to each associated worker
+        let data_out: Vec<u8> = match serializer::encode_message(message) {
+            Ok(data_out) => data_out,
+            Err(err) => return Err((None, err.to_server_error())),
+        };
+        //Store message for offline durable clients
+        for session in offline_sessions.iter_mut() {
+            session.push(&data_out, message.ttl, &self.durable_limits);
+        }
+        drop(offline_sessions);
+        drop(durable);
+        //Keep delivering to the other recipients if a write fails; the first error is returned
+        let mut result: Result<(), (Option<String>, OctopipesServerError)> = Ok(());
+        let mut recipients: Vec<String> = Vec::new();
         for worker in workers_associated {
-            if let Err(error) = worker.send(message) {
-                return Err((Some(worker.client_id.clone()), error));
+            //Queued messages come first
+            self.flush_replay(worker);
+            match worker.write_encoded(&data_out, message.ttl) {
+                Ok(..) => recipients.push(worker.client_id.clone()),
+                Err(reason) => {
+                    self.dead_letter(message, reason, Some(&worker.client_id));
+                    if result.is_ok() {
+                        result = Err((Some(worker.client_id.clone()), OctopipesServerError::WriteFailed));
+                    }
+                }
+            }
+        }
+        if !recipients.is_empty() {
+            self.emit(OctopipesServerEvent::MessageRouted {
+                origin: message.origin.clone(),
+                remote: recipient,
+                recipients,
+            });
+        }
+        result
+    }
+
+    /// ### reject
+    ///
+    /// `reject` sends an ERROR to the client whose message couldn't be decoded, had no recipient, claimed another origin
+    /// or was addressed to a $SYS group.
+    /// Returns whether the error was a rejection
+    fn reject(&self, worker: &OctopipesServerWorker, error: OctopipesServerError) -> bool {
+        let client_error: OctopipesError = match error {
+            OctopipesServerError::BadPacket => OctopipesError::BadPacket,
+            OctopipesServerError::BadChecksum => OctopipesError::BadChecksum,
+            OctopipesServerError::NoRecipient => OctopipesError::NoRecipient,
+            OctopipesServerError::BadOrigin => OctopipesError::BadPacket,
+            OctopipesServerError::ReservedGroup => OctopipesError::ReservedGroup,
+            _ => return false,
+        };
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &self.version,
+            &None,
+            &Some(worker.client_id.clone()),
+            1,
+            OctopipesOptions::empty(),
+            cap::encode_error(client_error),
+        );
+        let _ = worker.send(&message);
+        self.emit(OctopipesServerEvent::DecodeError {
+            client: worker.client_id.clone(),
+            error,
+        });
+        true
+    }
+
+    /// ### emit
+    ///
+    /// `emit` passes an event to the event callback and queues it for `poll_events`; the oldest events are dropped when the queue is full
+    fn emit(&self, event: OctopipesServerEvent) {
+        if let Some(on_event) = self.on_event_fn {
+            on_event(&event);
+        }
+        let mut events = self.events.borrow_mut();
+        if events.len() >= EVENT_QUEUE_CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// ### dead_letter
+    ///
+    /// `dead_letter` reports an undeliverable message to the dead letter callback and group.
+    /// If the origin asked for RCK, it gets a NACK with the reason as payload.
+    /// Messages sent by the server itself (presence events, dead letters...) are ignored
+    fn dead_letter(&self, message: &OctopipesMessage, reason: OctopipesDeadLetterReason, recipient: Option<&str>) {
+        let origin: &String = match message.origin.as_ref() {
+            Some(origin) => origin,
+            None => return,
+        };
+        let dead_letter: OctopipesDeadLetter = OctopipesDeadLetter::new(reason, recipient, message);
+        if let Some(on_dead_letter) = self.on_dead_letter_fn {
+            on_dead_letter(&dead_letter);
+        }
+        self.emit(OctopipesServerEvent::MessageDropped(dead_letter.clone()));
+        if let Some(group) = self.dead_letter_group.as_ref() {
+            if let Ok(payload) = dead_letter.encode() {
+                let report: OctopipesMessage = OctopipesMessage::new(
+                    &self.version,
+                    &None,
+                    &Some(group.clone()),
+                    5,
+                    OctopipesOptions::empty(),
+                    payload,
+                );
+                let _ = self.dispatch_message(&report);
+            }
+        }
+        if message.options.intersects(OctopipesOptions::RCK) {
+            if let Some(worker) = self.workers.get(origin) {
+                let nack: OctopipesMessage = OctopipesMessage::new(
+                    &self.version,
+                    &None,
+                    &Some(origin.clone()),
+                    1,
+                    OctopipesOptions::NACK,
+                    vec![reason as u8],
+                );
+                let _ = worker.send(&nack);
             }
         }
-        Ok(())
     }
     //@! Management
 
     /// ### process_cap_once
     ///
-    /// `process_cap_once` Reads up to one message from the CAP receiver and process it.
+    /// `process_cap_once` Reads up to one message from the CAP receiver and process it; then reaps the dead clients.
     /// When Ok, returns the amount of messages processed (0/1), otherwise an Error
     pub fn process_cap_once(&mut self) -> Result<usize, OctopipesServerError> {
+        let result: Result<usize, OctopipesServerError> = self.receive_cap_message();
+        if result != Err(OctopipesServerError::Uninitialized) {
+            self.reap_clients();
+        }
+        result
+    }
+
+    /// ### receive_cap_message
+    ///
+    /// `receive_cap_message` Reads up to one message from the CAP receiver and process it
+    fn receive_cap_message(&mut self) -> Result<usize, OctopipesServerError> {
         {
             //Check if server is intiialized
             let current_server_state = self.state.lock().unwrap();
@@ -379,7 +629,7 @@ impl OctopipesServer {
 
     /// ### process_cap_all
     ///
-    /// `process_cap_all` Reads all the available messages on the CAP until no one is available.
+    /// `process_cap_all` Reads all the available messages on the CAP until no one is available; dead clients are reaped on the way.
     /// When Ok, returns the amount of messages processed, otherwise Error
     pub fn process_cap_all(&mut self) -> Result<usize, OctopipesServerError> {
         let mut amount_of_process: usize = 0;
@@ -399,6 +649,85 @@ impl OctopipesServer {
         Ok(amount_of_process)
     }
 
+    /// ### poll_events
+    ///
+    /// `poll_events` processes the pending CAP requests, the messages queued for the clients and the messages received by the workers,
+    /// then returns the events occurred since the last poll (ordered by occurrence).
+    /// Errors occurred while processing the workers are returned as events; workers whose thread has terminated are stopped
+    /// and their clients are reported as left to the presence group.
+    /// It fails only if the server isn't running
+    pub fn poll_events(&mut self) -> Result<Vec<OctopipesServerEvent>, OctopipesServerError> {
+        //Serve CAP requests (clients are already told about failed requests in the CAP reply)
+        loop {
+            match self.process_cap_once() {
+                Ok(0) => break,
+                Ok(..) => continue,
+                Err(OctopipesServerError::Uninitialized) => return Err(OctopipesServerError::Uninitialized),
+                Err(OctopipesServerError::WorkerNotRunning) => return Err(OctopipesServerError::WorkerNotRunning), //CAP listener died
+                Err(..) => continue,
+            }
+        }
+        //Send the messages queued for the clients which have started reading
+        self.iter_workers().for_each(|worker| self.flush_replay(worker));
+        //Dispatch the messages received by each worker; workers whose thread has terminated are stopped
+        let mut dead_workers: Vec<String> = Vec::new();
+        for worker in self.iter_workers() {
+            loop {
+                match worker.get_next_message() {
+                    Ok(None) => break,
+                    Ok(Some(message)) => {
+                        if let Err((_, error)) = self.dispatch_message(&message) {
+                            self.reject(worker, error);
+                        }
+                    }
+                    Err(OctopipesServerError::WorkerNotRunning) => {
+                        self.emit(OctopipesServerEvent::WorkerFailed {
+                            client: worker.client_id.clone(),
+                            error: OctopipesServerError::WorkerNotRunning,
+                        });
+                        dead_workers.push(worker.client_id.clone());
+                        break;
+                    }
+                    Err(error) => {
+                        if !self.reject(worker, error) {
+                            self.emit(OctopipesServerEvent::WorkerFailed {
+                                client: worker.client_id.clone(),
+                                error,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        for client in dead_workers.iter() {
+            let _ = self.remove_client(client, OctopipesPresenceKind::Left);
+        }
+        Ok(self.events.borrow_mut().drain(..).collect())
+    }
+
+    /// ### reap_clients
+    ///
+    /// `reap_clients` stops the workers whose client hasn't sent anything on the CAP or on its TX pipe for longer than the client timeout.
+    /// Returns the ids of the reaped clients
+    pub fn reap_clients(&mut self) -> Vec<String> {
+        let timeout: Duration = match self.client_timeout {
+            Some(timeout) => timeout,
+            None => return vec![],
+        };
+        let dead_clients: Vec<String> = self
+            .iter_workers()
+            .filter(|worker| worker.subscription.last_seen.lock().unwrap().elapsed() > timeout)
+            .map(|worker| worker.client_id.clone())
+            .collect();
+        for client in dead_clients.iter() {
+            let _ = self.remove_client(client, OctopipesPresenceKind::Reaped);
+            if let Some(on_client_reaped) = self.on_client_reaped_fn {
+                (on_client_reaped)(client);
+            }
+        }
+        dead_clients
+    }
+
     /// ### manage_cap_message
     ///
     /// `manage_cap_message` Takes a Message from CAP and based on its type perform an action to the server.
@@ -414,6 +743,14 @@ impl OctopipesServer {
             None => return Err(OctopipesServerError::NoRecipient),
             Some(client) => origin = client.clone(),
         }
+        //The reply pipe path is built from the origin, so an invalid id (e.g. with '/') mustn't get any reply
+        if !topic::is_valid_client_id(&origin) {
+            return Err(OctopipesServerError::InvalidClientId);
+        }
+        //Any message from the client proves it is alive
+        if let Some(worker) = self.workers.get_mut(&origin) {
+            *worker.subscription.last_seen.lock().unwrap() = Instant::now();
+        }
         //Get message type
         match cap::get_cap_message_type(&message.data) {
             Err(err) => Err(err.to_server_error()),
@@ -423,10 +760,10 @@ impl OctopipesServer {
                         //Parse subscription message
                         match cap::decode_subscription(&message.data) {
                             Err(err) => Err(err.to_server_error()),
-                            Ok(mut groups) => {
+                            Ok((mut groups, durable)) => {
                                 //@! Very important, add client id to groups
                                 groups.push(origin.clone());
-                                self.manage_subscription(&origin, &groups)
+                                self.manage_subscription(&origin, &groups, durable)
                             }
                         }
                     }
@@ -437,6 +774,37 @@ impl OctopipesServer {
                             Ok(..) => self.manage_unsubscription(&origin),
                         }
                     }
+                    OctopipesCapMessage::Join => {
+                        //Parse join
+                        match cap::decode_join(&message.data) {
+                            Err(err) => Err(err.to_server_error()),
+                            Ok(groups) => self.manage_join(&origin, groups),
+                        }
+                    }
+                    OctopipesCapMessage::Heartbeat => {
+                        //Parse heartbeat
+                        match cap::decode_heartbeat(&message.data) {
+                            Err(err) => Err(err.to_server_error()),
+                            Ok(..) => match self.worker_exists(&origin) {
+                                true => Ok(OctopipesCapMessage::Heartbeat),
+                                false => Err(OctopipesServerError::WorkerNotFound),
+                            },
+                        }
+                    }
+                    OctopipesCapMessage::Query => {
+                        //Parse query
+                        match cap::decode_query(&message.data) {
+                            Err(err) => Err(err.to_server_error()),
+                            Ok((query, argument)) => self.manage_query(&origin, query, &argument),
+                        }
+                    }
+                    OctopipesCapMessage::Leave => {
+                        //Parse leave
+                        match cap::decode_leave(&message.data) {
+                            Err(err) => Err(err.to_server_error()),
+                            Ok(groups) => self.manage_leave(&origin, &groups),
+                        }
+                    }
                     _ => Err(OctopipesServerError::BadPacket),
                 }
             }
@@ -450,16 +818,15 @@ impl OctopipesServer {
         &mut self,
         client_id: &String,
         groups: &Vec<String>,
+        durable: bool,
     ) -> Result<OctopipesCapMessage, OctopipesServerError> {
         //Check if client is already subsribed
-        for worker in &self.workers {
-            if *client_id == worker.client_id {
-                //Encode assignment with cap error
-                let data_out: Vec<u8> =
-                    cap::encode_assignment(OctopipesCapError::NameAlreadyTaken, None, None);
-                let _ = self.write_cap(client_id, data_out);
-                return Err(OctopipesServerError::WorkerExists);
-            }
+        if self.worker_exists(client_id) {
+            //Encode assignment with cap error
+            let data_out: Vec<u8> =
+                cap::encode_assignment(OctopipesCapError::NameAlreadyTaken, None, None);
+            let _ = self.write_reply(client_id, data_out);
+            return Err(OctopipesServerError::WorkerExists);
         }
         //Okay, client doesn't exist, assign Pipes
         let tx_pipe: String = self.client_folder.clone() + "/" + client_id + "_tx.fifo";
@@ -474,7 +841,7 @@ impl OctopipesServer {
             Err(error) => {
                 let data_out: Vec<u8> =
                     cap::encode_assignment(OctopipesCapError::FileSystemError, None, None);
-                let _ = self.write_cap(client_id, data_out);
+                let _ = self.write_reply(client_id, data_out);
                 Err(error)
             }
             Ok(..) => {
@@ -484,13 +851,26 @@ impl OctopipesServer {
                     Some(&tx_pipe),
                     Some(&rx_pipe),
                 );
-                match self.write_cap(client_id, data_out) {
+                match self.write_reply(client_id, data_out) {
                     Err(err) => {
                         //Stop worker
                         let _ = self.stop_worker(client_id);
                         Err(err)
                     },
-                    Ok(..) => Ok(OctopipesCapMessage::Subscription),
+                    Ok(..) => {
+                        //Tell the other clients
+                        let client_groups: Vec<String> = self.get_client_groups(client_id);
+                        self.emit(OctopipesServerEvent::ClientSubscribed {
+                            client: client_id.clone(),
+                            groups: client_groups.clone(),
+                        });
+                        self.publish_presence(OctopipesPresenceKind::Joined, client_id, client_groups);
+                        //Deliver messages stored while the client was offline (a non durable subscription discards them)
+                        self.deliver_stored(client_id, durable);
+                        //Let the client know the current value of its groups
+                        self.deliver_retained(client_id, groups);
+                        Ok(OctopipesCapMessage::Subscription)
+                    }
                 }
             }
         }
@@ -498,40 +878,113 @@ impl OctopipesServer {
 
     /// ### manage_unsubscription
     ///
-    /// `manage_unsubscription` Handle an unsubscription request stopping the worker associated to this client
+    /// `manage_unsubscription` Handle an unsubscription request stopping the worker associated to this client and send the result back to the client
     fn manage_unsubscription(
         &mut self,
-        client_id: &String,
+        client_id: &str,
     ) -> Result<OctopipesCapMessage, OctopipesServerError> {
-        //Check if client is already subsribed
-        let mut client_exists: bool = false;
-        for worker in &self.workers {
-            if *client_id == worker.client_id {
-                client_exists = true;
-                break;
-            }
-        }
-        //If client doesn't exist return error, don't send anything back to client though
-        if !client_exists {
+        //If client doesn't exist report it back to the client
+        if !self.worker_exists(client_id) {
+            let data_out: Vec<u8> = cap::encode_unsubscription_result(OctopipesCapError::NotSubscribed);
+            let _ = self.write_reply(client_id, data_out);
             return Err(OctopipesServerError::WorkerNotFound);
         }
-        //Stop worker
-        match self.stop_worker(client_id) {
+        //Stop worker (the name and the pipes are released even if the worker wasn't running anymore)
+        let result = self.remove_client(client_id, OctopipesPresenceKind::Left);
+        let data_out: Vec<u8> = cap::encode_unsubscription_result(OctopipesCapError::NoError);
+        self.write_reply(client_id, data_out)?;
+        match result {
             Ok(..) => Ok(OctopipesCapMessage::Unsubscription),
             Err(err) => Err(err),
         }
     }
 
+    /// ### manage_join
+    ///
+    /// `manage_join` Handle a join request adding the groups to the subscription of the worker associated to this client and send the result back to the client
+    fn manage_join(
+        &mut self,
+        client_id: &str,
+        groups: Vec<String>,
+    ) -> Result<OctopipesCapMessage, OctopipesServerError> {
+        if !self.worker_exists(client_id) {
+            let _ = self.write_reply(client_id, cap::encode_groups_result(OctopipesCapError::NotSubscribed));
+            return Err(OctopipesServerError::WorkerNotFound);
+        }
+        //Reply first: if the client doesn't get the result, it keeps its groups, so the subscription mustn't change
+        self.write_reply(client_id, cap::encode_groups_result(OctopipesCapError::NoError))?;
+        for group in groups.iter() {
+            self.routes.insert(group, client_id);
+        }
+        if let Some(worker) = self.workers.get_mut(client_id) {
+            worker.subscription.join(groups.clone());
+        }
+        self.deliver_retained(client_id, &groups);
+        Ok(OctopipesCapMessage::Join)
+    }
+
+    /// ### manage_leave
+    ///
+    /// `manage_leave` Handle a leave request removing the groups from the subscription of the worker associated to this client and send the result back to the client
+    fn manage_leave(
+        &mut self,
+        client_id: &str,
+        groups: &[String],
+    ) -> Result<OctopipesCapMessage, OctopipesServerError> {
+        if !self.worker_exists(client_id) {
+            let _ = self.write_reply(client_id, cap::encode_groups_result(OctopipesCapError::NotSubscribed));
+            return Err(OctopipesServerError::WorkerNotFound);
+        }
+        //Reply first: if the client doesn't get the result, it keeps its groups, so the subscription mustn't change
+        self.write_reply(client_id, cap::encode_groups_result(OctopipesCapError::NoError))?;
+        for group in groups.iter().filter(|group| *group != client_id) {
+            self.routes.remove(group, client_id);
+        }
+        if let Some(worker) = self.workers.get_mut(client_id) {
+            worker.subscription.leave(client_id, groups);
+        }
+        Ok(OctopipesCapMessage::Leave)
+    }
+
+    /// ### manage_query
+    ///
+    /// `manage_query` Handle a query request sending the result back to the client
+    fn manage_query(
+        &mut self,
+        client_id: &str,
+        query: CapQuery,
+        argument: &str,
+    ) -> Result<OctopipesCapMessage, OctopipesServerError> {
+        let results: Vec<String> = match query {
+            CapQuery::Clients => self.get_clients(),
+            CapQuery::GroupMembers => self
+                .match_subscription(argument)
+                .iter()
+                .map(|worker| worker.client_id.clone())
+                .collect(),
+            CapQuery::IsOnline => match self.worker_exists(argument) {
+                true => vec![argument.to_string()],
+                false => vec![],
+            },
+        };
+        let data_out: Vec<u8> = cap::encode_query_result(&results);
+        self.write_reply(client_id, data_out)?;
+        Ok(OctopipesCapMessage::Query)
+    }
+
     /// ### process_first
     ///
     /// `process_first` Find the first Worker which has an available message to process and dispatch it
+    /// The messages queued for the clients which have started reading (e.g. stored while they were offline) are sent first
     /// When OK is returned, the number of processed workers is returned
     /// If no worker has a message to process, the function will just return Ok
     /// If an error was returned during the process, the function will return Error((client_id, Error))
     pub fn process_first(&self) -> Result<usize, (String, OctopipesServerError)> {
+        //Send the messages queued for the clients which have started reading
+        self.iter_workers().for_each(|worker| self.flush_replay(worker));
         //Iterate over workers
         let mut workers_processed: usize = 0;
-        for worker in self.workers.iter() {
+        for worker in self.iter_workers() {
             //Get next message
             match worker.get_next_message() {
                 Ok(message_opt) => {
@@ -543,6 +996,7 @@ impl OctopipesServer {
                         Some(message) => {
                             //If a message is returned, dispatch the message to endpoints
                             if let Err((_, error)) = self.dispatch_message(&message) {
+                                self.reject(worker, error);
                                 return Err((worker.client_id.clone(), error));
                             }
                             //Eventually increment workers processed
@@ -552,7 +1006,13 @@ impl OctopipesServer {
                     }
                 }
                 Err(error) => {
-                    //If an error is returned, return error pairing it with the worker id
+                    //If an error is returned, notify the client and return error pairing it with the worker id
+                    if !self.reject(worker, error) {
+                        self.emit(OctopipesServerEvent::WorkerFailed {
+                            client: worker.client_id.clone(),
+                            error,
+                        });
+                    }
                     return Err((worker.client_id.clone(), error));
                 }
             }
@@ -563,13 +1023,16 @@ impl OctopipesServer {
     /// ### process_once
     ///
     /// `process_once` For each worker process the first message in its inbox. If the worker has no message it will be just ignored.
+    /// The messages queued for the clients which have started reading (e.g. stored while they were offline) are sent first
     /// When OK is returned, the number of processed workers is returned
     /// If no worker has a message to process, the function will just return Ok
     /// If an error was returned during the process, the function will return Error((client_id, Error))
     pub fn process_once(&self) -> Result<usize, (String, OctopipesServerError)> {
+        //Send the messages queued for the clients which have started reading
+        self.iter_workers().for_each(|worker| self.flush_replay(worker));
         //Iterate over workers
         let mut workers_processed: usize = 0;
-        for worker in self.workers.iter() {
+        for worker in self.iter_workers() {
             //Get next message
             match worker.get_next_message() {
                 Ok(message_opt) => {
@@ -581,6 +1044,7 @@ impl OctopipesServer {
                         Some(message) => {
                             //If a message is returned, dispatch the message to endpoints
                             if let Err((_, error)) = self.dispatch_message(&message) {
+                                self.reject(worker, error);
                                 return Err((worker.client_id.clone(), error));
                             }
                             //Eventually increment workers processed
@@ -589,7 +1053,13 @@ impl OctopipesServer {
                     }
                 }
                 Err(error) => {
-                    //If an error is returned, return error pairing it with the worker id
+                    //If an error is returned, notify the client and return error pairing it with the worker id
+                    if !self.reject(worker, error) {
+                        self.emit(OctopipesServerEvent::WorkerFailed {
+                            client: worker.client_id.clone(),
+                            error,
+                        });
+                    }
                     return Err((worker.client_id.clone(), error));
                 }
             }
@@ -626,70 +1096,245 @@ impl OctopipesServer {
     /// `is_subscribed` returns whether a client with a certain ID is subscribed or not
     pub fn is_subscribed(&self, client: String) -> Option<std::time::Instant> {
         //Check if a client is subscribed and if it is, return the subscription time
-        for worker in &self.workers {
-            if worker.client_id == client {
-                return Some(worker.subscription.subscription_time);
-            }
-        }
-        None
+        self.workers
+            .get(&client)
+            .map(|worker| worker.subscription.subscription_time)
     }
 
     /// ### get_subscriptions
     ///
     /// `get_subscriptions` Get all the subscriptions for a certain client
     pub fn get_subscriptions(&self, client: String) -> Option<Vec<String>> {
-        for worker in &self.workers {
-            if worker.client_id == client {
-                //Return groups
-                return Some(worker.subscription.groups.clone());
-            }
-        }
-        None
+        self.workers
+            .get(&client)
+            .map(|worker| worker.subscription.groups.clone())
     }
 
     /// ### get_clients
     ///
     /// `get_clients` Get all the clients id subscribed to the server
     pub fn get_clients(&self) -> Vec<String> {
-        let mut clients: Vec<String> = Vec::with_capacity(self.workers.len());
-        for worker in &self.workers {
-            clients.push(worker.client_id.clone());
-        }
-        clients
+        self.clients.clone()
+    }
+
+    //@! Setters
+
+    /// ### set_client_timeout
+    ///
+    /// `set_client_timeout` sets (or disables if None) the time after which a client which hasn't sent anything on the CAP
+    /// (e.g. a heartbeat) nor on its TX pipe is considered dead and its worker is stopped
+    pub fn set_client_timeout(&mut self, timeout: Option<Duration>) {
+        self.client_timeout = timeout;
+    }
+
+    /// ### set_drain_deadline
+    ///
+    /// `set_drain_deadline` sets the maximum time `stop_server` spends dispatching pending messages before stopping workers
+    pub fn set_drain_deadline(&mut self, deadline: Duration) {
+        self.drain_deadline = deadline;
+    }
+
+    /// ### set_durable_limits
+    ///
+    /// `set_durable_limits` sets the limits of the messages stored for each offline durable client
+    pub fn set_durable_limits(&mut self, limits: OctopipesDurableLimits) {
+        self.durable_limits = limits;
+    }
+
+    /// ### set_dead_letter_group
+    ///
+    /// `set_dead_letter_group` sets the group where undeliverable messages are published as dead letters (None to disable)
+    pub fn set_dead_letter_group(&mut self, group: Option<String>) {
+        self.dead_letter_group = group;
+    }
+
+    /// ### set_on_dead_letter
+    ///
+    /// `set_on_dead_letter` sets the callback called with each message which couldn't be delivered
+    pub fn set_on_dead_letter(&mut self, callback: fn(&OctopipesDeadLetter)) {
+        self.on_dead_letter_fn = Some(callback);
+    }
+
+    /// ### set_on_event
+    ///
+    /// `set_on_event` sets the callback called with each server event as soon as it occurs
+    pub fn set_on_event(&mut self, callback: fn(&OctopipesServerEvent)) {
+        self.on_event_fn = Some(callback);
+    }
+
+    /// ### set_on_client_reaped
+    ///
+    /// `set_on_client_reaped` sets the function to call with the client id when a dead client is reaped
+    pub fn set_on_client_reaped(&mut self, callback: fn(&str)) {
+        self.on_client_reaped_fn = Some(callback);
     }
 
     //@! Privates
 
-    /// ### match_subscription
+    /// ### publish_presence
     ///
-    /// `match_subscription` Returns the Workers associated to a certain subscription
-    fn match_subscription(&self, subscription: &String) -> Vec<&OctopipesServerWorker> {
-        let mut subject_workers: Vec<&OctopipesServerWorker> = Vec::new();
-        for worker in &self.workers {
-            if worker.is_subscribed(subscription) {
-                subject_workers.push(worker);
+    /// `publish_presence` publishes a presence event to the presence group
+    fn publish_presence(&self, kind: OctopipesPresenceKind, client_id: &str, groups: Vec<String>) {
+        let event: OctopipesPresenceEvent = OctopipesPresenceEvent::new(kind, client_id, groups);
+        let message: OctopipesMessage = OctopipesMessage::new(
+            &self.version,
+            &None,
+            &Some(String::from(PRESENCE_GROUP)),
+            5,
+            OctopipesOptions::empty(),
+            event.encode(),
+        );
+        let _ = self.dispatch_message(&message);
+    }
+
+    /// ### remove_client
+    ///
+    /// `remove_client` stops the worker of a client and tells the presence group why the client is gone
+    fn remove_client(&mut self, client_id: &str, kind: OctopipesPresenceKind) -> Result<(), OctopipesServerError> {
+        let groups: Vec<String> = self.get_client_groups(client_id);
+        let result = self.stop_worker(&client_id.to_string());
+        self.publish_presence(kind, client_id, groups);
+        result
+    }
+
+    /// ### deliver_stored
+    ///
+    /// `deliver_stored` marks the subscription of the client as durable (or not) and queues on its worker the messages stored while it was offline.
+    /// They're sent by `flush_replay` once the client reads its RX pipe
+    fn deliver_stored(&mut self, client_id: &str, durable: bool) {
+        let session: Option<DurableSession> = self
+            .durable
+            .borrow_mut()
+            .remove(client_id)
+            .filter(|session| !session.is_expired(&self.durable_limits));
+        match self.workers.get_mut(client_id) {
+            Some(worker) => worker.subscription.durable = durable,
+            None => return,
+        }
+        let session: DurableSession = match (durable, session) {
+            (true, Some(session)) => session,
+            _ => return,
+        };
+        *self.workers[client_id].stored.borrow_mut() = session.take(&self.durable_limits);
+    }
+
+    /// ### flush_replay
+    ///
+    /// `flush_replay` sends to a client the messages queued on its worker while handling its subscription or a join, if it's reading its RX pipe.
+    /// The messages the client doesn't read are kept for the next call; stored messages older than the max age are reported as dead letters
+    fn flush_replay(&self, worker: &OctopipesServerWorker) {
+        let mut expired: Vec<StoredMessage> = Vec::new();
+        {
+            let mut stored = worker.stored.borrow_mut();
+            while stored.front().map(|message| message.stored_at.elapsed() > self.durable_limits.max_age) == Some(true) {
+                expired.extend(stored.pop_front());
+            }
+        }
+        for message in expired {
+            if let Ok(message) = serializer::decode_message(message.data) {
+                self.dead_letter(&message, OctopipesDeadLetterReason::Expired, Some(&worker.client_id));
+            }
+        }
+        if (worker.stored.borrow().is_empty() && worker.retained.borrow().is_empty()) || !pipes::pipe_has_reader(&worker.pipe_write) {
+            return;
+        }
+        //Stored messages come before the retained ones
+        for queue in [&worker.stored, &worker.retained] {
+            let mut queue = queue.borrow_mut();
+            while let Some(message) = queue.front() {
+                if worker.replay_encoded(&message.data, message.ttl).is_err() {
+                    return;
+                }
+                queue.pop_front();
             }
         }
-        subject_workers
     }
 
-    /// ###  worker_exists
+    /// ### deliver_retained
     ///
-    /// `worker_exists` Checks whether a Worker with that name already exists
-    fn worker_exists(&self, worker_name: &String) -> bool {
-        for worker in &self.workers {
-            if worker.client_id == *worker_name {
-                return true;
+    /// `deliver_retained` queues on the worker of a client the retained messages of the groups matching the provided subscriptions.
+    /// They're sent by `flush_replay` once the client reads its RX pipe
+    fn deliver_retained(&self, client_id: &str, subscriptions: &[String]) {
+        let worker: &OctopipesServerWorker = match self.workers.get(client_id) {
+            Some(worker) => worker,
+            None => return,
+        };
+        for (group, message) in self.retained.borrow().iter() {
+            if !subscriptions.iter().any(|subscription| topic::subscription_matches(subscription, group)) {
+                continue;
+            }
+            //Respect NOECHO
+            if message.options.intersects(OctopipesOptions::NOECHO) && message.origin.as_deref() == Some(client_id) {
+                continue;
             }
+            if let Ok(data_out) = serializer::encode_message(message) {
+                worker.retained.borrow_mut().push_back(StoredMessage {
+                    stored_at: Instant::now(),
+                    ttl: message.ttl,
+                    data: data_out,
+                });
+            }
+        }
+    }
+
+    /// ### get_client_groups
+    ///
+    /// `get_client_groups` returns the groups a client subscribed to, without its own id
+    fn get_client_groups(&self, client_id: &str) -> Vec<String> {
+        match self.workers.get(client_id) {
+            Some(worker) => worker
+                .subscription
+                .groups
+                .iter()
+                .filter(|group| *group != client_id)
+                .cloned()
+                .collect(),
+            None => vec![],
         }
-        false
+    }
+
+    /// ### match_subscription
+    ///
+    /// `match_subscription` Returns the Workers associated to a certain subscription
+    fn match_subscription(&self, subscription: &str) -> Vec<&OctopipesServerWorker> {
+        self.routes
+            .matches(subscription)
+            .iter()
+            .filter_map(|client| self.workers.get(client))
+            .collect()
+    }
+
+    /// ### select_recipients
+    ///
+    /// `select_recipients` Returns the Workers which must receive a message sent to a certain group.
+    /// Queue groups contribute only one of their members (round robin); the excluded client is never selected
+    fn select_recipients(&self, group: &str, exclude: Option<&str>) -> Vec<&OctopipesServerWorker> {
+        self.routes
+            .recipients(group, exclude)
+            .iter()
+            .filter_map(|client| self.workers.get(client))
+            .collect()
+    }
+
+    /// ###  worker_exists
+    ///
+    /// `worker_exists` Checks whether a Worker with that name already exists
+    fn worker_exists(&self, worker_name: &str) -> bool {
+        self.workers.contains_key(worker_name)
+    }
+
+    /// ###  iter_workers
+    ///
+    /// `iter_workers` iterates over the workers in subscription order
+    fn iter_workers(&self) -> impl Iterator<Item = &OctopipesServerWorker> {
+        self.clients.iter().filter_map(move |client| self.workers.get(client))
     }
 }
 
 impl Drop for OctopipesServer {
     fn drop(&mut self) {
         //Stop workers
-        for worker in self.workers.iter_mut() {
+        for worker in self.workers.values_mut() {
             let _ = worker.stop_worker();
         }
         //Stop thread
@@ -719,13 +1364,16 @@ impl OctopipesServerWorker {
         }
         //Prepare thread stuff
         let pipe_read: String = cli_pipe_tx.clone();
+        let thread_client_id: String = client_id.clone();
         let worker_active: Arc<Mutex<bool>> = Arc::new(Mutex::new(true)); //True
         let thread_active: Arc<Mutex<bool>> = Arc::clone(&worker_active); //Clone active for thread
+        let last_seen: Arc<Mutex<Instant>> = Arc::clone(&subscriptions_obj.last_seen);
                                                                           //Create channel
         let (worker_sender, worker_receiver) = mpsc::channel();
         //Start thread
         let join_handle = thread::spawn(move || {
             let mut terminate_thread: bool = false;
+            let mut pending: Vec<u8> = Vec::new(); //Incomplete packet waiting for the next read
             while !terminate_thread {
                 //Check if thread has to be stopped
                 {
@@ -738,18 +1386,28 @@ impl OctopipesServerWorker {
                 match pipes::pipe_read(&pipe_read, 500) {
                     Ok(data) => {
                         if let Some(data) = data {
-                            //Try to decode data
-                            match serializer::decode_message(data) {
-                                Ok(message) => {
-                                    //Send message
-                                    if let Err(..) = worker_sender.send(Ok(message)) {
-                                        terminate_thread = true; //Terminate threda if it wasn't possible to send message to the main thread
+                            //Any frame from the client proves it is alive
+                            *last_seen.lock().unwrap() = Instant::now();
+                            //Try to decode data (the client could have written more than one message, or only part of one)
+                            pending.extend(data);
+                            for result in serializer::decode_messages(&mut pending) {
+                                match result {
+                                    Ok(message) => {
+                                        //Clients can only send messages as themselves (no origin is reserved to the server)
+                                        let message = match message.origin.as_deref() == Some(thread_client_id.as_str()) {
+                                            true => Ok(message),
+                                            false => Err(OctopipesServerError::BadOrigin),
+                                        };
+                                        //Send message
+                                        if worker_sender.send(message).is_err() {
+                                            terminate_thread = true; //Terminate threda if it wasn't possible to send message to the main thread
+                                        }
                                     }
-                                }
-                                Err(err) => {
-                                    //Send error
-                                    if let Err(..) = worker_sender.send(Err(err.to_server_error())) {
-                                        terminate_thread = true; //Terminate thread if it wasn't possible to send message to the main thread
+                                    Err(err) => {
+                                        //Send error
+                                        if worker_sender.send(Err(err.to_server_error())).is_err() {
+                                            terminate_thread = true; //Terminate thread if it wasn't possible to send message to the main thread
+                                        }
                                     }
                                 }
                             }
@@ -775,6 +1433,8 @@ impl OctopipesServerWorker {
             worker_loop: Some(join_handle),
             worker_active: worker_active,
             receiver: worker_receiver,
+            stored: RefCell::new(VecDeque::new()),
+            retained: RefCell::new(VecDeque::new()),
         })
     }
 
@@ -805,17 +1465,46 @@ impl OctopipesServerWorker {
         //Encode message
         match serializer::encode_message(message) {
             Err(err) => Err(err.to_server_error()),
-            Ok(data_out) => {
-                //Write data
-                let timeout: u128 = message.ttl as u128 * 1000;
-                match pipes::pipe_write(&self.pipe_write, timeout, data_out) {
-                    Ok(..) => Ok(()),
-                    Err(..) => Err(OctopipesServerError::WriteFailed),
-                }
-            }
+            Ok(data_out) => self.send_encoded(&data_out, message.ttl),
+        }
+    }
+
+    /// ### send_encoded
+    ///
+    /// `send_encoded` sends an already encoded message to the client; the ttl is the time to wait for the client in seconds
+    fn send_encoded(&self, data_out: &[u8], ttl: u8) -> Result<(), OctopipesServerError> {
+        match self.write_encoded(data_out, ttl) {
+            Ok(..) => Ok(()),
+            Err(..) => Err(OctopipesServerError::WriteFailed),
+        }
+    }
+
+    /// ### write_encoded
+    ///
+    /// `write_encoded` writes an already encoded message to the client; when it fails, returns why the message couldn't be delivered
+    fn write_encoded(&self, data_out: &[u8], ttl: u8) -> Result<(), OctopipesDeadLetterReason> {
+        let timeout: u128 = ttl as u128 * 1000;
+        match pipes::pipe_write(&self.pipe_write, timeout, data_out) {
+            Ok(..) => Ok(()),
+            Err(err) => match err.kind() {
+                std::io::ErrorKind::WriteZero => Err(OctopipesDeadLetterReason::Expired), //Client didn't read within ttl
+                _ => Err(OctopipesDeadLetterReason::WriteFailed),
+            },
         }
     }
 
+    /// ### replay_encoded
+    ///
+    /// `replay_encoded` writes a message queued while handling a CAP request (retained and stored messages);
+    /// the server doesn't wait more than REPLAY_TTL for the client, whatever the ttl of the message (even 0)
+    fn replay_encoded(&self, data_out: &[u8], ttl: u8) -> Result<(), OctopipesDeadLetterReason> {
+        let ttl: u8 = match ttl {
+            0 => REPLAY_TTL,
+            ttl => ttl.min(REPLAY_TTL),
+        };
+        self.write_encoded(data_out, ttl)
+    }
+
     /// ### get_next_message
     ///
     /// `get_next_message` Get the next available message
@@ -836,13 +1525,6 @@ impl OctopipesServerWorker {
             }
         }
     }
-
-    /// ### is_subscribed
-    ///
-    /// `is_subscribed` returns wether groups contains the provided string
-    fn is_subscribed(&self, subscription: &String) -> bool {
-        self.subscription.is_subscribed(subscription)
-    }
 }
 
 impl Drop for OctopipesServerWorker {
@@ -861,13 +1543,113 @@ impl Subscription {
         Subscription {
             groups: subscriptions,
             subscription_time: std::time::Instant::now(),
+            last_seen: Arc::new(Mutex::new(std::time::Instant::now())),
+            durable: false,
+        }
+    }
+
+    /// ###  join
+    ///
+    /// `join` adds groups to the subscription, skipping the ones already subscribed
+    fn join(&mut self, groups: Vec<String>) {
+        for group in groups {
+            if !self.groups.contains(&group) {
+                self.groups.push(group);
+            }
         }
     }
 
-    /// ###  is_subscribed
+    /// ###  leave
     ///
-    /// `is_subscribed` returns wether groups contains the provided string
-    fn is_subscribed(&self, to_find: &String) -> bool {
-        self.groups.contains(to_find)
+    /// `leave` removes groups from the subscription; the client id can't be left
+    fn leave(&mut self, client_id: &str, groups: &[String]) {
+        self.groups.retain(|group| group == client_id || !groups.contains(group));
+    }
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poll_events_dead_worker() {
+        let mut server: OctopipesServer = OctopipesServer::new(
+            OctopipesProtocolVersion::Version1,
+            String::from("/tmp/cap_dead_worker.fifo"),
+            String::from("/tmp/clients_dead_worker/"),
+        );
+        assert!(server.start_cap_listener().is_ok());
+        let client: String = String::from("dead_worker");
+        assert!(server
+            .start_worker(
+                client.clone(),
+                vec![client.clone()],
+                String::from("/tmp/dead_worker_tx.fifo"),
+                String::from("/tmp/dead_worker_rx.fifo"),
+            )
+            .is_ok());
+        //A client watching the presence group
+        assert!(server
+            .start_worker(
+                String::from("dead_worker_watcher"),
+                vec![String::from("dead_worker_watcher"), String::from(PRESENCE_GROUP)],
+                String::from("/tmp/dead_worker_watcher_tx.fifo"),
+                String::from("/tmp/dead_worker_watcher_rx.fifo"),
+            )
+            .is_ok());
+        let watcher: thread::JoinHandle<Option<Vec<u8>>> =
+            thread::spawn(|| pipes::pipe_read("/tmp/dead_worker_watcher_rx.fifo", 5000).unwrap());
+        //Terminate the worker thread, as if it had panicked
+        *server.workers.get(&client).unwrap().worker_active.lock().unwrap() = false;
+        //Polling must return, report the failure once (as soon as the thread has terminated) and remove the worker
+        let mut failures: usize = 0;
+        let deadline: Instant = Instant::now() + Duration::from_secs(5);
+        while failures == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+            failures = server
+                .poll_events()
+                .unwrap()
+                .iter()
+                .filter(|event| matches!(event, OctopipesServerEvent::WorkerFailed { error: OctopipesServerError::WorkerNotRunning, .. }))
+                .count();
+        }
+        assert_eq!(failures, 1, "Worker failure should have been reported once");
+        assert_eq!(server.get_clients(), vec![String::from("dead_worker_watcher")], "Dead worker should have been removed");
+        //The watcher is told the client has left
+        let data: Vec<u8> = watcher.join().unwrap().expect("Watcher didn't receive the presence event");
+        let event: OctopipesPresenceEvent = OctopipesPresenceEvent::decode(&serializer::decode_message(data).unwrap().data).unwrap();
+        assert_eq!(event.kind, OctopipesPresenceKind::Left);
+        assert_eq!(event.client_id, client);
+        assert!(server.poll_events().unwrap().is_empty());
+        assert!(server.stop_server().is_ok());
+    }
+
+    #[test]
+    fn test_stop_server_failing_worker() {
+        let mut server: OctopipesServer = OctopipesServer::new(
+            OctopipesProtocolVersion::Version1,
+            String::from("/tmp/cap_failing_worker.fifo"),
+            String::from("/tmp/clients_failing_worker/"),
+        );
+        server.set_drain_deadline(Duration::from_secs(10));
+        assert!(server.start_cap_listener().is_ok());
+        let client: String = String::from("failing_worker");
+        assert!(server
+            .start_worker(
+                client.clone(),
+                vec![client.clone()],
+                String::from("/tmp/failing_worker_tx.fifo"),
+                String::from("/tmp/failing_worker_rx.fifo"),
+            )
+            .is_ok());
+        //The worker can't read its pipe anymore and keeps reporting ReadFailed
+        assert!(pipes::pipe_delete(&String::from("/tmp/failing_worker_tx.fifo")).is_ok());
+        thread::sleep(Duration::from_millis(1500));
+        //Shutdown mustn't wait for the drain deadline
+        let t_start: Instant = Instant::now();
+        assert!(server.stop_server().is_ok());
+        assert!(t_start.elapsed() < Duration::from_secs(5), "Shutdown took {:?}", t_start.elapsed());
     }
 }
@@ -0,0 +1,469 @@
+//! ## Topic
+//!
+//! `topic` is the module which takes care of matching groups against subscriptions and of indexing the subscribers for routing
+
+//
+//   RustyPipes
+//   Developed by Christian Visintin
+//
+// MIT License
+// Copyright (c) 2019-2020 Christian Visintin
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+//
+
+use super::QueueGroup;
+use super::RoutingIndex;
+use super::TopicTrie;
+use super::QUEUE_GROUP_PREFIX;
+use super::SYS_GROUP;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+
+/// ### topic_matches
+///
+/// `topic_matches` returns whether a group (topic) matches a subscription (filter).
+/// Levels are separated by '/'; in the filter '+' matches exactly one level, while '#' matches any number of remaining levels (even none).
+/// Wildcards at the first level don't match reserved groups (the ones starting with '$')
+pub(crate) fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    let mut first_level: bool = true;
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some("#"), topic_level) => {
+                return !(first_level && topic_level.map(is_reserved).unwrap_or(false));
+            }
+            (Some("+"), Some(topic_level)) => {
+                if first_level && is_reserved(topic_level) {
+                    return false;
+                }
+            }
+            (Some(filter_level), Some(topic_level)) => {
+                if filter_level != topic_level {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+        first_level = false;
+    }
+}
+
+/// ### parse_queue_group
+///
+/// `parse_queue_group` splits a queue group subscription (`$share/<queue>/<group>`) into queue name and group.
+/// Returns None if the subscription is not a queue group subscription
+pub(crate) fn parse_queue_group(subscription: &str) -> Option<(&str, &str)> {
+    let mut parts = subscription.strip_prefix(QUEUE_GROUP_PREFIX)?.splitn(2, '/');
+    match (parts.next(), parts.next()) {
+        (Some(queue), Some(group)) if !queue.is_empty() && !group.is_empty() => Some((queue, group)),
+        _ => None,
+    }
+}
+
+/// ### subscription_matches
+///
+/// `subscription_matches` returns whether a group matches a subscription, which can be a queue group subscription
+pub(crate) fn subscription_matches(subscription: &str, topic: &str) -> bool {
+    match parse_queue_group(subscription) {
+        Some((_, filter)) => topic_matches(filter, topic),
+        None => topic_matches(subscription, topic),
+    }
+}
+
+/// ### has_wildcards
+///
+/// `has_wildcards` returns whether a subscription contains wildcard levels
+fn has_wildcards(filter: &str) -> bool {
+    filter.split('/').any(|level| level == "+" || level == "#")
+}
+
+/// ### is_valid_client_id
+///
+/// `is_valid_client_id` returns whether a client id can be used as the exact group where the client is reached:
+/// it mustn't contain wildcards or levels and it mustn't be reserved to the server
+pub(crate) fn is_valid_client_id(client_id: &str) -> bool {
+    !client_id.is_empty() && !is_reserved(client_id) && !client_id.contains(['+', '#', '/'])
+}
+
+/// ### is_sys_group
+///
+/// `is_sys_group` returns whether a group is under $SYS, where only the server can publish
+pub(crate) fn is_sys_group(group: &str) -> bool {
+    group.split('/').next() == Some(SYS_GROUP)
+}
+
+/// ### is_reserved
+///
+/// `is_reserved` returns whether a level is reserved to the server
+fn is_reserved(level: &str) -> bool {
+    level.starts_with('$')
+}
+
+impl TopicTrie {
+    /// ### new
+    ///
+    /// `new` instances a new empty TopicTrie
+    pub(crate) fn new() -> TopicTrie {
+        TopicTrie {
+            children: HashMap::new(),
+            clients: Vec::new(),
+        }
+    }
+
+    /// ### insert
+    ///
+    /// `insert` subscribes a client to a filter
+    pub(crate) fn insert(&mut self, filter: &str, client_id: &str) {
+        let mut node: &mut TopicTrie = self;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_insert_with(TopicTrie::new);
+        }
+        if !node.clients.iter().any(|client| client == client_id) {
+            node.clients.push(client_id.to_string());
+        }
+    }
+
+    /// ### remove
+    ///
+    /// `remove` unsubscribes a client from a filter; branches left without subscribers are removed
+    pub(crate) fn remove(&mut self, filter: &str, client_id: &str) {
+        let levels: Vec<&str> = filter.split('/').collect();
+        self.remove_levels(&levels, client_id);
+    }
+
+    /// ### matches
+    ///
+    /// `matches` returns the clients subscribed to a filter matching the provided group.
+    /// Each client is returned once, even if it matches through more filters
+    pub(crate) fn matches(&self, topic: &str) -> Vec<String> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        let mut clients: Vec<String> = Vec::new();
+        self.collect(&levels, true, &mut clients);
+        clients
+    }
+
+    /// ### remove_levels
+    ///
+    /// `remove_levels` removes the client from the node at the end of levels. Returns whether this node can be pruned
+    fn remove_levels(&mut self, levels: &[&str], client_id: &str) -> bool {
+        match levels.split_first() {
+            None => self.clients.retain(|client| client != client_id),
+            Some((level, others)) => {
+                let prune: bool = match self.children.get_mut(*level) {
+                    Some(child) => child.remove_levels(others, client_id),
+                    None => false,
+                };
+                if prune {
+                    self.children.remove(*level);
+                }
+            }
+        }
+        self.clients.is_empty() && self.children.is_empty()
+    }
+
+    /// ### collect
+    ///
+    /// `collect` pushes into clients the subscribers of the filters matching the remaining levels
+    fn collect(&self, levels: &[&str], first_level: bool, clients: &mut Vec<String>) {
+        let reserved: bool = first_level && levels.first().map(|level| is_reserved(level)).unwrap_or(false);
+        //Multi-level wildcard matches whatever remains
+        if !reserved {
+            if let Some(child) = self.children.get("#") {
+                push_clients(clients, &child.clients);
+            }
+        }
+        match levels.split_first() {
+            None => push_clients(clients, &self.clients),
+            Some((level, others)) => {
+                if let Some(child) = self.children.get(*level) {
+                    child.collect(others, false, clients);
+                }
+                if !reserved && *level != "+" {
+                    if let Some(child) = self.children.get("+") {
+                        child.collect(others, false, clients);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl RoutingIndex {
+    /// ### new
+    ///
+    /// `new` instances a new empty RoutingIndex
+    pub(crate) fn new() -> RoutingIndex {
+        RoutingIndex {
+            groups: HashMap::new(),
+            wildcards: TopicTrie::new(),
+            queues: HashMap::new(),
+        }
+    }
+
+    /// ### insert
+    ///
+    /// `insert` subscribes a client to a group (which may contain wildcards or be a queue group subscription)
+    pub(crate) fn insert(&mut self, group: &str, client_id: &str) {
+        if let Some((_, filter)) = parse_queue_group(group) {
+            let queue: &mut QueueGroup = self.queues.entry(group.to_string()).or_insert_with(|| QueueGroup {
+                filter: filter.to_string(),
+                members: Vec::new(),
+                next: Cell::new(0),
+            });
+            if !queue.members.iter().any(|member| member == client_id) {
+                queue.members.push(client_id.to_string());
+            }
+        } else if has_wildcards(group) {
+            self.wildcards.insert(group, client_id);
+        } else {
+            let clients: &mut Vec<String> = self.groups.entry(group.to_string()).or_default();
+            if !clients.iter().any(|client| client == client_id) {
+                clients.push(client_id.to_string());
+            }
+        }
+    }
+
+    /// ### remove
+    ///
+    /// `remove` unsubscribes a client from a group
+    pub(crate) fn remove(&mut self, group: &str, client_id: &str) {
+        if parse_queue_group(group).is_some() {
+            if let Some(queue) = self.queues.get_mut(group) {
+                queue.members.retain(|member| member != client_id);
+                if queue.members.is_empty() {
+                    self.queues.remove(group);
+                }
+            }
+        } else if has_wildcards(group) {
+            self.wildcards.remove(group, client_id);
+        } else if let Some(clients) = self.groups.get_mut(group) {
+            clients.retain(|client| client != client_id);
+            if clients.is_empty() {
+                self.groups.remove(group);
+            }
+        }
+    }
+
+    /// ### matches
+    ///
+    /// `matches` returns all the clients subscribed to the provided group, including all the members of the matching queue groups.
+    /// Each client is returned once
+    pub(crate) fn matches(&self, group: &str) -> Vec<String> {
+        let mut clients: Vec<String> = self.plain_matches(group);
+        for queue in self.queues.values().filter(|queue| topic_matches(&queue.filter, group)) {
+            push_clients(&mut clients, &queue.members);
+        }
+        clients
+    }
+
+    /// ### recipients
+    ///
+    /// `recipients` returns the clients which must receive a message sent to the provided group:
+    /// all the plain subscribers and one member, chosen with round robin, for each matching queue group.
+    /// The excluded client (e.g. the origin of a NOECHO message) is neither returned nor chosen as queue member
+    pub(crate) fn recipients(&self, group: &str, exclude: Option<&str>) -> Vec<String> {
+        let mut clients: Vec<String> = self.plain_matches(group);
+        clients.retain(|client| Some(client.as_str()) != exclude);
+        for queue in self.queues.values().filter(|queue| topic_matches(&queue.filter, group)) {
+            let members: Vec<String> = queue
+                .members
+                .iter()
+                .filter(|member| Some(member.as_str()) != exclude)
+                .cloned()
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let index: usize = queue.next.get() % members.len();
+            queue.next.set(index + 1);
+            push_clients(&mut clients, &members[index..index + 1]);
+        }
+        clients
+    }
+
+    /// ### plain_matches
+    ///
+    /// `plain_matches` returns the clients subscribed to the provided group, excluding queue groups
+    fn plain_matches(&self, group: &str) -> Vec<String> {
+        let mut clients: Vec<String> = match self.groups.get(group) {
+            Some(clients) => clients.clone(),
+            None => Vec::new(),
+        };
+        push_clients(&mut clients, &self.wildcards.matches(group));
+        clients
+    }
+}
+
+/// ### push_clients
+///
+/// `push_clients` extends clients with the provided ones, skipping duplicates
+fn push_clients(clients: &mut Vec<String>, to_push: &[String]) {
+    for client in to_push.iter() {
+        if !clients.contains(client) {
+            clients.push(client.clone());
+        }
+    }
+}
+
+//@! Tests
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_client_id() {
+        assert!(is_valid_client_id("sensor_01"));
+        assert!(is_valid_client_id("client.a"));
+        assert!(!is_valid_client_id(""));
+        assert!(!is_valid_client_id("#"));
+        assert!(!is_valid_client_id("+"));
+        assert!(!is_valid_client_id("a+b"));
+        assert!(!is_valid_client_id("sensors/01"));
+        assert!(!is_valid_client_id("$share/q/jobs"));
+        assert!(!is_valid_client_id("$SYS"));
+    }
+
+    #[test]
+    fn test_sys_group() {
+        assert!(is_sys_group("$SYS"));
+        assert!(is_sys_group("$SYS/presence"));
+        assert!(!is_sys_group("$SYSTEM/presence"));
+        assert!(!is_sys_group("SYS/presence"));
+        assert!(!is_sys_group("sensors/$SYS"));
+    }
+
+    #[test]
+    fn test_topic_matches() {
+        assert!(topic_matches("sensors/kitchen/temp", "sensors/kitchen/temp"));
+        assert!(!topic_matches("sensors/kitchen/temp", "sensors/kitchen"));
+        assert!(!topic_matches("sensors/kitchen", "sensors/kitchen/temp"));
+        //Single level
+        assert!(topic_matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!topic_matches("sensors/+/temp", "sensors/kitchen/humidity"));
+        assert!(!topic_matches("sensors/+", "sensors/kitchen/temp"));
+        //Multi level
+        assert!(topic_matches("sensors/#", "sensors/kitchen/temp"));
+        assert!(topic_matches("sensors/#", "sensors"));
+        assert!(topic_matches("#", "BROADCAST"));
+        assert!(!topic_matches("sensors/#", "alarms/kitchen"));
+        //Reserved groups
+        assert!(!topic_matches("#", "$SYS/presence"));
+        assert!(!topic_matches("+/presence", "$SYS/presence"));
+        assert!(topic_matches("$SYS/#", "$SYS/presence"));
+    }
+
+    #[test]
+    fn test_topic_trie() {
+        let mut trie: TopicTrie = TopicTrie::new();
+        trie.insert("sensors/kitchen/temp", "exact");
+        trie.insert("sensors/+/temp", "single");
+        trie.insert("sensors/#", "multi");
+        trie.insert("sensors/#", "multi"); //Duplicated
+        trie.insert("#", "all");
+        trie.insert("$SYS/presence", "presence");
+        assert_eq!(trie.matches("sensors/kitchen/temp"), vec!["all", "multi", "exact", "single"]);
+        assert_eq!(trie.matches("sensors/bedroom/temp"), vec!["all", "multi", "single"]);
+        assert_eq!(trie.matches("sensors"), vec!["all", "multi"]);
+        assert_eq!(trie.matches("alarms"), vec!["all"]);
+        assert_eq!(trie.matches("$SYS/presence"), vec!["presence"]);
+        //A client matching through more filters is returned once
+        trie.insert("sensors/kitchen/temp", "multi");
+        assert_eq!(trie.matches("sensors/kitchen/temp"), vec!["all", "multi", "exact", "single"]);
+        //Remove
+        trie.remove("sensors/#", "multi");
+        trie.remove("sensors/kitchen/temp", "multi");
+        trie.remove("#", "all");
+        trie.remove("alarms", "all"); //Not subscribed
+        assert_eq!(trie.matches("sensors/kitchen/temp"), vec!["exact", "single"]);
+        trie.remove("sensors/kitchen/temp", "exact");
+        trie.remove("sensors/+/temp", "single");
+        assert!(trie.matches("sensors/kitchen/temp").is_empty());
+        //Empty branches are pruned
+        assert_eq!(trie.children.len(), 1);
+        assert!(trie.children.contains_key("$SYS"));
+    }
+
+    #[test]
+    fn test_routing_index() {
+        let mut index: RoutingIndex = RoutingIndex::new();
+        index.insert("sensors/kitchen/temp", "exact");
+        index.insert("sensors/kitchen/temp", "exact"); //Duplicated
+        index.insert("sensors/+/temp", "single");
+        index.insert("sensors/#", "exact");
+        assert_eq!(index.groups.len(), 1, "Only exact groups should be in the groups map");
+        assert_eq!(index.matches("sensors/kitchen/temp"), vec!["exact", "single"]);
+        assert_eq!(index.matches("sensors/bedroom/temp"), vec!["exact", "single"]);
+        assert!(index.matches("alarms").is_empty());
+        //Remove
+        index.remove("sensors/kitchen/temp", "exact");
+        assert!(index.groups.is_empty(), "Groups without subscribers should be removed");
+        assert_eq!(index.matches("sensors/kitchen/temp"), vec!["exact", "single"]);
+        index.remove("sensors/#", "exact");
+        index.remove("sensors/+/temp", "single");
+        assert!(index.matches("sensors/kitchen/temp").is_empty());
+    }
+
+    #[test]
+    fn test_queue_groups() {
+        assert_eq!(parse_queue_group("$share/workers/jobs/new"), Some(("workers", "jobs/new")));
+        assert_eq!(parse_queue_group("$share/workers"), None);
+        assert_eq!(parse_queue_group("$share//jobs"), None);
+        assert_eq!(parse_queue_group("jobs"), None);
+        assert!(subscription_matches("$share/workers/jobs/+", "jobs/new"));
+        assert!(!subscription_matches("$share/workers/jobs", "workers/jobs"));
+        let mut index: RoutingIndex = RoutingIndex::new();
+        index.insert("$share/workers/jobs", "worker_a");
+        index.insert("$share/workers/jobs", "worker_b");
+        index.insert("$share/workers/jobs", "worker_b"); //Duplicated
+        index.insert("jobs", "monitor");
+        assert!(index.groups.get("$share/workers/jobs").is_none(), "Queue groups shouldn't be plain groups");
+        //Every member is subscribed
+        assert_eq!(index.matches("jobs"), vec!["monitor", "worker_a", "worker_b"]);
+        //But only one receives each message
+        assert_eq!(index.recipients("jobs", None), vec!["monitor", "worker_a"]);
+        assert_eq!(index.recipients("jobs", None), vec!["monitor", "worker_b"]);
+        assert_eq!(index.recipients("jobs", None), vec!["monitor", "worker_a"]);
+        assert_eq!(index.recipients("alarms", None), Vec::<String>::new());
+        //Remove members
+        index.remove("$share/workers/jobs", "worker_a");
+        assert_eq!(index.recipients("jobs", None), vec!["monitor", "worker_b"]);
+        assert_eq!(index.recipients("jobs", None), vec!["monitor", "worker_b"]);
+        index.remove("$share/workers/jobs", "worker_b");
+        assert!(index.queues.is_empty(), "Empty queue groups should be removed");
+        assert_eq!(index.recipients("jobs", None), vec!["monitor"]);
+    }
+
+    #[test]
+    fn test_recipients_exclude() {
+        let mut index: RoutingIndex = RoutingIndex::new();
+        index.insert("$share/workers/jobs", "worker_a");
+        index.insert("$share/workers/jobs", "worker_b");
+        index.insert("jobs", "monitor");
+        //The excluded client never gets the queue group turn
+        for _ in 0..4 {
+            assert_eq!(index.recipients("jobs", Some("worker_a")), vec!["monitor", "worker_b"]);
+        }
+        assert_eq!(index.recipients("jobs", Some("monitor")).len(), 1);
+        //A queue group whose only member is excluded is skipped
+        index.remove("$share/workers/jobs", "worker_b");
+        assert_eq!(index.recipients("jobs", Some("worker_a")), vec!["monitor"]);
+    }
+}
@@ -29,9 +29,283 @@ extern crate rustypipes;
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+    use std::path::Path;
+    use std::process::{Child, Command, Stdio};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
     use std::sync::{Arc, Mutex};
     use std::thread::{sleep, spawn, JoinHandle};
     use std::time::{Duration, Instant};
+
+    static PRESENCE_JOINED: AtomicBool = AtomicBool::new(false);
+    static PRESENCE_LEFT: AtomicBool = AtomicBool::new(false);
+    static GROUP_HANDLED: AtomicBool = AtomicBool::new(false);
+    static LATE_GROUP_HANDLED: AtomicBool = AtomicBool::new(false);
+    static CLIENT_KICKED: AtomicBool = AtomicBool::new(false);
+    static SHUTDOWN_NOTIFIED: AtomicBool = AtomicBool::new(false);
+    static DEAD_LETTERED: AtomicBool = AtomicBool::new(false);
+    static EVENT_NOTIFIED: AtomicBool = AtomicBool::new(false);
+    static CLIENT_REAPED: AtomicBool = AtomicBool::new(false);
+    static CONNECTION_LOST: AtomicBool = AtomicBool::new(false);
+    static RECONNECTED: AtomicBool = AtomicBool::new(false);
+    static QUEUED_MESSAGE_SENT: AtomicBool = AtomicBool::new(false);
+    static SENDERS_SENT: AtomicUsize = AtomicUsize::new(0);
+    static UNSUBSCRIBE_CONNECTION_LOST: AtomicBool = AtomicBool::new(false);
+    static CRASH_CONNECTION_LOST: AtomicBool = AtomicBool::new(false);
+    static CRASH_RECONNECTED: AtomicBool = AtomicBool::new(false);
+
+    fn on_presence(message: &rustypipes::OctopipesMessage) {
+        let event = rustypipes::OctopipesPresenceEvent::decode(&message.data).expect("Bad presence event");
+        if event.client_id != "presence_joiner" {
+            return;
+        }
+        assert_eq!(event.groups, vec![String::from("PRESENCE_TEST")]);
+        match event.kind {
+            rustypipes::OctopipesPresenceKind::Joined => PRESENCE_JOINED.store(true, Ordering::SeqCst),
+            rustypipes::OctopipesPresenceKind::Left => PRESENCE_LEFT.store(true, Ordering::SeqCst),
+            _ => panic!("presence_joiner should only join and leave"),
+        }
+    }
+
+    fn on_handled_group(message: &rustypipes::OctopipesMessage) {
+        assert_eq!(message.remote.as_deref(), Some("HANDLED"));
+        assert_eq!(message.data, b"handled".to_vec());
+        GROUP_HANDLED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_late_group(message: &rustypipes::OctopipesMessage) {
+        assert_eq!(message.remote.as_deref(), Some("LATE"));
+        assert_eq!(message.data, b"late".to_vec());
+        LATE_GROUP_HANDLED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_kicked(reason: &str) {
+        assert_eq!(reason, "misbehaving");
+        CLIENT_KICKED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_server_shutdown() {
+        SHUTDOWN_NOTIFIED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_dead_letter(dead_letter: &rustypipes::OctopipesDeadLetter) {
+        if dead_letter.message.remote.as_deref() == Some("nobody") {
+            assert_eq!(dead_letter.reason, rustypipes::OctopipesDeadLetterReason::NoSubscribers);
+            DEAD_LETTERED.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn on_event(event: &rustypipes::OctopipesServerEvent) {
+        if let rustypipes::OctopipesServerEvent::ClientSubscribed { client, .. } = event {
+            if client == "events_reader" {
+                EVENT_NOTIFIED.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn on_client_reaped(client: &str) {
+        assert_eq!(client, "silent_client", "Only the silent client should be reaped");
+        CLIENT_REAPED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_connection_lost() {
+        CONNECTION_LOST.store(true, Ordering::SeqCst);
+    }
+
+    fn on_reconnected() {
+        RECONNECTED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_senders_sent(message: &rustypipes::OctopipesMessage) {
+        if message.remote.as_deref() == Some("SENDERS") {
+            SENDERS_SENT.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn on_unsubscribe_connection_lost() {
+        UNSUBSCRIBE_CONNECTION_LOST.store(true, Ordering::SeqCst);
+    }
+
+    fn on_unsubscribe_event(event: &rustypipes::OctopipesServerEvent) {
+        //Delay the reply, once the pipes have been removed
+        if let rustypipes::OctopipesServerEvent::ClientUnsubscribed { client } = event {
+            if client == "leaving_client" {
+                sleep(Duration::from_millis(1000));
+            }
+        }
+    }
+
+    fn on_crash_connection_lost() {
+        CRASH_CONNECTION_LOST.store(true, Ordering::SeqCst);
+    }
+
+    fn on_crash_reconnected() {
+        CRASH_RECONNECTED.store(true, Ordering::SeqCst);
+    }
+
+    fn on_sent(message: &rustypipes::OctopipesMessage) {
+        if message.data == b"queued".to_vec() {
+            QUEUED_MESSAGE_SENT.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Runs the server until the thread has terminated (30 seconds at most)
+    fn run_server<T>(server: &mut rustypipes::OctopipesServer, join_hnd: &JoinHandle<T>) {
+        let t_start: Instant = Instant::now();
+        while !join_hnd.is_finished() && t_start.elapsed().as_millis() < 30000 {
+            let _ = server.process_cap_all();
+            let _ = server.process_all();
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    /// Waits for the next message received by the client (5 seconds at most)
+    fn wait_message(client: &rustypipes::OctopipesClient) -> Option<rustypipes::OctopipesMessage> {
+        let t_start: Instant = Instant::now();
+        while t_start.elapsed().as_millis() < 5000 {
+            if let Some(message) = client.get_next_message().expect("Could not get message") {
+                return Some(message);
+            }
+            sleep(Duration::from_millis(50));
+        }
+        None
+    }
+
+    /// Waits for the server to report the expected members for a group (the query is served after the previous CAP requests)
+    fn wait_members(client: &rustypipes::OctopipesClient, group: &str, expected: &[String]) {
+        let t_start: Instant = Instant::now();
+        while client.group_members(group).expect("Could not get group members") != expected && t_start.elapsed().as_millis() < 5000 {
+            sleep(Duration::from_millis(50));
+        }
+        assert_eq!(client.group_members(group).expect("Could not get group members"), expected);
+    }
+
+    /// Collects the messages received by the clients until the expected amount has been received (5 seconds at most)
+    fn collect_messages(clients: &[rustypipes::OctopipesClient], expected: usize) -> Vec<Vec<String>> {
+        let mut received: Vec<Vec<String>> = vec![Vec::new(); clients.len()];
+        let t_start: Instant = Instant::now();
+        while received.iter().map(|messages| messages.len()).sum::<usize>() < expected && t_start.elapsed().as_millis() < 5000 {
+            for (client, messages) in clients.iter().zip(received.iter_mut()) {
+                for message in client.get_all_message().expect("Could not get messages") {
+                    messages.push(String::from_utf8(message.data).unwrap());
+                }
+            }
+            sleep(Duration::from_millis(50));
+        }
+        //Give duplicates the time to show up
+        sleep(Duration::from_millis(500));
+        for (client, messages) in clients.iter().zip(received.iter_mut()) {
+            for message in client.get_all_message().expect("Could not get messages") {
+                messages.push(String::from_utf8(message.data).unwrap());
+            }
+        }
+        received
+    }
+
+    /// Encodes a frame by hand (ICK is always set, so there's no checksum)
+    fn encode_frame(origin: &str, remote: &str, options: rustypipes::OctopipesOptions, payload: &[u8]) -> Vec<u8> {
+        let mut frame: Vec<u8> = vec![0x01, 0x01, origin.len() as u8]; //SOH, version, origin size
+        frame.extend_from_slice(origin.as_bytes());
+        frame.push(remote.len() as u8);
+        frame.extend_from_slice(remote.as_bytes());
+        frame.push(5); //TTL
+        frame.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+        frame.extend_from_slice(&[(options | rustypipes::OctopipesOptions::ICK).bits(), 0x00, 0x02]); //Options, checksum, STX
+        frame.extend_from_slice(payload);
+        frame.push(0x03); //ETX
+        frame
+    }
+
+    /// Writes raw data to a TX pipe
+    fn write_raw(tx_pipe: &str, data: &[u8]) {
+        let mut pipe = std::fs::OpenOptions::new().write(true).open(tx_pipe).expect("Could not open TX pipe");
+        pipe.write_all(data).expect("Could not write to TX pipe");
+    }
+
+    /// Writes to a TX pipe a frame without origin, as if it had been sent by the server
+    fn forge_server_frame(tx_pipe: &str, remote: &str, options: rustypipes::OctopipesOptions, payload: &[u8]) {
+        write_raw(tx_pipe, &encode_frame("", remote, options, payload));
+    }
+
+    /// A client forges a server frame with the provided payload for another client:
+    /// the server must reject it and the victim mustn't receive anything
+    fn assert_forged_frame_rejected(cap_pipe: &str, client_folder: &str, options: rustypipes::OctopipesOptions, payload: Vec<u8>) {
+        let cap_pipe: String = String::from(cap_pipe);
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from(client_folder),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Option<rustypipes::OctopipesError>, bool, Option<rustypipes::OctopipesError>)> = spawn(move || {
+            let mut victim: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("forge_victim"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = victim.subscribe(&vec![]) {
+                panic!("Victim couldn't subscribe: {}", error);
+            }
+            if let Err(error) = victim.loop_start() {
+                panic!("Victim couldn't start loop: {}", error);
+            }
+            let mut forger: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("forger"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = forger.subscribe(&vec![]) {
+                panic!("Forger couldn't subscribe: {}", error);
+            }
+            if let Err(error) = forger.loop_start() {
+                panic!("Forger couldn't start loop: {}", error);
+            }
+            let (tx_pipe, _) = forger.assigned_pipes().expect("Forger has no pipes");
+            forge_server_frame(&tx_pipe, "forge_victim", options, &payload);
+            //The forger is told its frame has been rejected, the victim gets nothing
+            let mut forger_error: Option<rustypipes::OctopipesError> = None;
+            let mut victim_error: Option<rustypipes::OctopipesError> = None;
+            let mut victim_received: bool = false;
+            let t_start: Instant = Instant::now();
+            while t_start.elapsed().as_millis() < 2000 {
+                if let Err(error) = forger.get_next_message() {
+                    forger_error = Some(error);
+                }
+                match victim.get_next_message() {
+                    Ok(Some(_)) => victim_received = true,
+                    Ok(None) => {}
+                    Err(error) => victim_error = Some(error),
+                }
+                sleep(Duration::from_millis(50));
+            }
+            let victim_running: bool = victim.state() == rustypipes::OctopipesState::Running;
+            let _ = forger.unsubscribe();
+            let _ = victim.unsubscribe();
+            (forger_error, victim_running && !victim_received, victim_error)
+        });
+        let mut rejected: bool = false;
+        let t_start: Instant = Instant::now();
+        while !clients.is_finished() && t_start.elapsed().as_secs() < 10 {
+            for event in server.poll_events().expect("Poll events failed") {
+                if let rustypipes::OctopipesServerEvent::DecodeError { client, error } = event {
+                    rejected |= client == "forger" && error == rustypipes::OctopipesServerError::BadOrigin;
+                }
+            }
+            sleep(Duration::from_millis(50));
+        }
+        let (forger_error, victim_untouched, victim_error) = clients.join().expect("Clients thread panic");
+        assert!(rejected, "Server should have rejected the forged frame");
+        assert!(forger_error == Some(rustypipes::OctopipesError::BadPacket), "Forger should have been told its frame was rejected");
+        assert!(victim_error.is_none(), "Victim got an error: {}", victim_error.unwrap());
+        assert!(victim_untouched, "Victim should still be running and have received nothing");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
     #[test]
     fn server_sim() {
         //Simulates an entire server with a client
@@ -168,8 +442,9 @@ mod tests {
             }
             //Unsubscribe
             let t_recv: Instant = Instant::now();
-            if let Err(error) = client_r.unsubscribe() {
-                panic!("Error while client_r was trying to unsubscribe: {}\n", error);
+            match client_r.unsubscribe() {
+                Ok(cap_error) => assert_eq!(cap_error, rustypipes::OctopipesCapError::NoError),
+                Err(error) => panic!("Error while client_r was trying to unsubscribe: {}\n", error),
             }
             println!(
                 "It took {}ms for client_w to unsubscribe",
@@ -277,4 +552,1967 @@ mod tests {
             panic!("Could not stop Server: {}\n", error);
         }
     }
+
+    #[test]
+    fn concurrent_senders() {
+        //Several threads send large messages through cloned senders while the loop is running: each frame must arrive intact
+        const THREADS: usize = 4;
+        const MESSAGES: usize = 10;
+        const PAYLOAD_SIZE: usize = 16384; //Larger than the atomic write size of pipes
+        let cap_pipe: String = String::from("/tmp/cap_senders.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_senders/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Vec<Vec<u8>>, usize)> = spawn(move || {
+            let mut receiver: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("senders_receiver"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = receiver.subscribe(&vec![String::from("SENDERS")]) {
+                panic!("Receiver couldn't subscribe: {}", error);
+            }
+            if let Err(error) = receiver.loop_start() {
+                panic!("Receiver couldn't start loop: {}", error);
+            }
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("senders_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("Client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("Client couldn't start loop: {}", error);
+            }
+            let sender: rustypipes::OctopipesSender = client.sender();
+            //Set after the sender has been created
+            client.set_on_sent_callback(on_senders_sent);
+            let threads: Vec<JoinHandle<()>> = (0..THREADS)
+                .map(|thread_id| {
+                    let sender: rustypipes::OctopipesSender = sender.clone();
+                    spawn(move || {
+                        for index in 0..MESSAGES {
+                            //Each payload is made of a single byte identifying the message
+                            let payload: Vec<u8> = vec![(thread_id * MESSAGES + index) as u8; PAYLOAD_SIZE];
+                            if let Err(error) = sender.send("SENDERS", payload) {
+                                panic!("Thread {} couldn't send: {}", thread_id, error);
+                            }
+                        }
+                    })
+                })
+                .collect();
+            for thread in threads {
+                thread.join().expect("Sender thread panic");
+            }
+            let mut received: Vec<Vec<u8>> = Vec::new();
+            let t_start: Instant = Instant::now();
+            while received.len() < THREADS * MESSAGES && t_start.elapsed().as_millis() < 10000 {
+                match receiver.get_next_message() {
+                    Ok(Some(message)) => received.push(message.data),
+                    Ok(None) => sleep(Duration::from_millis(50)),
+                    Err(error) => panic!("Receiver got an error: {}", error),
+                }
+            }
+            let _ = client.unsubscribe();
+            let _ = receiver.unsubscribe();
+            (received, SENDERS_SENT.load(Ordering::SeqCst))
+        });
+        run_server(&mut server, &clients);
+        let (received, sent): (Vec<Vec<u8>>, usize) = clients.join().expect("Clients thread panic");
+        assert_eq!(sent, THREADS * MESSAGES, "on_sent should have been called for each message sent by the senders");
+        assert_eq!(received.len(), THREADS * MESSAGES);
+        let mut ids: Vec<u8> = Vec::new();
+        for payload in received.iter() {
+            assert_eq!(payload.len(), PAYLOAD_SIZE);
+            assert!(payload.iter().all(|byte| *byte == payload[0]), "Frames have been interleaved");
+            ids.push(payload[0]);
+        }
+        ids.sort();
+        assert_eq!(ids, (0..(THREADS * MESSAGES) as u8).collect::<Vec<u8>>());
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn join_leave_routing() {
+        //Groups joined and left by a running client change the messages the server routes to it
+        let cap_pipe: String = String::from("/tmp/cap_join_leave.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_join_leave/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut receiver: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("joining_client"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = receiver.subscribe(&vec![]) {
+                panic!("joining_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = receiver.loop_start() {
+                panic!("joining_client couldn't start loop: {}", error);
+            }
+            let mut sender: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("room_sender"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = sender.subscribe(&vec![]) {
+                panic!("room_sender couldn't subscribe: {}", error);
+            }
+            //Join ROOM
+            if let Err(error) = receiver.join_groups(&[String::from("ROOM")]) {
+                panic!("joining_client couldn't join ROOM: {}", error);
+            }
+            assert_eq!(receiver.groups(), vec![String::from("ROOM")]);
+            wait_members(&sender, "ROOM", &[String::from("joining_client")]);
+            if let Err(error) = sender.send(&String::from("ROOM"), b"joined".to_vec()) {
+                panic!("room_sender couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&receiver).expect("Message for ROOM wasn't received").data, b"joined".to_vec());
+            //Leave ROOM: messages for it aren't routed to the client anymore, but the ones for its id are
+            if let Err(error) = receiver.leave_groups(&[String::from("ROOM")]) {
+                panic!("joining_client couldn't leave ROOM: {}", error);
+            }
+            assert!(receiver.groups().is_empty());
+            wait_members(&sender, "ROOM", &[]);
+            if let Err(error) = sender.send(&String::from("ROOM"), b"left".to_vec()) {
+                panic!("room_sender couldn't send: {}", error);
+            }
+            if let Err(error) = sender.send(&String::from("joining_client"), b"direct".to_vec()) {
+                panic!("room_sender couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&receiver).expect("Direct message wasn't received").data, b"direct".to_vec());
+            let _ = sender.unsubscribe();
+            let _ = receiver.unsubscribe();
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn join_leave_rollback() {
+        //If the server doesn't confirm a join or a leave, the groups of the client are left unchanged
+        let cap_pipe: String = String::from("/tmp/cap_join_rollback.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_join_rollback/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<rustypipes::OctopipesClient> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("rollback_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![String::from("KEPT")]) {
+                panic!("rollback_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.join_groups(&[String::from("JOINED")]) {
+                panic!("rollback_client couldn't join JOINED: {}", error);
+            }
+            client
+        });
+        run_server(&mut server, &client_join_hnd);
+        let mut client: rustypipes::OctopipesClient = match client_join_hnd.join() {
+            Ok(client) => client,
+            Err(err) => panic!("Client thread panic: {:?}", err),
+        };
+        assert_eq!(client.groups(), vec![String::from("KEPT"), String::from("JOINED")]);
+        //The server forgets the client, which doesn't know it (its loop isn't running)
+        server.set_client_timeout(Some(Duration::from_millis(100)));
+        sleep(Duration::from_millis(200));
+        assert_eq!(server.reap_clients(), vec![String::from("rollback_client")]);
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            assert_eq!(client.join_groups(&[String::from("LOST")]).err(), Some(rustypipes::OctopipesError::NotSubscribed));
+            assert_eq!(client.leave_groups(&[String::from("KEPT")]).err(), Some(rustypipes::OctopipesError::NotSubscribed));
+            assert_eq!(client.groups(), vec![String::from("KEPT"), String::from("JOINED")]);
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn no_echo_delivery() {
+        //A client with no echo doesn't get back the messages it publishes to its own groups, while the other subscribers do
+        let cap_pipe: String = String::from("/tmp/cap_no_echo.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_no_echo/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut publisher: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("quiet_publisher"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            publisher.set_no_echo(true);
+            if let Err(error) = publisher.subscribe(&vec![String::from("CHAT")]) {
+                panic!("quiet_publisher couldn't subscribe: {}", error);
+            }
+            if let Err(error) = publisher.loop_start() {
+                panic!("quiet_publisher couldn't start loop: {}", error);
+            }
+            let mut listener: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("chat_listener"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = listener.subscribe(&vec![String::from("CHAT")]) {
+                panic!("chat_listener couldn't subscribe: {}", error);
+            }
+            if let Err(error) = listener.loop_start() {
+                panic!("chat_listener couldn't start loop: {}", error);
+            }
+            if let Err(error) = publisher.send(&String::from("CHAT"), b"hello".to_vec()) {
+                panic!("quiet_publisher couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&listener).expect("chat_listener didn't receive the message").data, b"hello".to_vec());
+            //The echo would have been dispatched together with the listener copy, so it would come before this one
+            if let Err(error) = listener.send(&String::from("quiet_publisher"), b"marker".to_vec()) {
+                panic!("chat_listener couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&publisher).expect("quiet_publisher didn't receive the marker").data, b"marker".to_vec());
+            //Echo enabled again: the publisher gets its own message
+            publisher.set_no_echo(false);
+            if let Err(error) = publisher.send(&String::from("CHAT"), b"echo".to_vec()) {
+                panic!("quiet_publisher couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&publisher).expect("quiet_publisher didn't receive its echo").data, b"echo".to_vec());
+            assert_eq!(wait_message(&listener).expect("chat_listener didn't receive the echo").data, b"echo".to_vec());
+            let _ = listener.unsubscribe();
+            let _ = publisher.unsubscribe();
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn queue_group_delivery() {
+        //Each message sent to a group is delivered to exactly one live member of a queue, while plain subscribers get all of them
+        let cap_pipe: String = String::from("/tmp/cap_queue_group.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_queue_group/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut workers: Vec<rustypipes::OctopipesClient> = Vec::new();
+            for i in 0..3 {
+                let mut worker: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    format!("queue_worker_{}", i),
+                    cap_pipe.clone(),
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                if let Err(error) = worker.subscribe(&vec![String::from("$share/workers/JOBS")]) {
+                    panic!("queue_worker_{} couldn't subscribe: {}", i, error);
+                }
+                if let Err(error) = worker.loop_start() {
+                    panic!("queue_worker_{} couldn't start loop: {}", i, error);
+                }
+                workers.push(worker);
+            }
+            let mut observer: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("jobs_observer"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = observer.subscribe(&vec![String::from("JOBS")]) {
+                panic!("jobs_observer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = observer.loop_start() {
+                panic!("jobs_observer couldn't start loop: {}", error);
+            }
+            let mut sender: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("jobs_sender"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = sender.subscribe(&vec![]) {
+                panic!("jobs_sender couldn't subscribe: {}", error);
+            }
+            let send_jobs = |jobs: std::ops::Range<usize>| {
+                for job in jobs {
+                    if let Err(error) = sender.send(&String::from("JOBS"), format!("job_{}", job).into_bytes()) {
+                        panic!("jobs_sender couldn't send job_{}: {}", job, error);
+                    }
+                }
+            };
+            //Every job reaches exactly one worker
+            send_jobs(0..6);
+            let received: Vec<Vec<String>> = collect_messages(&workers, 6);
+            for (i, jobs) in received.iter().enumerate() {
+                assert!(!jobs.is_empty(), "queue_worker_{} should have received some jobs", i);
+            }
+            let mut jobs: Vec<String> = received.concat();
+            jobs.sort();
+            assert_eq!(jobs, (0..6).map(|job| format!("job_{}", job)).collect::<Vec<String>>());
+            //Once a worker has left, jobs are shared by the remaining ones
+            let mut gone: rustypipes::OctopipesClient = workers.pop().unwrap();
+            if let Err(error) = gone.unsubscribe() {
+                panic!("queue_worker_2 couldn't unsubscribe: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            while sender.is_online("queue_worker_2").unwrap() && t_start.elapsed().as_millis() < 5000 {
+                sleep(Duration::from_millis(50));
+            }
+            send_jobs(6..10);
+            let mut jobs: Vec<String> = collect_messages(&workers, 4).concat();
+            jobs.sort();
+            assert_eq!(jobs, (6..10).map(|job| format!("job_{}", job)).collect::<Vec<String>>());
+            //The plain subscriber got all of them
+            let observed: Vec<String> = collect_messages(std::slice::from_ref(&observer), 10).concat();
+            assert_eq!(observed, (0..10).map(|job| format!("job_{}", job)).collect::<Vec<String>>());
+            let _ = observer.unsubscribe();
+            let _ = sender.unsubscribe();
+            for mut worker in workers {
+                let _ = worker.unsubscribe();
+            }
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn group_handlers() {
+        //Messages for a group with a handler are passed to it by the client loop, the others end up in the inbox
+        let cap_pipe: String = String::from("/tmp/cap_handlers.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_handlers/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut receiver: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("handlers_receiver"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            let groups: Vec<String> = vec![String::from("HANDLED"), String::from("UNHANDLED"), String::from("LATE")];
+            if let Err(error) = receiver.subscribe(&groups) {
+                panic!("handlers_receiver couldn't subscribe: {}", error);
+            }
+            receiver.on_group("HANDLED", on_handled_group);
+            if let Err(error) = receiver.loop_start() {
+                panic!("handlers_receiver couldn't start loop: {}", error);
+            }
+            //Handlers can be registered while the loop is running too
+            receiver.on_group("LATE", on_late_group);
+            let mut sender: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("handlers_sender"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = sender.subscribe(&vec![]) {
+                panic!("handlers_sender couldn't subscribe: {}", error);
+            }
+            for (group, data) in [("HANDLED", "handled"), ("UNHANDLED", "unhandled"), ("LATE", "late")].iter() {
+                if let Err(error) = sender.send(&String::from(*group), data.as_bytes().to_vec()) {
+                    panic!("handlers_sender couldn't send to {}: {}", group, error);
+                }
+            }
+            //Collect the messages which end up in the inbox
+            let mut inbox: Vec<rustypipes::OctopipesMessage> = Vec::new();
+            let t_start: Instant = Instant::now();
+            while t_start.elapsed().as_millis() < 3000 {
+                inbox.extend(receiver.get_all_message().expect("Could not get messages"));
+                if GROUP_HANDLED.load(Ordering::SeqCst) && LATE_GROUP_HANDLED.load(Ordering::SeqCst) && !inbox.is_empty() {
+                    break;
+                }
+                sleep(Duration::from_millis(50));
+            }
+            assert!(GROUP_HANDLED.load(Ordering::SeqCst), "HANDLED handler should have been called");
+            assert!(LATE_GROUP_HANDLED.load(Ordering::SeqCst), "LATE handler should have been called");
+            assert_eq!(inbox.len(), 1, "Only the message without handler should be in the inbox");
+            assert_eq!(inbox[0].remote.as_deref(), Some("UNHANDLED"));
+            assert_eq!(inbox[0].data, b"unhandled".to_vec());
+            let _ = sender.unsubscribe();
+            let _ = receiver.unsubscribe();
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn presence_events() {
+        //A client watching the presence group is notified when another client subscribes and unsubscribes
+        let cap_pipe: String = String::from("/tmp/cap_presence.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_presence/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut watcher: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("presence_watcher"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = watcher.subscribe(&vec![]) {
+                panic!("presence_watcher couldn't subscribe: {}", error);
+            }
+            watcher.on_group(rustypipes::PRESENCE_GROUP, on_presence);
+            if let Err(error) = watcher.join_groups(&[String::from(rustypipes::PRESENCE_GROUP)]) {
+                panic!("presence_watcher couldn't join the presence group: {}", error);
+            }
+            if let Err(error) = watcher.loop_start() {
+                panic!("presence_watcher couldn't start loop: {}", error);
+            }
+            let mut joiner: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("presence_joiner"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = joiner.subscribe(&vec![String::from("PRESENCE_TEST")]) {
+                panic!("presence_joiner couldn't subscribe: {}", error);
+            }
+            if let Err(error) = joiner.unsubscribe() {
+                panic!("presence_joiner couldn't unsubscribe: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            while !PRESENCE_LEFT.load(Ordering::SeqCst) && t_start.elapsed().as_millis() < 5000 {
+                sleep(Duration::from_millis(50));
+            }
+            assert!(PRESENCE_JOINED.load(Ordering::SeqCst), "presence_watcher should have been notified of presence_joiner joining");
+            assert!(PRESENCE_LEFT.load(Ordering::SeqCst), "presence_watcher should have been notified of presence_joiner leaving");
+            let _ = watcher.unsubscribe();
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn client_introspection() {
+        //The client state, groups, pipes and session must follow subscription, loop start and unsubscription
+        let cap_pipe: String = String::from("/tmp/cap_introspection.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_introspection/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("introspected_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            assert_eq!(client.id(), "introspected_client");
+            assert_eq!(client.state(), rustypipes::OctopipesState::Initialized);
+            assert!(client.groups().is_empty());
+            assert!(client.assigned_pipes().is_none(), "Client shouldn't have pipes before subscribing");
+            assert!(client.subscribed_since().is_none(), "Client shouldn't have a subscription time before subscribing");
+            assert!(client.server_version().is_none(), "Client shouldn't know the server version before subscribing");
+            let groups: Vec<String> = vec![String::from("INTROSPECTION"), String::from("BROADCAST")];
+            if let Err(error) = client.subscribe(&groups) {
+                panic!("introspected_client couldn't subscribe: {}", error);
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Subscribed);
+            assert_eq!(client.groups(), groups);
+            let (tx_pipe, rx_pipe) = client.assigned_pipes().expect("Client should have assigned pipes");
+            assert!(tx_pipe.ends_with("/introspected_client_tx.fifo"), "Unexpected tx pipe {}", tx_pipe);
+            assert!(rx_pipe.ends_with("/introspected_client_rx.fifo"), "Unexpected rx pipe {}", rx_pipe);
+            assert!(client.subscribed_since().is_some(), "Client should have a subscription time");
+            assert!(matches!(client.server_version(), Some(rustypipes::OctopipesProtocolVersion::Version1)), "Client should know the server version");
+            if let Err(error) = client.loop_start() {
+                panic!("introspected_client couldn't start loop: {}", error);
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Running);
+            if let Err(error) = client.unsubscribe() {
+                panic!("introspected_client couldn't unsubscribe: {}", error);
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Unsubscribed);
+            assert!(client.subscribed_since().is_none(), "Client shouldn't have a subscription time anymore");
+            assert!(client.server_version().is_none(), "Client shouldn't know the server version anymore");
+            assert!(client.assigned_pipes().is_none(), "Client shouldn't have pipes anymore");
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn discovery() {
+        //A client asks the server which clients are subscribed, who belongs to a group and whether a client is online
+        let cap_pipe: String = String::from("/tmp/cap_discovery.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_discovery/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut member: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("discovered_client"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = member.subscribe(&vec![String::from("DISCOVERY")]) {
+                panic!("discovered_client couldn't subscribe: {}", error);
+            }
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("discovering_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("discovering_client couldn't subscribe: {}", error);
+            }
+            let mut clients: Vec<String> = client.list_clients().expect("Could not list clients");
+            clients.sort();
+            assert_eq!(clients, vec![String::from("discovered_client"), String::from("discovering_client")]);
+            let members: Vec<String> = client.group_members("DISCOVERY").expect("Could not get group members");
+            assert_eq!(members, vec![String::from("discovered_client")]);
+            assert!(client.group_members("NOBODY").expect("Could not get group members").is_empty());
+            assert_eq!(client.is_online("discovered_client").unwrap(), true);
+            assert_eq!(client.is_online("unknown_client").unwrap(), false);
+            //Once unsubscribed, a client isn't online anymore
+            let _ = member.unsubscribe();
+            assert_eq!(client.is_online("discovered_client").unwrap(), false);
+            let _ = client.unsubscribe();
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn kick_client() {
+        //The server kicks a running client, which must become unsubscribed
+        let cap_pipe: String = String::from("/tmp/cap_kick.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_kick/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("kicked_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_on_kicked(on_kicked);
+            match client.subscribe(&vec![]) {
+                Ok(cap_error) => assert_eq!(cap_error, rustypipes::OctopipesCapError::NoError),
+                Err(error) => panic!("kicked_client couldn't subscribe: {}", error),
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("kicked_client couldn't start loop: {}", error);
+            }
+            //Wait for the kick
+            let t_start: Instant = Instant::now();
+            while client.state() == rustypipes::OctopipesState::Running && t_start.elapsed().as_millis() < 10000 {
+                sleep(Duration::from_millis(50));
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Unsubscribed);
+            assert!(CLIENT_KICKED.load(Ordering::SeqCst), "on_kicked should have been called");
+            assert!(matches!(client.get_next_message(), Err(rustypipes::OctopipesError::Kicked)));
+            assert!(client.server_version().is_none(), "Session should have been cleared");
+            assert!(client.assigned_pipes().is_none(), "Pipes removed by the server shouldn't be reported anymore");
+        });
+        //Wait for subscription
+        let t_start: Instant = Instant::now();
+        while server.is_subscribed(String::from("kicked_client")).is_none() && t_start.elapsed().as_millis() < 10000 {
+            if let Err(error) = server.process_cap_all() {
+                panic!("Error while processing CAP: {}\n", error);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        //Give the client the time to start its loop
+        sleep(Duration::from_millis(500));
+        if let Err(error) = server.kick("kicked_client", "misbehaving") {
+            panic!("Could not kick client: {}", error);
+        }
+        assert!(server.is_subscribed(String::from("kicked_client")).is_none());
+        assert!(server.kick("kicked_client", "again").is_err(), "Kicking an unknown client should fail");
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn client_reaping() {
+        //A client which doesn't send anything must be reaped once the client timeout expires, the others (heartbeats or messages) are kept
+        let cap_pipe: String = String::from("/tmp/cap_reaping.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_reaping/"),
+        );
+        server.set_client_timeout(Some(Duration::from_secs(1)));
+        server.set_on_client_reaped(on_client_reaped);
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let (stop_tx, stop_rx) = std::sync::mpsc::channel::<()>();
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut silent: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("silent_client"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = silent.subscribe(&vec![]) {
+                panic!("silent_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = silent.loop_start() {
+                panic!("silent_client couldn't start loop: {}", error);
+            }
+            let mut beating: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("beating_client"),
+                cap_pipe.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            beating.set_heartbeat_interval(Some(Duration::from_millis(200)));
+            if let Err(error) = beating.subscribe(&vec![]) {
+                panic!("beating_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = beating.loop_start() {
+                panic!("beating_client couldn't start loop: {}", error);
+            }
+            //Only sends messages, without loop nor heartbeats
+            let mut sending: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("sending_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = sending.subscribe(&vec![]) {
+                panic!("sending_client couldn't subscribe: {}", error);
+            }
+            while stop_rx.try_recv().is_err() {
+                if let Err(error) = sending.send(&String::from("REAPING"), b"busy".to_vec()) {
+                    panic!("sending_client couldn't send: {}", error);
+                }
+                sleep(Duration::from_millis(200));
+            }
+            let _ = sending.unsubscribe();
+            let _ = beating.unsubscribe();
+        });
+        //Only the CAP is served
+        let t_start: Instant = Instant::now();
+        while !CLIENT_REAPED.load(Ordering::SeqCst) && t_start.elapsed().as_millis() < 10000 {
+            let _ = server.process_cap_once();
+            sleep(Duration::from_millis(50));
+        }
+        assert!(CLIENT_REAPED.load(Ordering::SeqCst), "silent_client should have been reaped");
+        assert!(server.is_subscribed(String::from("silent_client")).is_none());
+        assert!(server.is_subscribed(String::from("beating_client")).is_some(), "beating_client should have been kept");
+        //Keep serving for longer than the timeout while sending_client is busy
+        let t_start: Instant = Instant::now();
+        while t_start.elapsed().as_millis() < 2000 {
+            let _ = server.process_cap_once();
+            sleep(Duration::from_millis(50));
+        }
+        assert!(server.is_subscribed(String::from("sending_client")).is_some(), "sending_client should have been kept");
+        assert!(server.is_subscribed(String::from("beating_client")).is_some(), "beating_client should have been kept");
+        //Clients go silent while unsubscribing
+        server.set_client_timeout(None);
+        let _ = stop_tx.send(());
+        while !client_join_hnd.is_finished() {
+            let _ = server.process_cap_once();
+            sleep(Duration::from_millis(50));
+        }
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn reconnection() {
+        //The server goes away without notice and is started again: the client must subscribe again and keep working
+        let cap_pipe: String = String::from("/tmp/cap_reconnect.fifo");
+        let client_folder: String = String::from("/tmp/clients_reconnect/");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            client_folder.clone(),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let cap_pipe_c: String = cap_pipe.clone();
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("reconnecting_client"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_reconnect_policy(Some(rustypipes::OctopipesReconnectPolicy {
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(500),
+                max_attempts: Some(50),
+            }));
+            client.set_on_connection_lost(on_connection_lost);
+            client.set_on_reconnected(on_reconnected);
+            if let Err(error) = client.subscribe(&vec![String::from("RESTART")]) {
+                panic!("reconnecting_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("reconnecting_client couldn't start loop: {}", error);
+            }
+            //Wait for the client to subscribe to the new server
+            let t_start: Instant = Instant::now();
+            while !RECONNECTED.load(Ordering::SeqCst) && t_start.elapsed().as_millis() < 20000 {
+                sleep(Duration::from_millis(50));
+            }
+            assert!(CONNECTION_LOST.load(Ordering::SeqCst), "on_connection_lost should have been called");
+            assert!(RECONNECTED.load(Ordering::SeqCst), "on_reconnected should have been called");
+            assert_eq!(client.state(), rustypipes::OctopipesState::Running);
+            //The new pipes must work
+            if let Err(error) = client.send(&String::from("RESTART"), b"after restart".to_vec()) {
+                panic!("reconnecting_client couldn't send: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            let mut received: Option<rustypipes::OctopipesMessage> = None;
+            while received.is_none() && t_start.elapsed().as_millis() < 5000 {
+                received = client.get_next_message().expect("Could not get message");
+                sleep(Duration::from_millis(50));
+            }
+            assert_eq!(received.expect("Message sent after reconnecting wasn't received").data, b"after restart".to_vec());
+            let _ = client.unsubscribe();
+        });
+        //Wait for subscription
+        let t_start: Instant = Instant::now();
+        while server.is_subscribed(String::from("reconnecting_client")).is_none() && t_start.elapsed().as_millis() < 10000 {
+            let _ = server.process_cap_all();
+            sleep(Duration::from_millis(50));
+        }
+        sleep(Duration::from_millis(500));
+        //The server goes away without notifying the clients (its pipes are removed)
+        drop(server);
+        sleep(Duration::from_millis(500));
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe,
+            client_folder,
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener again: {}", error);
+        }
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn server_crash() {
+        //The server process is killed, leaving its pipes behind: the client must notice it from its heartbeats and subscribe again to the next server
+        let cap_pipe: String = String::from("/tmp/cap_crash.fifo");
+        let client_folder: String = String::from("/tmp/clients_crash/");
+        let rx_pipe: String = format!("{}/crash_client_rx.fifo", client_folder);
+        //When the test runs itself in another process, it's the server which is killed
+        if std::env::var("RUSTYPIPES_CRASHING_SERVER").is_ok() {
+            let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+                rustypipes::OctopipesProtocolVersion::Version1,
+                cap_pipe,
+                client_folder,
+            );
+            if let Err(error) = server.start_cap_listener() {
+                panic!("Could not start CAP listener: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            while t_start.elapsed().as_secs() < 60 {
+                let _ = server.process_cap_all();
+                let _ = server.process_all();
+                sleep(Duration::from_millis(50));
+            }
+            return;
+        }
+        let mut crashing_server: Child = Command::new(std::env::current_exe().expect("Could not get test executable"))
+            .args(&["tests::server_crash", "--exact", "--nocapture"])
+            .env("RUSTYPIPES_CRASHING_SERVER", "1")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("Could not start server process");
+        let (subscribed_tx, subscribed_rx) = std::sync::mpsc::channel::<()>();
+        let cap_pipe_c: String = cap_pipe.clone();
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("crash_client"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_reconnect_policy(Some(rustypipes::OctopipesReconnectPolicy {
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(500),
+                max_attempts: Some(50),
+            }));
+            client.set_heartbeat_interval(Some(Duration::from_millis(200)));
+            client.set_on_connection_lost(on_crash_connection_lost);
+            client.set_on_reconnected(on_crash_reconnected);
+            //Wait for the server process to be ready
+            let t_start: Instant = Instant::now();
+            while client.subscribe(&vec![String::from("CRASH")]).ok() != Some(rustypipes::OctopipesCapError::NoError) {
+                assert!(t_start.elapsed().as_secs() < 10, "crash_client couldn't subscribe");
+                sleep(Duration::from_millis(100));
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("crash_client couldn't start loop: {}", error);
+            }
+            subscribed_tx.send(()).expect("Main thread has gone away");
+            //Wait for the client to subscribe to the new server
+            let t_start: Instant = Instant::now();
+            while !CRASH_RECONNECTED.load(Ordering::SeqCst) && t_start.elapsed().as_secs() < 30 {
+                sleep(Duration::from_millis(50));
+            }
+            assert!(CRASH_RECONNECTED.load(Ordering::SeqCst), "on_reconnected should have been called");
+            assert_eq!(client.state(), rustypipes::OctopipesState::Running);
+            if let Err(error) = client.send(&String::from("CRASH"), b"after crash".to_vec()) {
+                panic!("crash_client couldn't send: {}", error);
+            }
+            assert_eq!(wait_message(&client).expect("Message sent after reconnecting wasn't received").data, b"after crash".to_vec());
+            let _ = client.unsubscribe();
+        });
+        if subscribed_rx.recv_timeout(Duration::from_secs(20)).is_err() {
+            let _ = crashing_server.kill();
+            panic!("Client thread didn't subscribe: {:?}", client_join_hnd.join().err());
+        }
+        sleep(Duration::from_millis(500));
+        //Kill the server: its drop doesn't run, so the pipes stay on disk
+        crashing_server.kill().expect("Could not kill server process");
+        let _ = crashing_server.wait();
+        assert!(Path::new(&rx_pipe).exists(), "The pipes of the killed server should be left behind");
+        let t_start: Instant = Instant::now();
+        while !CRASH_CONNECTION_LOST.load(Ordering::SeqCst) && t_start.elapsed().as_secs() < 20 {
+            sleep(Duration::from_millis(50));
+        }
+        assert!(CRASH_CONNECTION_LOST.load(Ordering::SeqCst), "Client should have noticed the server has gone away");
+        //The next server removes the stale pipes
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe,
+            client_folder,
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        assert!(!Path::new(&rx_pipe).exists(), "Stale pipes should have been removed");
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn unsubscribe_no_reconnect() {
+        //A client with a reconnect policy which unsubscribes mustn't take the removal of its pipes for a lost connection
+        let cap_pipe: String = String::from("/tmp/cap_unsubscribe_reconnect.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_unsubscribe_reconnect/"),
+        );
+        server.set_on_event(on_unsubscribe_event);
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("leaving_client"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_reconnect_policy(Some(rustypipes::OctopipesReconnectPolicy {
+                initial_delay: Duration::from_millis(10),
+                max_delay: Duration::from_millis(100),
+                max_attempts: None,
+            }));
+            client.set_on_connection_lost(on_unsubscribe_connection_lost);
+            if let Err(error) = client.subscribe(&vec![String::from("LEAVING")]) {
+                panic!("leaving_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("leaving_client couldn't start loop: {}", error);
+            }
+            match client.unsubscribe() {
+                Ok(cap_error) => assert_eq!(cap_error, rustypipes::OctopipesCapError::NoError),
+                Err(error) => panic!("leaving_client couldn't unsubscribe: {}", error),
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Unsubscribed);
+            //Give a reconnection the time to happen
+            sleep(Duration::from_millis(1000));
+        });
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        let _ = server.process_cap_all();
+        assert!(!UNSUBSCRIBE_CONNECTION_LOST.load(Ordering::SeqCst), "on_connection_lost shouldn't have been called");
+        assert!(server.is_subscribed(String::from("leaving_client")).is_none(), "The client shouldn't have subscribed again");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn outbound_queue_reconnect() {
+        //A message sent while the client is reconnecting is queued, then written once the client has subscribed to the new server
+        let cap_pipe: String = String::from("/tmp/cap_outbound.fifo");
+        let client_folder: String = String::from("/tmp/clients_outbound/");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            client_folder.clone(),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let message_queued: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        let message_queued_c: Arc<AtomicBool> = Arc::clone(&message_queued);
+        let cap_pipe_c: String = cap_pipe.clone();
+        let client_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("queueing_client"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_reconnect_policy(Some(rustypipes::OctopipesReconnectPolicy {
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(500),
+                max_attempts: Some(50),
+            }));
+            client.set_on_sent_callback(on_sent);
+            if let Err(error) = client.set_outbound_queue(8, rustypipes::OctopipesOverflowPolicy::Reject, None) {
+                panic!("queueing_client couldn't enable the outbound queue: {}", error);
+            }
+            if let Err(error) = client.subscribe(&vec![String::from("OUTBOX")]) {
+                panic!("queueing_client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("queueing_client couldn't start loop: {}", error);
+            }
+            //Wait for the server to go away
+            let t_start: Instant = Instant::now();
+            while client.state() != rustypipes::OctopipesState::Reconnecting && t_start.elapsed().as_millis() < 10000 {
+                sleep(Duration::from_millis(50));
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Reconnecting);
+            //Send a message to itself; it can't be written yet
+            if let Err(error) = client.send(&String::from("OUTBOX"), b"queued".to_vec()) {
+                panic!("queueing_client couldn't queue a message: {}", error);
+            }
+            assert_eq!(client.get_outbound_queue_len(), 1);
+            assert!(!QUEUED_MESSAGE_SENT.load(Ordering::SeqCst), "on_sent shouldn't be called for a queued message");
+            message_queued_c.store(true, Ordering::SeqCst);
+            //Once reconnected, the queue is flushed and the message is received through the new pipes
+            let t_start: Instant = Instant::now();
+            let mut received: Option<rustypipes::OctopipesMessage> = None;
+            while received.is_none() && t_start.elapsed().as_millis() < 10000 {
+                received = client.get_next_message().expect("Could not get message");
+                sleep(Duration::from_millis(50));
+            }
+            assert_eq!(received.expect("Queued message wasn't received").data, b"queued".to_vec());
+            assert_eq!(client.get_outbound_queue_len(), 0);
+            assert!(QUEUED_MESSAGE_SENT.load(Ordering::SeqCst), "on_sent should have been called once the message was written");
+            let _ = client.unsubscribe();
+        });
+        //Wait for subscription
+        let t_start: Instant = Instant::now();
+        while server.is_subscribed(String::from("queueing_client")).is_none() && t_start.elapsed().as_millis() < 10000 {
+            let _ = server.process_cap_all();
+            sleep(Duration::from_millis(50));
+        }
+        sleep(Duration::from_millis(500));
+        //The server goes away without notifying the clients and is started again once the client has queued its message
+        drop(server);
+        let t_start: Instant = Instant::now();
+        while !message_queued.load(Ordering::SeqCst) && !client_join_hnd.is_finished() && t_start.elapsed().as_millis() < 10000 {
+            sleep(Duration::from_millis(50));
+        }
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe,
+            client_folder,
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener again: {}", error);
+        }
+        run_server(&mut server, &client_join_hnd);
+        if let Err(err) = client_join_hnd.join() {
+            panic!("Client thread panic: {:?}", err);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn server_shutdown() {
+        //Messages pending when the server is stopped must be delivered, then clients are told the server has shut down
+        let cap_pipe: String = String::from("/tmp/cap_shutdown.fifo");
+        let cap_pipe_w: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_shutdown/"),
+        );
+        server.set_drain_deadline(Duration::from_secs(5));
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let reader_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("shutdown_reader"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_on_server_shutdown(on_server_shutdown);
+            if let Err(error) = client.subscribe(&vec![String::from("NEWS")]) {
+                panic!("shutdown_reader couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("shutdown_reader couldn't start loop: {}", error);
+            }
+            //Wait for the server to go away
+            let t_start: Instant = Instant::now();
+            while client.state() == rustypipes::OctopipesState::Running && t_start.elapsed().as_millis() < 20000 {
+                sleep(Duration::from_millis(50));
+            }
+            assert_eq!(client.state(), rustypipes::OctopipesState::Unsubscribed);
+            assert!(SHUTDOWN_NOTIFIED.load(Ordering::SeqCst), "on_server_shutdown should have been called");
+            match client.get_next_message() {
+                Ok(Some(message)) => assert_eq!(message.data, b"last news".to_vec()),
+                _ => panic!("Pending message should have been delivered before shutdown"),
+            }
+            assert!(matches!(client.get_next_message(), Err(rustypipes::OctopipesError::ServerShutdown)));
+        });
+        let writer_join_hnd: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("shutdown_writer"),
+                cap_pipe_w,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("shutdown_writer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.send_ex(&String::from("NEWS"), b"last news".to_vec(), 5, rustypipes::OctopipesOptions::empty()) {
+                panic!("shutdown_writer couldn't send: {}", error);
+            }
+        });
+        //Wait for both subscriptions
+        let t_start: Instant = Instant::now();
+        while server.get_clients().len() < 2 && t_start.elapsed().as_millis() < 10000 {
+            if let Err(error) = server.process_cap_all() {
+                panic!("Error while processing CAP: {}\n", error);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        if let Err(err) = writer_join_hnd.join() {
+            panic!("Writer thread panic: {:?}", err);
+        }
+        //Give the worker the time to read the message, then stop without processing it
+        sleep(Duration::from_millis(1000));
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+        if let Err(err) = reader_join_hnd.join() {
+            panic!("Reader thread panic: {:?}", err);
+        }
+    }
+
+    #[test]
+    fn retained_messages() {
+        //Clients subscribing after a retained message has been sent must receive it, until it's cleared
+        let cap_pipe: String = String::from("/tmp/cap_retain.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_retain/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        //Spawns a client which subscribes to config/+ and returns the payload of the first message received in 2 seconds
+        let spawn_reader = |client_id: &str| -> JoinHandle<Option<Vec<u8>>> {
+            let cap_pipe: String = cap_pipe.clone();
+            let client_id: String = client_id.to_string();
+            spawn(move || {
+                let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    client_id,
+                    cap_pipe,
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                if let Err(error) = client.subscribe(&vec![String::from("config/+")]) {
+                    panic!("Reader couldn't subscribe: {}", error);
+                }
+                if let Err(error) = client.loop_start() {
+                    panic!("Reader couldn't start loop: {}", error);
+                }
+                let t_start: Instant = Instant::now();
+                while t_start.elapsed().as_millis() < 2000 {
+                    if let Ok(Some(message)) = client.get_next_message() {
+                        return Some(message.data);
+                    }
+                    sleep(Duration::from_millis(50));
+                }
+                None
+            })
+        };
+        //Spawns a client which sends a retained message to config/mode
+        let spawn_writer = |payload: Vec<u8>| -> JoinHandle<()> {
+            let cap_pipe: String = cap_pipe.clone();
+            spawn(move || {
+                let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    String::from("retain_writer"),
+                    cap_pipe,
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                if let Err(error) = client.subscribe(&vec![]) {
+                    panic!("Writer couldn't subscribe: {}", error);
+                }
+                if let Err(error) = client.send_ex(&String::from("config/mode"), payload, 5, rustypipes::OctopipesOptions::RETAIN) {
+                    panic!("Writer couldn't send: {}", error);
+                }
+                sleep(Duration::from_millis(500));
+                let _ = client.unsubscribe();
+            })
+        };
+        //Retain a message
+        let writer: JoinHandle<()> = spawn_writer(b"eco".to_vec());
+        run_server(&mut server, &writer);
+        writer.join().expect("Writer thread panic");
+        //A late subscriber gets it
+        let reader: JoinHandle<Option<Vec<u8>>> = spawn_reader("retain_reader_a");
+        run_server(&mut server, &reader);
+        assert_eq!(reader.join().expect("Reader thread panic"), Some(b"eco".to_vec()));
+        //Clear it
+        let writer: JoinHandle<()> = spawn_writer(vec![]);
+        run_server(&mut server, &writer);
+        writer.join().expect("Writer thread panic");
+        let reader: JoinHandle<Option<Vec<u8>>> = spawn_reader("retain_reader_b");
+        run_server(&mut server, &reader);
+        assert_eq!(reader.join().expect("Reader thread panic"), None, "Retained message should have been cleared");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn retained_messages_no_reader() {
+        //A subscriber which doesn't read its pipe can't block the server, even if the retained message has no ttl,
+        //and it gets the retained message once it starts reading
+        let cap_pipe: String = String::from("/tmp/cap_retain_idle.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_retain_idle/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Option<rustypipes::OctopipesMessage>, Option<rustypipes::OctopipesMessage>)> = spawn(move || {
+            let mut writer: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("retain_idle_writer"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = writer.subscribe(&vec![String::from("config/+")]) {
+                panic!("Writer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = writer.loop_start() {
+                panic!("Writer couldn't start loop: {}", error);
+            }
+            if let Err(error) = writer.send_ex(&String::from("config/mode"), b"eco".to_vec(), 0, rustypipes::OctopipesOptions::RETAIN) {
+                panic!("Writer couldn't send: {}", error);
+            }
+            //Once the echo is back, the server has retained the message
+            assert!(wait_message(&writer).is_some(), "Writer didn't get its message back");
+            //Subscribes without ever starting the loop
+            let mut idle: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("retain_idle_subscriber"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = idle.subscribe(&vec![String::from("config/+")]) {
+                panic!("Idle subscriber couldn't subscribe: {}", error);
+            }
+            //The server must still serve the next subscriber
+            let mut reader: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("retain_idle_reader"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = reader.subscribe(&vec![String::from("config/+")]) {
+                panic!("Reader couldn't subscribe: {}", error);
+            }
+            if let Err(error) = reader.loop_start() {
+                panic!("Reader couldn't start loop: {}", error);
+            }
+            let received: Option<rustypipes::OctopipesMessage> = wait_message(&reader);
+            //The idle subscriber starts reading a while later
+            sleep(Duration::from_millis(1500));
+            if let Err(error) = idle.loop_start() {
+                panic!("Idle subscriber couldn't start loop: {}", error);
+            }
+            (received, wait_message(&idle))
+        });
+        run_server(&mut server, &clients);
+        let (message, late_message) = clients.join().expect("Clients thread panic");
+        assert_eq!(message.map(|message| message.data), Some(b"eco".to_vec()));
+        assert_eq!(late_message.map(|message| message.data), Some(b"eco".to_vec()), "Idle subscriber didn't get the retained message");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn durable_subscription() {
+        //Messages sent while a durable client is offline are delivered, in order, when it subscribes again and reads its pipe
+        let cap_pipe: String = String::from("/tmp/cap_durable.fifo");
+        let cap_pipe_w: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_durable/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let (offline_tx, offline_rx) = std::sync::mpsc::channel::<()>();
+        let reader: JoinHandle<Vec<Vec<u8>>> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("durable_reader"),
+                cap_pipe,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            client.set_durable(true);
+            if let Err(error) = client.subscribe(&vec![String::from("NEWS")]) {
+                panic!("Reader couldn't subscribe: {}", error);
+            }
+            match client.unsubscribe() {
+                Ok(cap_error) => assert_eq!(cap_error, rustypipes::OctopipesCapError::NoError),
+                Err(error) => panic!("Reader couldn't unsubscribe: {}", error),
+            }
+            offline_tx.send(()).expect("Main thread has gone away");
+            //Wait for the messages to be sent, then come back
+            rx.recv().expect("Main thread has gone away");
+            if let Err(error) = client.subscribe(&vec![String::from("NEWS")]) {
+                panic!("Reader couldn't subscribe again: {}", error);
+            }
+            //The stored messages must wait for the reader, even if it starts reading a while after subscribing
+            sleep(Duration::from_millis(1500));
+            if let Err(error) = client.loop_start() {
+                panic!("Reader couldn't start loop: {}", error);
+            }
+            let mut received: Vec<Vec<u8>> = Vec::new();
+            let t_start: Instant = Instant::now();
+            while received.len() < 2 && t_start.elapsed().as_millis() < 5000 {
+                if let Ok(Some(message)) = client.get_next_message() {
+                    received.push(message.data);
+                }
+                sleep(Duration::from_millis(50));
+            }
+            received
+        });
+        //Wait for the reader to go offline
+        let t_start: Instant = Instant::now();
+        while offline_rx.try_recv().is_err() && t_start.elapsed().as_millis() < 10000 {
+            let _ = server.process_cap_all();
+            sleep(Duration::from_millis(50));
+        }
+        //Send two messages while the reader is offline
+        let writer: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("durable_writer"),
+                cap_pipe_w,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("Writer couldn't subscribe: {}", error);
+            }
+            for payload in ["first", "second"].iter() {
+                if let Err(error) = client.send_ex(&String::from("NEWS"), payload.as_bytes().to_vec(), 5, rustypipes::OctopipesOptions::empty()) {
+                    panic!("Writer couldn't send: {}", error);
+                }
+            }
+            sleep(Duration::from_millis(500));
+        });
+        run_server(&mut server, &writer);
+        writer.join().expect("Writer thread panic");
+        let _ = server.process_all();
+        //Let the reader come back
+        tx.send(()).expect("Reader has gone away");
+        run_server(&mut server, &reader);
+        let received: Vec<Vec<u8>> = reader.join().expect("Reader thread panic");
+        assert_eq!(received, vec![b"first".to_vec(), b"second".to_vec()]);
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn durable_subscription_no_ttl() {
+        //Messages without ttl stored for a durable client are delivered if it reads them, but can't block the server if it doesn't
+        let cap_pipe: String = String::from("/tmp/cap_durable_no_ttl.fifo");
+        let cap_pipe_w: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_durable_no_ttl/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let (tx, rx) = std::sync::mpsc::channel::<()>();
+        let (offline_tx, offline_rx) = std::sync::mpsc::channel::<()>();
+        let readers: JoinHandle<(Option<Vec<u8>>, Vec<String>)> = spawn(move || {
+            let mut clients: Vec<rustypipes::OctopipesClient> = Vec::new();
+            for client_id in ["durable_active", "durable_idle"].iter() {
+                let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    String::from(*client_id),
+                    cap_pipe.clone(),
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                client.set_durable(true);
+                if let Err(error) = client.subscribe(&vec![String::from("NEWS")]) {
+                    panic!("{} couldn't subscribe: {}", client_id, error);
+                }
+                if let Err(error) = client.unsubscribe() {
+                    panic!("{} couldn't unsubscribe: {}", client_id, error);
+                }
+                clients.push(client);
+            }
+            offline_tx.send(()).expect("Main thread has gone away");
+            rx.recv().expect("Main thread has gone away");
+            //The active reader starts its loop right away and gets the message
+            if let Err(error) = clients[0].subscribe(&vec![String::from("NEWS")]) {
+                panic!("durable_active couldn't subscribe again: {}", error);
+            }
+            if let Err(error) = clients[0].loop_start() {
+                panic!("durable_active couldn't start loop: {}", error);
+            }
+            let received: Option<Vec<u8>> = wait_message(&clients[0]).map(|message| message.data);
+            //The idle reader never reads its pipe, but the server must still serve its requests
+            if let Err(error) = clients[1].subscribe(&vec![String::from("NEWS")]) {
+                panic!("durable_idle couldn't subscribe again: {}", error);
+            }
+            let online: Vec<String> = match clients[1].list_clients() {
+                Ok(online) => online,
+                Err(error) => panic!("durable_idle couldn't list clients: {}", error),
+            };
+            for client in clients.iter_mut() {
+                let _ = client.unsubscribe();
+            }
+            (received, online)
+        });
+        //Wait for the readers to go offline
+        let t_start: Instant = Instant::now();
+        while offline_rx.try_recv().is_err() && t_start.elapsed().as_millis() < 10000 {
+            let _ = server.process_cap_all();
+            sleep(Duration::from_millis(50));
+        }
+        //Send a message without ttl while the readers are offline
+        let writer: JoinHandle<()> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("durable_no_ttl_writer"),
+                cap_pipe_w,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("Writer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.send(&String::from("NEWS"), b"no ttl".to_vec()) {
+                panic!("Writer couldn't send: {}", error);
+            }
+            sleep(Duration::from_millis(500));
+        });
+        run_server(&mut server, &writer);
+        writer.join().expect("Writer thread panic");
+        let _ = server.process_all();
+        //Let the readers come back
+        tx.send(()).expect("Readers have gone away");
+        run_server(&mut server, &readers);
+        let (received, online) = readers.join().expect("Readers thread panic");
+        assert_eq!(received, Some(b"no ttl".to_vec()));
+        assert!(online.contains(&String::from("durable_idle")));
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn dead_letters() {
+        //A message without subscribers is reported to the callback and the dead letter group, and the sender gets a NACK
+        let cap_pipe: String = String::from("/tmp/cap_deadletter.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_deadletter/"),
+        );
+        server.set_dead_letter_group(Some(String::from("dead_letters")));
+        server.set_on_dead_letter(on_dead_letter);
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Option<rustypipes::OctopipesMessage>, Option<rustypipes::OctopipesMessage>)> = spawn(move || {
+            let mut monitor: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("dead_letter_monitor"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = monitor.subscribe(&vec![String::from("dead_letters")]) {
+                panic!("Monitor couldn't subscribe: {}", error);
+            }
+            if let Err(error) = monitor.loop_start() {
+                panic!("Monitor couldn't start loop: {}", error);
+            }
+            let mut sender: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("dead_letter_sender"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = sender.subscribe(&vec![]) {
+                panic!("Sender couldn't subscribe: {}", error);
+            }
+            if let Err(error) = sender.loop_start() {
+                panic!("Sender couldn't start loop: {}", error);
+            }
+            //A retained message is stored for later subscribers: it's not a dead letter (the first report must be for the next message)
+            let retain: rustypipes::OctopipesOptions = rustypipes::OctopipesOptions::RCK | rustypipes::OctopipesOptions::RETAIN;
+            if let Err(error) = sender.send_ex(&String::from("nobody/retained"), b"kept".to_vec(), 5, retain) {
+                panic!("Sender couldn't send: {}", error);
+            }
+            if let Err(error) = sender.send_ex(&String::from("nobody"), b"hello".to_vec(), 5, rustypipes::OctopipesOptions::RCK) {
+                panic!("Sender couldn't send: {}", error);
+            }
+            let mut report: Option<rustypipes::OctopipesMessage> = None;
+            let mut nack: Option<rustypipes::OctopipesMessage> = None;
+            let t_start: Instant = Instant::now();
+            while (report.is_none() || nack.is_none()) && t_start.elapsed().as_millis() < 3000 {
+                if let Ok(Some(message)) = monitor.get_next_message() {
+                    report = Some(message);
+                }
+                if let Ok(Some(message)) = sender.get_next_message() {
+                    nack = Some(message);
+                }
+                sleep(Duration::from_millis(50));
+            }
+            let _ = sender.unsubscribe();
+            let _ = monitor.unsubscribe();
+            (report, nack)
+        });
+        run_server(&mut server, &clients);
+        let (report, nack) = clients.join().expect("Clients thread panic");
+        assert!(DEAD_LETTERED.load(Ordering::SeqCst), "Dead letter callback wasn't called");
+        let report: rustypipes::OctopipesMessage = report.expect("Monitor didn't receive the dead letter");
+        let dead_letter = rustypipes::OctopipesDeadLetter::decode(&report.data).expect("Bad dead letter");
+        assert_eq!(dead_letter.reason, rustypipes::OctopipesDeadLetterReason::NoSubscribers);
+        assert_eq!(dead_letter.recipient, None);
+        assert_eq!(dead_letter.message.origin.as_deref(), Some("dead_letter_sender"));
+        assert_eq!(dead_letter.message.data, b"hello".to_vec());
+        let nack: rustypipes::OctopipesMessage = nack.expect("Sender didn't receive the NACK");
+        assert_eq!(nack.nack_reason(), Some(rustypipes::OctopipesDeadLetterReason::NoSubscribers));
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn rejected_message() {
+        //A message without recipient is rejected and the client gets the error on its inbox
+        let cap_pipe: String = String::from("/tmp/cap_reject.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_reject/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client: JoinHandle<Option<rustypipes::OctopipesError>> = spawn(move || {
+            let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("reject_client"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = client.subscribe(&vec![]) {
+                panic!("Client couldn't subscribe: {}", error);
+            }
+            if let Err(error) = client.loop_start() {
+                panic!("Client couldn't start loop: {}", error);
+            }
+            if let Err(error) = client.send(&String::new(), b"lost".to_vec()) {
+                panic!("Client couldn't send: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            let mut rejection: Option<rustypipes::OctopipesError> = None;
+            while rejection.is_none() && t_start.elapsed().as_millis() < 3000 {
+                if let Err(error) = client.get_next_message() {
+                    rejection = Some(error);
+                }
+                sleep(Duration::from_millis(50));
+            }
+            let _ = client.unsubscribe();
+            rejection
+        });
+        let t_start: Instant = Instant::now();
+        let mut server_error: Option<rustypipes::OctopipesServerError> = None;
+        while !client.is_finished() && t_start.elapsed().as_secs() < 10 {
+            let _ = server.process_cap_all();
+            if let Err((client_id, error)) = server.process_all() {
+                assert_eq!(client_id, "reject_client");
+                server_error = Some(error);
+            }
+            sleep(Duration::from_millis(50));
+        }
+        assert!(server_error == Some(rustypipes::OctopipesServerError::NoRecipient), "Server should have returned NoRecipient");
+        assert!(
+            client.join().expect("Client thread panic") == Some(rustypipes::OctopipesError::NoRecipient),
+            "Client should have received NoRecipient"
+        );
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn server_events() {
+        //The server reports subscriptions, routed messages and unsubscriptions through poll_events
+        let cap_pipe: String = String::from("/tmp/cap_events.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_events/"),
+        );
+        server.set_on_event(on_event);
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<bool> = spawn(move || {
+            let mut reader: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("events_reader"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = reader.subscribe(&vec![String::from("events")]) {
+                panic!("Reader couldn't subscribe: {}", error);
+            }
+            if let Err(error) = reader.loop_start() {
+                panic!("Reader couldn't start loop: {}", error);
+            }
+            let mut writer: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("events_writer"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = writer.subscribe(&vec![]) {
+                panic!("Writer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = writer.send(&String::from("events"), b"ping".to_vec()) {
+                panic!("Writer couldn't send: {}", error);
+            }
+            let t_start: Instant = Instant::now();
+            let mut received: bool = false;
+            while !received && t_start.elapsed().as_millis() < 3000 {
+                received = matches!(reader.get_next_message(), Ok(Some(_)));
+                sleep(Duration::from_millis(50));
+            }
+            let _ = writer.unsubscribe();
+            let _ = reader.unsubscribe();
+            received
+        });
+        let mut events: Vec<rustypipes::OctopipesServerEvent> = Vec::new();
+        let t_start: Instant = Instant::now();
+        while !clients.is_finished() && t_start.elapsed().as_secs() < 10 {
+            events.extend(server.poll_events().expect("Poll events failed"));
+            sleep(Duration::from_millis(50));
+        }
+        events.extend(server.poll_events().expect("Poll events failed"));
+        assert!(clients.join().expect("Clients thread panic"), "Reader didn't receive the message");
+        assert!(EVENT_NOTIFIED.load(Ordering::SeqCst), "Event callback wasn't called");
+        let subscribed: Vec<String> = events
+            .iter()
+            .filter_map(|event| match event {
+                rustypipes::OctopipesServerEvent::ClientSubscribed { client, .. } => Some(client.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(subscribed, vec![String::from("events_reader"), String::from("events_writer")]);
+        assert!(events.iter().any(|event| match event {
+            rustypipes::OctopipesServerEvent::MessageRouted { origin, remote, recipients } => {
+                origin.as_deref() == Some("events_writer") && remote == "events" && *recipients == vec![String::from("events_reader")]
+            }
+            _ => false,
+        }), "Message to events should have been routed to events_reader");
+        let unsubscribed: usize = events
+            .iter()
+            .filter(|event| matches!(event, rustypipes::OctopipesServerEvent::ClientUnsubscribed { .. }))
+            .count();
+        assert_eq!(unsubscribed, 2);
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn forged_disconnect() {
+        //Only the server can kick a client
+        assert_forged_frame_rejected("/tmp/cap_forge_disconnect.fifo", "/tmp/clients_forge_disconnect/", rustypipes::OctopipesOptions::empty(), vec![0xfc, 0x03, 0x62, 0x79, 0x65]);
+    }
+
+    #[test]
+    fn forged_shutdown() {
+        //Only the server can announce its shutdown
+        assert_forged_frame_rejected("/tmp/cap_forge_shutdown.fifo", "/tmp/clients_forge_shutdown/", rustypipes::OctopipesOptions::empty(), vec![0xfb]);
+    }
+
+    #[test]
+    fn forged_error() {
+        //Only the server can report errors to a client
+        assert_forged_frame_rejected("/tmp/cap_forge_error.fifo", "/tmp/clients_forge_error/", rustypipes::OctopipesOptions::empty(), vec![0xfa, 0x01]);
+    }
+
+    #[test]
+    fn forged_nack() {
+        //Only the server can tell a client its message couldn't be delivered
+        assert_forged_frame_rejected("/tmp/cap_forge_nack.fifo", "/tmp/clients_forge_nack/", rustypipes::OctopipesOptions::NACK, vec![0x01]);
+    }
+
+    #[test]
+    fn invalid_client_id() {
+        //Client ids are groups, so they can't be wildcards or reserved groups
+        let cap_pipe: String = String::from("/tmp/cap_invalid_id.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_invalid_id/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let client: JoinHandle<Vec<rustypipes::OctopipesCapError>> = spawn(move || {
+            let mut results: Vec<rustypipes::OctopipesCapError> = Vec::new();
+            for client_id in ["#", "+", "$share"] {
+                let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    String::from(client_id),
+                    cap_pipe_c.clone(),
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                results.push(client.subscribe(&vec![]).expect("Subscription failed"));
+            }
+            results
+        });
+        run_server(&mut server, &client);
+        for result in client.join().expect("Client thread panic") {
+            assert!(result == rustypipes::OctopipesCapError::InvalidName, "Subscription should have been refused, but got {}", result);
+        }
+        assert!(server.get_clients().is_empty(), "No client should have subscribed");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn reply_pipe_hijack() {
+        //The server must write replies only to FIFOs in the reply pipe path of valid client ids
+        let cap_pipe: String = String::from("/tmp/cap_hijack.fifo");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_hijack/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        //An id escaping the CAP folder (its reply pipe would be /tmp/hijack_target) and a regular file planted as reply pipe
+        let _ = std::fs::create_dir_all(format!("{}.x", cap_pipe));
+        std::fs::write("/tmp/hijack_target", b"target").expect("Could not create target file");
+        std::fs::write(format!("{}.planted_client", cap_pipe), b"planted").expect("Could not create planted file");
+        let subscription: Vec<u8> = vec![0x01, 0x00]; //SUBSCRIPTION without groups
+        write_raw(&cap_pipe, &encode_frame("x/../hijack_target", "", rustypipes::OctopipesOptions::empty(), &subscription));
+        write_raw(&cap_pipe, &encode_frame("planted_client", "", rustypipes::OctopipesOptions::empty(), &subscription));
+        let t_start: Instant = Instant::now();
+        while t_start.elapsed().as_millis() < 1000 {
+            let _ = server.process_cap_all();
+            sleep(Duration::from_millis(50));
+        }
+        assert_eq!(std::fs::read("/tmp/hijack_target").unwrap(), b"target".to_vec(), "The server wrote outside the CAP folder");
+        assert_eq!(std::fs::read(format!("{}.planted_client", cap_pipe)).unwrap(), b"planted".to_vec(), "The server wrote to a regular file");
+        assert!(server.get_clients().is_empty(), "No client should have subscribed");
+        let _ = std::fs::remove_file("/tmp/hijack_target");
+        let _ = std::fs::remove_file(format!("{}.planted_client", cap_pipe));
+        let _ = std::fs::remove_dir(format!("{}.x", cap_pipe));
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn reserved_groups() {
+        //Clients can't publish to $SYS groups, so they can't forge presence events
+        let cap_pipe: String = String::from("/tmp/cap_reserved.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_reserved/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Option<rustypipes::OctopipesError>, bool)> = spawn(move || {
+            let mut listener: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("presence_listener"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = listener.subscribe(&vec![String::from(rustypipes::PRESENCE_GROUP)]) {
+                panic!("Listener couldn't subscribe: {}", error);
+            }
+            if let Err(error) = listener.loop_start() {
+                panic!("Listener couldn't start loop: {}", error);
+            }
+            let mut forger: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("presence_forger"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = forger.subscribe(&vec![]) {
+                panic!("Forger couldn't subscribe: {}", error);
+            }
+            if let Err(error) = forger.loop_start() {
+                panic!("Forger couldn't start loop: {}", error);
+            }
+            if let Err(error) = forger.send(&String::from(rustypipes::PRESENCE_GROUP), b"forged".to_vec()) {
+                panic!("Forger couldn't send: {}", error);
+            }
+            let mut forger_error: Option<rustypipes::OctopipesError> = None;
+            let mut forged_received: bool = false;
+            let t_start: Instant = Instant::now();
+            while t_start.elapsed().as_millis() < 2000 {
+                if let Err(error) = forger.get_next_message() {
+                    forger_error = Some(error);
+                }
+                if let Ok(Some(message)) = listener.get_next_message() {
+                    forged_received |= message.origin.is_some() || message.data == b"forged".to_vec();
+                }
+                sleep(Duration::from_millis(50));
+            }
+            let _ = forger.unsubscribe();
+            let _ = listener.unsubscribe();
+            (forger_error, forged_received)
+        });
+        run_server(&mut server, &clients);
+        let (forger_error, forged_received) = clients.join().expect("Clients thread panic");
+        assert!(forger_error == Some(rustypipes::OctopipesError::ReservedGroup), "Forger should have been told $SYS is reserved");
+        assert!(!forged_received, "Listener shouldn't have received the forged event");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn split_frame() {
+        //A frame written in two parts, read separately by the server, is delivered once complete
+        let cap_pipe: String = String::from("/tmp/cap_split.fifo");
+        let cap_pipe_c: String = cap_pipe.clone();
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            String::from("/tmp/clients_split/"),
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let clients: JoinHandle<(Option<Vec<u8>>, bool)> = spawn(move || {
+            let mut reader: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("split_reader"),
+                cap_pipe_c.clone(),
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = reader.subscribe(&vec![String::from("split")]) {
+                panic!("Reader couldn't subscribe: {}", error);
+            }
+            if let Err(error) = reader.loop_start() {
+                panic!("Reader couldn't start loop: {}", error);
+            }
+            let mut writer: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                String::from("split_writer"),
+                cap_pipe_c,
+                rustypipes::OctopipesProtocolVersion::Version1,
+            );
+            if let Err(error) = writer.subscribe(&vec![]) {
+                panic!("Writer couldn't subscribe: {}", error);
+            }
+            if let Err(error) = writer.loop_start() {
+                panic!("Writer couldn't start loop: {}", error);
+            }
+            let (tx_pipe, _) = writer.assigned_pipes().expect("Writer has no pipes");
+            let frame: Vec<u8> = encode_frame("split_writer", "split", rustypipes::OctopipesOptions::empty(), b"in two parts");
+            write_raw(&tx_pipe, &frame[..20]);
+            sleep(Duration::from_millis(1000)); //Let the worker read the first part alone
+            write_raw(&tx_pipe, &frame[20..]);
+            let mut received: Option<Vec<u8>> = None;
+            let mut writer_error: bool = false;
+            let t_start: Instant = Instant::now();
+            while received.is_none() && t_start.elapsed().as_millis() < 3000 {
+                if let Ok(Some(message)) = reader.get_next_message() {
+                    received = Some(message.data);
+                }
+                writer_error |= writer.get_next_message().is_err();
+                sleep(Duration::from_millis(50));
+            }
+            let _ = writer.unsubscribe();
+            let _ = reader.unsubscribe();
+            (received, writer_error)
+        });
+        run_server(&mut server, &clients);
+        let (received, writer_error) = clients.join().expect("Clients thread panic");
+        assert_eq!(received, Some(b"in two parts".to_vec()));
+        assert!(!writer_error, "Writer shouldn't have been told its frame was rejected");
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
+
+    #[test]
+    fn concurrent_subscriptions() {
+        //Many clients subscribe at the same time; each one must get its own assignment
+        const CLIENTS: usize = 32;
+        let cap_pipe: String = String::from("/tmp/cap_stress.fifo");
+        let client_folder: String = String::from("/tmp/clients_stress/");
+        let mut server: rustypipes::OctopipesServer = rustypipes::OctopipesServer::new(
+            rustypipes::OctopipesProtocolVersion::Version1,
+            cap_pipe.clone(),
+            client_folder,
+        );
+        if let Err(error) = server.start_cap_listener() {
+            panic!("Could not start CAP listener: {}", error);
+        }
+        let mut join_hnds: Vec<JoinHandle<()>> = Vec::with_capacity(CLIENTS);
+        for i in 0..CLIENTS {
+            let cap_pipe: String = cap_pipe.clone();
+            join_hnds.push(spawn(move || {
+                let client_id: String = format!("stress_client_{}", i);
+                let mut client: rustypipes::OctopipesClient = rustypipes::OctopipesClient::new(
+                    client_id.clone(),
+                    cap_pipe,
+                    rustypipes::OctopipesProtocolVersion::Version1,
+                );
+                match client.subscribe(&vec![String::from("STRESS")]) {
+                    Ok(cap_error) => assert_eq!(cap_error, rustypipes::OctopipesCapError::NoError),
+                    Err(error) => panic!("{} couldn't subscribe: {}", client_id, error),
+                }
+                //The assignment must be the one for this client
+                let (tx_pipe, rx_pipe) = client.assigned_pipes().unwrap();
+                assert!(tx_pipe.ends_with(&format!("/{}_tx.fifo", client_id)), "{} got tx pipe {}", client_id, tx_pipe);
+                assert!(rx_pipe.ends_with(&format!("/{}_rx.fifo", client_id)), "{} got rx pipe {}", client_id, rx_pipe);
+            }));
+        }
+        //Process CAP until all clients are subscribed
+        let t_start: Instant = Instant::now();
+        while server.get_clients().len() < CLIENTS && t_start.elapsed().as_millis() < 30000 {
+            if let Err(error) = server.process_cap_all() {
+                panic!("Error while processing CAP: {}\n", error);
+            }
+            sleep(Duration::from_millis(10));
+        }
+        for join_hnd in join_hnds {
+            if let Err(err) = join_hnd.join() {
+                panic!("Client thread panic: {:?}", err);
+            }
+        }
+        assert_eq!(server.get_clients().len(), CLIENTS, "All clients should be subscribed");
+        for i in 0..CLIENTS {
+            let client_id: String = format!("stress_client_{}", i);
+            let groups: Vec<String> = server.get_subscriptions(client_id.clone()).unwrap();
+            assert_eq!(groups, vec![String::from("STRESS"), client_id]);
+        }
+        if let Err(error) = server.stop_server() {
+            panic!("Could not stop server: {}", error);
+        }
+    }
 }